@@ -0,0 +1,117 @@
+use std::collections::hash_map::HashMap;
+use std::collections::btree_set::BTreeSet;
+use std::io;
+use byteorder::{NetworkEndian, ByteOrder};
+
+use super::{SiteId, State, VersionVector};
+use serialization::{write_site_id, read_site_id, preallocation};
+
+// Records which remote operations have been applied, so that retried deliveries
+// can be recognised. For each site this keeps the timestamp below which every
+// operation has been applied, plus any applied out of order beyond it.
+#[derive(Debug, Default)]
+pub struct AppliedOperations {
+    sites: HashMap<SiteId, (u64, BTreeSet<u64>)>
+}
+
+impl AppliedOperations {
+    #[inline]
+    pub fn new() -> AppliedOperations {
+        AppliedOperations {
+            sites: HashMap::new()
+        }
+    }
+
+    pub fn contains(&self, state: &State) -> bool {
+        match self.sites.get(&state.site_id) {
+            Some(&(high_water, ref beyond)) => state.time_stamp < high_water || beyond.contains(&state.time_stamp),
+            None => false
+        }
+    }
+
+    pub fn insert(&mut self, state: &State) {
+        let &mut (ref mut high_water, ref mut beyond) = self.sites.entry(state.site_id).or_insert_with(|| (0, BTreeSet::new()));
+        if state.time_stamp < *high_water {
+            return
+        }
+        beyond.insert(state.time_stamp);
+        while beyond.remove(high_water) {
+            *high_water += 1;
+        }
+    }
+
+    pub fn high_water_marks(&self) -> VersionVector {
+        let mut version_vector = VersionVector::new();
+        for (&site_id, &(high_water, _)) in self.sites.iter() {
+            version_vector.observe(site_id, high_water);
+        }
+        version_vector
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, self.sites.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (&site_id, &(high_water, ref beyond)) in self.sites.iter() {
+            try!(write_site_id(writer, site_id));
+            NetworkEndian::write_u64(&mut long_buf, high_water);
+            try!(writer.write_all(&long_buf));
+            NetworkEndian::write_u32(&mut int_buf, beyond.len() as u32);
+            try!(writer.write_all(&int_buf));
+            for &time_stamp in beyond.iter() {
+                NetworkEndian::write_u64(&mut long_buf, time_stamp);
+                try!(writer.write_all(&long_buf));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<AppliedOperations> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut int_buf));
+        let site_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut sites = HashMap::with_capacity(preallocation(site_count));
+        for _ in 0..site_count {
+            let site_id = try!(read_site_id(reader));
+            try!(reader.read_exact(&mut long_buf));
+            let high_water = NetworkEndian::read_u64(&long_buf);
+            try!(reader.read_exact(&mut int_buf));
+            let beyond_count = NetworkEndian::read_u32(&int_buf) as usize;
+            let mut beyond = BTreeSet::new();
+            for _ in 0..beyond_count {
+                try!(reader.read_exact(&mut long_buf));
+                beyond.insert(NetworkEndian::read_u64(&long_buf));
+            }
+            sites.insert(site_id, (high_water, beyond));
+        }
+        Ok(AppliedOperations {
+            sites: sites
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AppliedOperations;
+    use super::super::State;
+
+    #[test]
+    fn high_water_mark_advances() {
+        let mut applied = AppliedOperations::new();
+        applied.insert(&State { site_id: 1, time_stamp: 1 });
+        assert!(!applied.contains(&State { site_id: 1, time_stamp: 0 }));
+        assert!(applied.contains(&State { site_id: 1, time_stamp: 1 }));
+        applied.insert(&State { site_id: 1, time_stamp: 0 });
+        assert_eq!(applied.sites[&1], (2, Default::default()));
+        assert!(!applied.contains(&State { site_id: 2, time_stamp: 0 }));
+
+        let mut buffer = Vec::new();
+        applied.insert(&State { site_id: 2, time_stamp: 5 });
+        applied.compress_to(&mut buffer).unwrap();
+        let applied = AppliedOperations::expand_from(&mut &buffer[..]).unwrap();
+        assert!(applied.contains(&State { site_id: 1, time_stamp: 0 }));
+        assert!(applied.contains(&State { site_id: 2, time_stamp: 5 }));
+    }
+}
@@ -0,0 +1,120 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use super::{FileMetadata, SiteId};
+
+// Every attribute the crate itself maintains lives under this prefix. Sites
+// preserve and replicate system attributes they don't recognise, so newer
+// versions can add keys without older ones discarding them.
+pub const SYSTEM_PREFIX: &'static str = "sys:";
+pub const MTIME: &'static str = "sys:mtime";
+pub const MODE: &'static str = "sys:mode";
+pub const CONTENT_HASH: &'static str = "sys:content_hash";
+pub const KIND: &'static str = "sys:kind";
+pub const DIRECTORY: &'static str = "directory";
+// Whether the file is text or binary, worked out when it is first updated
+pub const CONTENT_TYPE: &'static str = "sys:content_type";
+pub const TEXT: &'static str = "text";
+pub const BINARY: &'static str = "binary";
+// Who is editing the file, as the site id, the wall time in milliseconds when the
+// lease runs out and the holder's name. Empty once released.
+pub const EDIT_LOCK: &'static str = "sys:edit_lock";
+// Followed by the id of a site that could not create its copy of the file
+pub const UNMATERIALIZED_PREFIX: &'static str = "sys:unmaterialized:";
+
+#[inline]
+pub fn is_system_attribute(key: &str) -> bool {
+    key.starts_with(SYSTEM_PREFIX)
+}
+
+// How much of the start of a file is looked at for a null byte
+pub const SNIFF_LENGTH: usize = 8000;
+
+const TEXT_EXTENSIONS: &'static [&'static str] = &["txt", "md", "rs", "c", "h", "cpp", "py", "js", "json", "toml", "yaml", "yml", "xml", "html", "css", "csv", "ini", "cfg", "sh"];
+const BINARY_EXTENSIONS: &'static [&'static str] = &["png", "jpg", "jpeg", "gif", "bmp", "ico", "pdf", "zip", "gz", "tar", "7z", "exe", "dll", "so", "mp3", "mp4", "ogg", "wav", "avi", "mov", "doc", "docx", "xls", "xlsx"];
+
+// Goes by the extension when it's a well known one, and otherwise calls the file
+// binary if the start of it has a null byte in it, as git does
+pub fn classify_content(path: &Path, start: &[u8]) -> &'static str {
+    let extension = path.extension().and_then(OsStr::to_str).map(|extension| extension.to_lowercase());
+    if let Some(extension) = extension {
+        if TEXT_EXTENSIONS.contains(&extension.as_str()) {
+            return TEXT
+        }
+        if BINARY_EXTENSIONS.contains(&extension.as_str()) {
+            return BINARY
+        }
+    }
+    if start.iter().take(SNIFF_LENGTH).any(|&byte| byte == 0) { BINARY } else { TEXT }
+}
+
+// Advisory only: nothing stops another site from changing the file, but the
+// application can warn before opening a file that merges badly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EditLock {
+    pub site_id: SiteId,
+    pub expires: u64,
+    pub holder: String
+}
+
+impl EditLock {
+    pub fn to_value(&self) -> String {
+        format!("{} {} {}", self.site_id, self.expires, self.holder)
+    }
+
+    pub fn from_value(value: &str) -> Option<EditLock> {
+        let mut parts = value.splitn(3, ' ');
+        let site_id = parts.next().and_then(|site_id| site_id.parse().ok());
+        let expires = parts.next().and_then(|expires| expires.parse().ok());
+        match (site_id, expires, parts.next()) {
+            (Some(site_id), Some(expires), Some(holder)) => Some(EditLock {
+                site_id: site_id,
+                expires: expires,
+                holder: holder.to_string()
+            }),
+            _ => None
+        }
+    }
+}
+
+impl FileMetadata {
+    pub fn get_attribute(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).map(|&(_, ref value)| value.as_str())
+    }
+
+    pub fn mtime(&self) -> Option<u64> {
+        self.get_attribute(MTIME).and_then(|value| value.parse().ok())
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.get_attribute(MODE).and_then(|value| u32::from_str_radix(value, 8).ok())
+    }
+
+    pub fn content_hash(&self) -> Option<&str> {
+        self.get_attribute(CONTENT_HASH)
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.get_attribute(KIND) == Some(DIRECTORY)
+    }
+
+    // None until the file has been updated
+    pub fn is_binary(&self) -> Option<bool> {
+        self.get_attribute(CONTENT_TYPE).map(|content_type| content_type == BINARY)
+    }
+
+    // The lock on the file, if there is one and its lease hasn't run out by `now`
+    pub fn edit_lock(&self, now: u64) -> Option<EditLock> {
+        self.get_attribute(EDIT_LOCK).and_then(EditLock::from_value).and_then(|lock| if lock.expires > now { Some(lock) } else { None })
+    }
+
+    pub fn unmaterialized_sites(&self) -> Vec<(SiteId, &str)> {
+        self.attributes.iter().filter_map(|(key, &(_, ref reason))| {
+            if key.starts_with(UNMATERIALIZED_PREFIX) {
+                key[UNMATERIALIZED_PREFIX.len()..].parse().ok().map(|site_id| (site_id, reason.as_str()))
+            } else {
+                None
+            }
+        }).collect()
+    }
+}
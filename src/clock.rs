@@ -0,0 +1,150 @@
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use byteorder::{NetworkEndian, ByteOrder};
+
+// Milliseconds of wall clock time, and a counter for events within the same
+// millisecond, or for when the wall clock has fallen behind a remote site's
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct HybridTimestamp {
+    pub wall_time: u64,
+    pub logical: u32
+}
+
+impl HybridTimestamp {
+    // The next timestamp at the same wall time, or the next millisecond once the
+    // counter runs out, which only a clock far ahead of the wall clock could do
+    fn successor(&self) -> HybridTimestamp {
+        match self.logical.checked_add(1) {
+            Some(logical) => HybridTimestamp {
+                wall_time: self.wall_time,
+                logical: logical
+            },
+            None => HybridTimestamp {
+                wall_time: self.wall_time.checked_add(1).expect("hybrid clock exhausted"),
+                logical: 0
+            }
+        }
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut long_buf = [0;8];
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u64(&mut long_buf, self.wall_time);
+        try!(writer.write_all(&long_buf));
+        NetworkEndian::write_u32(&mut int_buf, self.logical);
+        writer.write_all(&int_buf)
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<HybridTimestamp> {
+        let mut long_buf = [0;8];
+        let mut int_buf = [0;4];
+        try!(reader.read_exact(&mut long_buf));
+        try!(reader.read_exact(&mut int_buf));
+        Ok(HybridTimestamp {
+            wall_time: NetworkEndian::read_u64(&long_buf),
+            logical: NetworkEndian::read_u32(&int_buf)
+        })
+    }
+}
+
+// A hybrid logical clock. Its timestamps stay close to the wall clock, but never
+// go backwards and always come after every timestamp the clock has observed.
+#[derive(Debug, Clone, Default)]
+pub struct HybridClock {
+    last: HybridTimestamp
+}
+
+impl HybridClock {
+    #[inline]
+    pub fn new() -> HybridClock {
+        HybridClock {
+            last: HybridTimestamp::default()
+        }
+    }
+
+    #[inline]
+    pub fn get_last(&self) -> HybridTimestamp {
+        self.last
+    }
+
+    pub fn tick(&mut self) -> HybridTimestamp {
+        self.tick_at(wall_time())
+    }
+
+    pub fn tick_at(&mut self, wall_time: u64) -> HybridTimestamp {
+        self.last = if wall_time > self.last.wall_time {
+            HybridTimestamp {
+                wall_time: wall_time,
+                logical: 0
+            }
+        } else {
+            self.last.successor()
+        };
+        self.last
+    }
+
+    pub fn observe(&mut self, remote: HybridTimestamp) -> HybridTimestamp {
+        self.observe_at(remote, wall_time())
+    }
+
+    pub fn observe_at(&mut self, remote: HybridTimestamp, wall_time: u64) -> HybridTimestamp {
+        let latest = if remote > self.last { remote } else { self.last };
+        self.last = if wall_time > latest.wall_time {
+            HybridTimestamp {
+                wall_time: wall_time,
+                logical: 0
+            }
+        } else {
+            latest.successor()
+        };
+        self.last
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.last.compress_to(writer)
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<HybridClock> {
+        Ok(HybridClock {
+            last: try!(HybridTimestamp::expand_from(reader))
+        })
+    }
+}
+
+pub fn wall_time() -> u64 {
+    // A clock set before 1970 just means the logical counter does all the work
+    duration_millis(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default())
+}
+
+pub fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HybridClock, HybridTimestamp};
+
+    #[test]
+    fn clock_never_goes_backwards() {
+        let mut clock = HybridClock::new();
+        assert_eq!(clock.tick_at(100), HybridTimestamp { wall_time: 100, logical: 0 });
+        assert_eq!(clock.tick_at(100), HybridTimestamp { wall_time: 100, logical: 1 });
+        // The wall clock was set back
+        assert_eq!(clock.tick_at(50), HybridTimestamp { wall_time: 100, logical: 2 });
+        // A remote site's clock is ahead of this one
+        let remote = HybridTimestamp { wall_time: 200, logical: 4 };
+        assert_eq!(clock.observe_at(remote, 150), HybridTimestamp { wall_time: 200, logical: 5 });
+        assert!(clock.tick_at(150) > remote);
+        assert_eq!(clock.observe_at(remote, 300), HybridTimestamp { wall_time: 300, logical: 0 });
+
+        let mut buffer = Vec::new();
+        clock.compress_to(&mut buffer).unwrap();
+        let clock = HybridClock::expand_from(&mut &buffer[..]).unwrap();
+        assert_eq!(clock.get_last(), HybridTimestamp { wall_time: 300, logical: 0 });
+
+        // A counter that runs out carries into the wall time
+        let mut clock = HybridClock::new();
+        clock.observe_at(HybridTimestamp { wall_time: 400, logical: u32::max_value() - 1 }, 0);
+        assert_eq!(clock.tick_at(0), HybridTimestamp { wall_time: 401, logical: 0 });
+    }
+}
@@ -0,0 +1,106 @@
+use std::io;
+use byteorder::{NetworkEndian, ByteOrder};
+use sha2::{Sha256, Digest};
+
+use super::{FileSet, FileUpdater, FileMetadata, FileID, SiteId};
+use serialization::{write_id, write_str};
+use attributes;
+
+impl<FU: FileUpdater> FileSet<FU> {
+    // A hash of every file's id, name and attributes, with their timestamps, which
+    // two sites that have seen the same operations agree on. The names conflicts
+    // are printed under here are left out, since they depend on the order things
+    // arrived in, and so are the attributes each site keeps for itself. It is
+    // always SHA-256: every site has to hash the same way for digests to agree,
+    // and only names and attributes are hashed, so a faster hash would gain little.
+    pub fn digest(&self) -> [u8; 32] {
+        self.digest_shared_with(None)
+    }
+
+    // Covers only what the peer's outbound filter lets it have, which is what its
+    // own digest comes to once it has caught up
+    pub fn digest_for_peer(&self, peer: SiteId) -> [u8; 32] {
+        self.digest_shared_with(Some(peer))
+    }
+
+    fn digest_shared_with(&self, peer: Option<SiteId>) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        self.write_digest(&mut hasher, peer).unwrap();
+        hasher.finalize().into()
+    }
+
+    fn write_digest(&self, hasher: &mut Sha256, peer: Option<SiteId>) -> io::Result<()> {
+        let mut files: Vec<_> = self.files.iter().filter(|&(_, file)| self.is_metadata_shared_with(peer, file)).collect();
+        files.sort_by_key(|&(&id, _)| id);
+        for (&id, file) in files {
+            try!(write_file_digest(hasher, id, file));
+        }
+        Ok(())
+    }
+}
+
+pub fn file_digest(id: FileID, file: &FileMetadata) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    write_file_digest(&mut hasher, id, file).unwrap();
+    hasher.finalize().into()
+}
+
+// Whether a site's copy of the file can't be built, whether it is locked right
+// now, and what its contents looked like when this site first saw them can all
+// differ between sites that have seen the same operations
+fn is_site_local(key: &str) -> bool {
+    key.starts_with(attributes::UNMATERIALIZED_PREFIX) || key == attributes::EDIT_LOCK || key == attributes::CONTENT_TYPE
+}
+
+fn write_file_digest<W: io::Write>(writer: &mut W, id: FileID, file: &FileMetadata) -> io::Result<()> {
+    let mut int_buf = [0;4];
+    let mut long_buf = [0;8];
+    try!(write_id(writer, id));
+    NetworkEndian::write_u64(&mut long_buf, file.filename.0);
+    try!(writer.write_all(&long_buf));
+    NetworkEndian::write_u32(&mut int_buf, file.filename.1.len() as u32);
+    try!(writer.write_all(&int_buf));
+    for component in file.filename.1.iter() {
+        try!(write_str(writer, &mut int_buf, component));
+    }
+    let mut attributes: Vec<_> = file.attributes.iter().filter(|&(key, _)| !is_site_local(key)).collect();
+    attributes.sort();
+    NetworkEndian::write_u32(&mut int_buf, attributes.len() as u32);
+    try!(writer.write_all(&int_buf));
+    for (key, &(time_stamp, ref value)) in attributes {
+        try!(write_str(writer, &mut int_buf, key));
+        NetworkEndian::write_u64(&mut long_buf, time_stamp);
+        try!(writer.write_all(&long_buf));
+        try!(write_str(writer, &mut int_buf, value));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{file_digest, is_site_local};
+    use super::super::{FileMetadata, State, HybridTimestamp};
+    use attributes;
+
+    fn hex(digest: [u8; 32]) -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn site_local_attributes_are_left_out() {
+        let state = State {
+            site_id: 1,
+            time_stamp: 0
+        };
+        let mut file = FileMetadata::new_entry(vec!["file1".to_string()], "file1".to_string(), false, &state, HybridTimestamp::default());
+        let digest = hex(file_digest((1, 0), &file));
+        let unmaterialized = format!("{}2", attributes::UNMATERIALIZED_PREFIX);
+        for key in [attributes::EDIT_LOCK, attributes::CONTENT_TYPE, unmaterialized.as_str()].iter() {
+            assert!(is_site_local(key));
+            file.set_attribute(key.to_string(), "value".to_string(), &state, HybridTimestamp::default());
+        }
+        assert_eq!(hex(file_digest((1, 0), &file)), digest);
+        file.set_attribute("colour".to_string(), "red".to_string(), &state, HybridTimestamp::default());
+        assert!(hex(file_digest((1, 0), &file)) != digest);
+    }
+}
@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use super::{FileID, SiteId, SiteInfo};
+use attributes::EditLock;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncEvent {
+    SyncStarted,
+    SyncFinished {
+        local_changes: usize,
+        remote_changes: usize,
+        generation: u64
+    },
+    ConflictDetected {
+        id: FileID,
+        path: PathBuf,
+        generation: u64
+    },
+    SiteAnnounced {
+        site_id: SiteId,
+        info: SiteInfo
+    },
+    // Another site locked or released a file, or None if it was released
+    EditLockChanged {
+        id: FileID,
+        path: PathBuf,
+        lock: Option<EditLock>
+    }
+}
+
+pub trait SyncListener {
+    fn on_sync_event(&mut self, event: &SyncEvent);
+}
+
+// Two changes that couldn't both be kept, and which one every site settled on.
+// Two files created at the same path are both kept unless concurrent creates are
+// being merged, in which case the other one goes into the kept one. Otherwise the
+// other one is shown here under a conflict name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolvedConflict {
+    Rename {
+        id: FileID,
+        kept: Vec<String>,
+        discarded: Vec<String>
+    },
+    Attribute {
+        id: FileID,
+        key: String,
+        kept: String,
+        discarded: String
+    },
+    Create {
+        path: Vec<String>,
+        kept: FileID,
+        other: FileID,
+        merged: bool
+    }
+}
+
+pub trait ConflictHandler {
+    fn on_conflict_resolved(&mut self, conflict: &ResolvedConflict);
+}
@@ -0,0 +1,64 @@
+use std::collections::hash_map::HashMap;
+
+use super::{FileSet, FileUpdater, SiteId, VersionVector};
+
+impl<FU: FileUpdater> FileSet<FU> {
+    // Records that a peer has seen everything in the version vector
+    pub fn acknowledge(&mut self, peer: SiteId, seen: &VersionVector) {
+        self.acknowledgements.entry(peer).or_insert_with(VersionVector::new).merge(seen);
+        self.save().unwrap();
+    }
+
+    pub fn get_acknowledgements(&self) -> &HashMap<SiteId, VersionVector> {
+        &self.acknowledgements
+    }
+
+    // Every operation this vector includes has reached this site and every peer
+    // in the roster, so it will never be concurrent with anything still to come
+    // from them
+    pub fn stable_frontier(&self) -> VersionVector {
+        self.stable_under(&self.acknowledgements)
+    }
+
+    pub fn gc_acknowledged(&mut self) -> usize {
+        let acked = self.acknowledgements.clone();
+        self.gc(&acked)
+    }
+
+    // Forgets tombstones and folder moves that every peer has acknowledged seeing,
+    // given the version vector each peer last acknowledged. Returns how many
+    // entries were dropped.
+    pub fn gc(&mut self, acked: &HashMap<SiteId, VersionVector>) -> usize {
+        let seen = self.get_version_vector();
+        // An operation a peer made before acknowledging may still be on its way
+        // here, and may have been concurrent with a removal, so wait for it
+        if acked.iter().any(|(&site_id, acked_vector)| seen.get(site_id) < acked_vector.get(site_id)) {
+            trace!("Not collecting garbage until operations from before the acknowledgements arrive");
+            return 0
+        }
+        let stable = self.stable_under(acked);
+        let buried: Vec<_> = self.removed_at.iter().filter(|&(_, state)| stable.includes(state)).map(|(&id, _)| id).collect();
+        for id in buried.iter() {
+            self.removed.remove(id);
+            self.removed_at.remove(id);
+            self.removed_seen.remove(id);
+            self.last_updates.remove(id);
+            self.metadata_history.forget(*id);
+        }
+        let pruned = buried.len() + self.folder_moves.prune(&stable);
+        if pruned > 0 {
+            trace!("Collected {} tombstones and folder moves", pruned);
+            self.save().unwrap();
+        }
+        pruned
+    }
+
+    fn stable_under(&self, acked: &HashMap<SiteId, VersionVector>) -> VersionVector {
+        // A site in the roster that hasn't acknowledged anything may not have seen anything
+        let site_id = self.site_id;
+        if self.roster.site_ids().any(|&peer| peer != site_id && !acked.contains_key(&peer)) {
+            return VersionVector::new()
+        }
+        acked.values().fold(self.get_version_vector(), |stable, acked_vector| stable.meet(acked_vector))
+    }
+}
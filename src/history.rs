@@ -0,0 +1,166 @@
+use std::collections::hash_map::HashMap;
+use std::collections::vec_deque::VecDeque;
+use std::io;
+use byteorder::{NetworkEndian, ByteOrder};
+
+use super::{FileID, State};
+use serialization::{write_id, read_id, write_str, read_str, preallocation};
+
+// How many changes are kept for each file before the oldest are forgotten
+const HISTORY_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataValue {
+    Filename(Vec<String>),
+    Attribute(String, String)
+}
+
+// A change to a file's name or attributes, and which site made it when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataChange {
+    pub state: State,
+    pub value: MetadataValue
+}
+
+// The latest changes applied to each file's name and attributes, oldest first,
+// so that the application can show who renamed a file and work out why two
+// sites ended up with different names. A folder move is recorded against every
+// entry it renames.
+#[derive(Debug, Default)]
+pub struct MetadataHistory {
+    files: HashMap<FileID, VecDeque<MetadataChange>>
+}
+
+impl MetadataHistory {
+    #[inline]
+    pub fn new() -> MetadataHistory {
+        MetadataHistory {
+            files: HashMap::new()
+        }
+    }
+
+    pub fn record(&mut self, id: FileID, state: State, value: MetadataValue) {
+        let changes = self.files.entry(id).or_insert_with(VecDeque::new);
+        if changes.len() == HISTORY_LENGTH {
+            changes.pop_front();
+        }
+        changes.push_back(MetadataChange {
+            state: state,
+            value: value
+        });
+    }
+
+    pub fn get(&self, id: FileID) -> Option<&VecDeque<MetadataChange>> {
+        self.files.get(&id)
+    }
+
+    // Carries the history of one id over to another that has taken its place
+    pub fn transfer(&mut self, from: FileID, to: FileID) {
+        if let Some(changes) = self.files.remove(&from) {
+            self.files.insert(to, changes);
+        }
+    }
+
+    pub fn forget(&mut self, id: FileID) {
+        self.files.remove(&id);
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, self.files.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (&id, changes) in self.files.iter() {
+            try!(write_id(writer, id));
+            NetworkEndian::write_u32(&mut int_buf, changes.len() as u32);
+            try!(writer.write_all(&int_buf));
+            for change in changes.iter() {
+                try!(write_id(writer, (change.state.site_id, change.state.time_stamp)));
+                match change.value {
+                    MetadataValue::Filename(ref filename) => {
+                        try!(writer.write_all(&[0]));
+                        NetworkEndian::write_u32(&mut int_buf, filename.len() as u32);
+                        try!(writer.write_all(&int_buf));
+                        for component in filename.iter() {
+                            try!(write_str(writer, &mut int_buf, component));
+                        }
+                    },
+                    MetadataValue::Attribute(ref key, ref value) => {
+                        try!(writer.write_all(&[1]));
+                        try!(write_str(writer, &mut int_buf, key));
+                        try!(write_str(writer, &mut int_buf, value));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<MetadataHistory> {
+        let mut int_buf = [0;4];
+        let mut kind = [0;1];
+        try!(reader.read_exact(&mut int_buf));
+        let file_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut files = HashMap::with_capacity(preallocation(file_count));
+        for _ in 0..file_count {
+            let id = try!(read_id(reader));
+            try!(reader.read_exact(&mut int_buf));
+            let change_count = NetworkEndian::read_u32(&int_buf) as usize;
+            let mut changes = VecDeque::with_capacity(preallocation(change_count));
+            for _ in 0..change_count {
+                let (site_id, time_stamp) = try!(read_id(reader));
+                try!(reader.read_exact(&mut kind));
+                let value = match kind[0] {
+                    0 => {
+                        try!(reader.read_exact(&mut int_buf));
+                        let component_count = NetworkEndian::read_u32(&int_buf) as usize;
+                        let mut filename = Vec::with_capacity(preallocation(component_count));
+                        for _ in 0..component_count {
+                            filename.push(try!(read_str(reader, &mut int_buf)));
+                        }
+                        MetadataValue::Filename(filename)
+                    },
+                    1 => {
+                        let key = try!(read_str(reader, &mut int_buf));
+                        MetadataValue::Attribute(key, try!(read_str(reader, &mut int_buf)))
+                    },
+                    other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown metadata change kind {}", other)))
+                };
+                changes.push_back(MetadataChange {
+                    state: State {
+                        site_id: site_id,
+                        time_stamp: time_stamp
+                    },
+                    value: value
+                });
+            }
+            files.insert(id, changes);
+        }
+        Ok(MetadataHistory {
+            files: files
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MetadataHistory, MetadataValue, HISTORY_LENGTH};
+    use State;
+
+    #[test]
+    fn history_keeps_the_latest_changes() {
+        let mut history = MetadataHistory::new();
+        for time_stamp in 0..HISTORY_LENGTH as u64 + 2 {
+            history.record((1, 0), State { site_id: 3, time_stamp: time_stamp }, MetadataValue::Filename(vec![time_stamp.to_string()]));
+        }
+        history.record((1, 1), State { site_id: 2, time_stamp: 7 }, MetadataValue::Attribute("colour".to_string(), "red".to_string()));
+
+        let mut buffer = Vec::new();
+        history.compress_to(&mut buffer).unwrap();
+        let history = MetadataHistory::expand_from(&mut &buffer[..]).unwrap();
+        let changes = history.get((1, 0)).unwrap();
+        assert_eq!(changes.len(), HISTORY_LENGTH);
+        assert_eq!(changes.front().unwrap().state.time_stamp, 2);
+        assert_eq!(changes.back().unwrap().value, MetadataValue::Filename(vec![(HISTORY_LENGTH + 1).to_string()]));
+        assert_eq!(history.get((1, 1)).unwrap()[0].value, MetadataValue::Attribute("colour".to_string(), "red".to_string()));
+    }
+}
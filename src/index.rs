@@ -0,0 +1,19 @@
+use std::path::PathBuf;
+
+use super::FileID;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexChange {
+    Changed {
+        id: FileID,
+        path: PathBuf,
+        content_changed: bool
+    },
+    Removed(FileID)
+}
+
+// Indexers are handed changes in batches, with every file appearing at most
+// once per batch no matter how many times it changed in between.
+pub trait Indexer {
+    fn index(&mut self, changes: &[IndexChange]);
+}
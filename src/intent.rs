@@ -0,0 +1,191 @@
+use serialization::{read_str, write_str};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{NetworkEndian, ByteOrder};
+
+const BEGIN: u8 = 0;
+const COMPLETE: u8 = 1;
+
+const CREATE: u8 = 0;
+const REMOVE: u8 = 1;
+const MOVE: u8 = 2;
+const UPDATE: u8 = 3;
+const CREATE_DIRECTORY: u8 = 4;
+const REMOVE_DIRECTORY: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Intent {
+    Create(PathBuf),
+    Remove(PathBuf),
+    Move(PathBuf, PathBuf),
+    Update(PathBuf),
+    CreateDirectory(PathBuf),
+    RemoveDirectory(PathBuf)
+}
+
+// An append-only record of changes about to be made to the file system.
+// Anything begun but never completed was interrupted, and has to be checked
+// against the disk the next time the fileset is opened.
+pub struct IntentLog {
+    log_path: PathBuf,
+    next_sequence: u32,
+    pending: Vec<(u32, Intent)>
+}
+
+impl IntentLog {
+    pub fn open<P: AsRef<Path>>(log_path: P) -> io::Result<IntentLog> {
+        let log_path = log_path.as_ref().to_path_buf();
+        let mut pending = Vec::new();
+        let mut next_sequence = 0;
+        match fs::File::open(&log_path) {
+            Ok(mut log_file) => {
+                let mut int_buf = [0;4];
+                // A record cut short by a crash can only be the last one, and
+                // its change was never started, so stop at the first bad read
+                while let Ok((kind, sequence)) = read_header(&mut log_file, &mut int_buf) {
+                    next_sequence = sequence + 1;
+                    if kind == COMPLETE {
+                        pending.retain(|&(s, _)| s != sequence);
+                    } else {
+                        match read_intent(&mut log_file, &mut int_buf) {
+                            Ok(intent) => pending.push((sequence, intent)),
+                            Err(_) => break
+                        }
+                    }
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e)
+        }
+        trace!("Pending intents: {:?}", pending);
+        Ok(IntentLog {
+            log_path: log_path,
+            next_sequence: next_sequence,
+            pending: pending
+        })
+    }
+
+    pub fn begin(&mut self, intent: &Intent) -> io::Result<u32> {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        let mut record = Vec::new();
+        let mut int_buf = [0;4];
+        try!(write_header(&mut record, &mut int_buf, BEGIN, sequence));
+        try!(write_intent(&mut record, &mut int_buf, intent));
+        try!(self.append(&record));
+        self.pending.push((sequence, intent.clone()));
+        Ok(sequence)
+    }
+
+    pub fn complete(&mut self, sequence: u32) -> io::Result<()> {
+        self.pending.retain(|&(s, _)| s != sequence);
+        if self.pending.is_empty() {
+            // Nothing is in flight, so the whole log can be thrown away
+            self.next_sequence = 0;
+            return fs::File::create(&self.log_path).map(|_| ())
+        }
+        let mut record = Vec::new();
+        let mut int_buf = [0;4];
+        try!(write_header(&mut record, &mut int_buf, COMPLETE, sequence));
+        self.append(&record)
+    }
+
+    pub fn pending(&self) -> Vec<(u32, Intent)> {
+        self.pending.clone()
+    }
+
+    fn append(&self, record: &[u8]) -> io::Result<()> {
+        let mut log_file = try!(fs::OpenOptions::new().create(true).append(true).open(&self.log_path));
+        try!(log_file.write_all(record));
+        log_file.sync_data()
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, int_buf: &mut [u8;4], kind: u8, sequence: u32) -> io::Result<()> {
+    try!(writer.write_all(&[kind]));
+    NetworkEndian::write_u32(int_buf, sequence);
+    writer.write_all(int_buf)
+}
+
+fn read_header<R: Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<(u8, u32)> {
+    let mut kind = [0;1];
+    try!(reader.read_exact(&mut kind));
+    try!(reader.read_exact(int_buf));
+    Ok((kind[0], NetworkEndian::read_u32(int_buf)))
+}
+
+fn write_intent<W: Write>(writer: &mut W, int_buf: &mut [u8;4], intent: &Intent) -> io::Result<()> {
+    match *intent {
+        Intent::Create(ref path) => {
+            try!(writer.write_all(&[CREATE]));
+            write_str(writer, int_buf, &path.to_string_lossy())
+        },
+        Intent::Remove(ref path) => {
+            try!(writer.write_all(&[REMOVE]));
+            write_str(writer, int_buf, &path.to_string_lossy())
+        },
+        Intent::Move(ref old_path, ref new_path) => {
+            try!(writer.write_all(&[MOVE]));
+            try!(write_str(writer, int_buf, &old_path.to_string_lossy()));
+            write_str(writer, int_buf, &new_path.to_string_lossy())
+        },
+        Intent::Update(ref path) => {
+            try!(writer.write_all(&[UPDATE]));
+            write_str(writer, int_buf, &path.to_string_lossy())
+        },
+        Intent::CreateDirectory(ref path) => {
+            try!(writer.write_all(&[CREATE_DIRECTORY]));
+            write_str(writer, int_buf, &path.to_string_lossy())
+        },
+        Intent::RemoveDirectory(ref path) => {
+            try!(writer.write_all(&[REMOVE_DIRECTORY]));
+            write_str(writer, int_buf, &path.to_string_lossy())
+        }
+    }
+}
+
+fn read_intent<R: Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<Intent> {
+    let mut kind = [0;1];
+    try!(reader.read_exact(&mut kind));
+    let path = PathBuf::from(try!(read_str(reader, int_buf)));
+    match kind[0] {
+        CREATE => Ok(Intent::Create(path)),
+        REMOVE => Ok(Intent::Remove(path)),
+        MOVE => {
+            let new_path = PathBuf::from(try!(read_str(reader, int_buf)));
+            Ok(Intent::Move(path, new_path))
+        },
+        UPDATE => Ok(Intent::Update(path)),
+        CREATE_DIRECTORY => Ok(Intent::CreateDirectory(path)),
+        REMOVE_DIRECTORY => Ok(Intent::RemoveDirectory(path)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown intent"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IntentLog, Intent};
+    use std::path::PathBuf;
+    use std::fs;
+    use std::env;
+
+    #[test]
+    fn interrupted_intents_survive_reopening() {
+        let log_path = env::temp_dir().join("crdt_fileset_test_intents");
+        let _ = fs::remove_file(&log_path);
+        {
+            let mut log = IntentLog::open(&log_path).unwrap();
+            let create = log.begin(&Intent::Create(PathBuf::from("folder1/file1"))).unwrap();
+            log.begin(&Intent::Move(PathBuf::from("file2"), PathBuf::from("file3"))).unwrap();
+            log.complete(create).unwrap();
+        }
+        let mut log = IntentLog::open(&log_path).unwrap();
+        let pending = log.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].1, Intent::Move(PathBuf::from("file2"), PathBuf::from("file3")));
+        log.complete(pending[0].0).unwrap();
+        assert!(IntentLog::open(&log_path).unwrap().pending().is_empty());
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), 0);
+    }
+}
@@ -1,86 +1,346 @@
 extern crate byteorder;
+extern crate sha2;
+#[cfg(feature = "search")]
+extern crate tantivy;
 
 #[macro_use]
 extern crate log;
 
 mod serialization;
 mod lookup;
+mod events;
+mod transaction;
+mod intent;
+mod timestamp;
+mod index;
+mod roster;
+mod applied;
+mod version;
+mod report;
+mod moves;
+mod plan;
+mod limits;
+mod clock;
+mod gc;
+mod history;
+mod merge;
+mod digest;
+mod merkle;
+mod oplog;
+mod undo;
+mod snapshot;
+mod tiebreak;
+#[cfg(feature = "search")]
+mod search;
+pub mod attributes;
+pub mod prelude;
 
-use lookup::IDLookup;
-use std::collections::hash_map::{HashMap, Entry};
-use std::collections::btree_map::{BTreeMap};
+use lookup::{IDLookup, is_conflict_name};
+use attributes::EditLock;
+use intent::{IntentLog, Intent};
+use oplog::OperationLog;
+use applied::AppliedOperations;
+use moves::{MoveLog, MoveRecord, EntryName};
+use history::MetadataHistory;
+pub use events::{SyncEvent, SyncListener, ResolvedConflict, ConflictHandler};
+pub use transaction::FileSetTransaction;
+pub use timestamp::TimestampMap;
+pub use index::{Indexer, IndexChange};
+#[cfg(feature = "search")]
+pub use search::SearchIndexer;
+pub use roster::{SiteInfo, SiteRoster, random_site_id};
+pub use version::VersionVector;
+pub use report::ReconciliationReport;
+pub use plan::{ReconciliationPlan, PlannedChange};
+pub use limits::PathLimits;
+pub use clock::{HybridClock, HybridTimestamp};
+pub use history::{MetadataChange, MetadataValue};
+pub use merge::SerializedFileSet;
+pub use merkle::MerkleNode;
+pub use oplog::{LoggedOperation, OperationRecord};
+pub use tiebreak::{TieBreaker, Contender, SitePriority, GreatestValue};
+pub use serialization::SalvageReport;
+use std::collections::hash_map::{HashMap, RandomState};
+use std::collections::hash_set::HashSet;
+use std::hash::{BuildHasher, Hasher};
 use std::path::{Path, PathBuf};
 use std::ffi::OsStr;
 use std::fs;
-use std::io;
+use std::env;
+use std::io::{self, Read};
 use std::fmt;
+use std::mem;
+use std::iter;
+use std::cmp;
+use std::slice;
+use std::ops::Range;
+use std::time::{Duration, Instant, SystemTime};
 
-pub type FileID = (u32, u32);
+// Wide enough that sites can pick their ids at random, with random_site_id,
+// without ever colliding
+pub type SiteId = u128;
+
+pub type FileID = (SiteId, u64);
+
+// For sites to include in their announcements
+pub const CRATE_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 pub trait FileUpdater: fmt::Debug {
     type FileTransaction: fmt::Debug;
     fn create_file<P: AsRef<Path>>(&mut self, filename: P) -> io::Result<()>;
     fn remove_file<P: AsRef<Path>>(&mut self, filename: P) -> io::Result<()>;
-    fn update_file<P: AsRef<Path>>(&mut self, filename: P, timestamp_lookup: &BTreeMap<u32, (u32, u32)>, transaction: &mut Self::FileTransaction) -> io::Result<()>;
+    fn update_file<P: AsRef<Path>>(&mut self, filename: P, timestamp_lookup: &TimestampMap, transaction: &mut Self::FileTransaction) -> io::Result<()>;
     fn move_file<P: AsRef<Path>>(&mut self, old_filename: P, new_filename: P) -> io::Result<()>;
-    fn get_local_changes<P: AsRef<Path>>(&mut self, filename: P) -> io::Result<(Self::FileTransaction, BTreeMap<u32, (u32, u32)>)>;
-    fn get_changes_since<P: AsRef<Path>>(&self, filename: P, last_timestamp: Option<(u32, u32)>) -> Self::FileTransaction;
+    fn get_local_changes<P: AsRef<Path>>(&mut self, filename: P) -> io::Result<(Self::FileTransaction, TimestampMap)>;
+    fn get_changes_since<P: AsRef<Path>>(&self, filename: P, last_timestamp: Option<(SiteId, u64)>) -> Self::FileTransaction;
+    fn get_changes_since_vector<P: AsRef<Path>>(&self, filename: P, _seen: &VersionVector) -> Self::FileTransaction {
+        // Sending the whole history is always safe, just wasteful
+        self.get_changes_since(filename, None)
+    }
     fn get_base_path(&self) -> &Path;
+    // Checks a remote transaction can be applied, without changing anything
+    fn validate<P: AsRef<Path>>(&self, _filename: P, _transaction: &Self::FileTransaction) -> io::Result<()> {
+        Ok(())
+    }
+    fn create_directory<P: AsRef<Path>>(&mut self, dirname: P) -> io::Result<()> {
+        fs::create_dir_all(self.get_base_path().join(dirname))
+    }
+    fn remove_directory<P: AsRef<Path>>(&mut self, dirname: P) -> io::Result<()> {
+        fs::remove_dir(self.get_base_path().join(dirname))
+    }
+    // Writes the file's contents to a path outside the fileset, as of the vector if
+    // there is one. Updaters that keep no history can only give them as they are now.
+    fn export_file<P: AsRef<Path>>(&self, filename: P, _seen: Option<&VersionVector>, destination: &Path) -> io::Result<()> {
+        fs::copy(self.get_base_path().join(filename), destination).map(|_| ())
+    }
 }
 
-#[derive(Debug)]
+// Sequential ids are reused if the store is lost while peers still know about
+// files created here, so sites that can't guarantee their store should use random ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdAllocation {
+    Sequential,
+    Random
+}
+
+// What happens when a file is removed at one site while another site updates it.
+// Every site sharing a fileset has to use the same policy, or they will diverge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    RemoveWins,
+    AddWins
+}
+
+// What happens when a file is renamed at one site while another site removes it.
+// A file that is kept as a conflict goes into the resurrection folder, if there is
+// one. Every site sharing a fileset has to use the same policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenamePolicy {
+    RemoveWins,
+    RenameWins,
+    KeepAsConflict
+}
+
+// What to do with a file on disk that has a conflict suffix but isn't in the fileset,
+// left behind by a conflict rename or an integration that failed part way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrphanPolicy {
+    // Add it to the fileset like any other new file
+    Adopt,
+    // Move it out of the fileset, into the given folder. A relative folder is taken
+    // from the fileset's base path. A folder inside the base path is added to the
+    // ignored paths, which aren't stored, so it has to be added again on opening.
+    MoveInto(PathBuf)
+}
+
+// A file shown under a conflict name because another one was already at its path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictRecord {
+    pub path: PathBuf,
+    pub kept: FileID,
+    pub kept_on_disk: PathBuf,
+    pub copy: FileID,
+    pub copy_on_disk: PathBuf
+}
+
+// How a listed conflict is settled. The local file is the one that kept the path
+// here, and the remote one is the copy under a conflict name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    KeepLocal,
+    KeepRemote,
+    // The copy keeps its conflict name for good
+    KeepBoth,
+    // Both are removed, and the given file takes their path
+    MergeInto(FileID)
+}
+
+#[derive(Debug, Clone)]
 pub enum MetadataTransaction {
     Filename(Vec<String>),
     Custom(String, String),
+    CustomBatch(Vec<(String, String)>),
 }
 pub struct FileSet<FU: FileUpdater> {
-    files: HashMap<(u32, u32), FileMetadata>,
+    files: HashMap<(SiteId, u64), FileMetadata>,
     id_lookup: IDLookup,
     updater: FU,
-    last_timestamp: u32,
-    last_id: u32,
-    site_id: u32,
-    storage_path: PathBuf
+    last_timestamp: u64,
+    last_id: u64,
+    site_id: SiteId,
+    storage_path: PathBuf,
+    listeners: Vec<Box<dyn SyncListener>>,
+    conflict_handler: Option<Box<dyn ConflictHandler>>,
+    outbound_filters: HashMap<SiteId, Vec<PathBuf>>,
+    intents: IntentLog,
+    operation_log: OperationLog,
+    excluded: HashSet<FileID>,
+    generation: u64,
+    // The generation at which each file's contents last changed at this site
+    content_generations: HashMap<FileID, u64>,
+    // For each file, the latest operation from each site that changed it
+    modified_at: HashMap<FileID, VersionVector>,
+    indexers: Vec<Box<dyn Indexer>>,
+    // Files changed since the indexers were last run, and whether their contents changed
+    pending_index_changes: HashMap<FileID, bool>,
+    // How long changes have to stop for before indexers are sent them
+    index_debounce: Duration,
+    last_index_change: Option<Instant>,
+    id_allocation: IdAllocation,
+    roster: SiteRoster,
+    // Remote operations waiting for the file they refer to to be created
+    pending_operations: Vec<FileSetOperation<FU>>,
+    applied: AppliedOperations,
+    // The states trees were adopted at, here or at another site. No operation
+    // carries them, so they are passed on with merged states instead.
+    adopted: Vec<State>,
+    conflict_policy: ConflictPolicy,
+    rename_policy: RenamePolicy,
+    last_updates: HashMap<FileID, State>,
+    // Metadata of removed files, kept so that an add-wins update can bring them back
+    removed: HashMap<FileID, FileMetadata>,
+    // The state of each removal, so the tombstone can go once every site has seen it
+    removed_at: HashMap<FileID, State>,
+    // What the sites that removed files had seen, for changes arriving after the removal
+    removed_seen: HashMap<FileID, VersionVector>,
+    // The latest version vector each peer has acknowledged seeing
+    acknowledgements: HashMap<SiteId, VersionVector>,
+    merge_concurrent_creates: bool,
+    // Files merged into another file created concurrently at the same path
+    aliases: HashMap<FileID, FileID>,
+    // Remote operations the updater refused, set aside for the application to inspect
+    quarantined: Vec<FileSetOperation<FU>>,
+    folder_moves: MoveLog,
+    path_limits: PathLimits,
+    // Where files kept or brought back by add-wins go, so the user notices them
+    resurrection_folder: Option<String>,
+    clock: HybridClock,
+    metadata_history: MetadataHistory,
+    // Paths under the base path that belong to the fileset itself or the
+    // application, which are never scanned, created or written to by a sync
+    ignored_paths: Vec<PathBuf>,
+    // The latest modification time under each folder as of the last scan, so the
+    // next scan can start where things were last changing
+    scan_mtimes: HashMap<PathBuf, SystemTime>,
+    // Integrating everything, but leaving the disk alone until promoted
+    standby: bool,
+    // Operation log entries undone, or made by undoing
+    undone: HashSet<u64>,
+    tie_breaker: Box<dyn TieBreaker>
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FileMetadata {
-    filename: (u32, Vec<String>),
+    filename: (u64, Vec<String>),
     printed_filename: String,
-    attributes: HashMap<String, (u32, String)>
+    attributes: HashMap<String, (u64, String)>,
+    // The sites that made the current name and attributes, for breaking ties
+    filename_site: Option<SiteId>,
+    attribute_sites: HashMap<String, SiteId>,
+    // When the current name and attributes were set by the hybrid clock, which
+    // decides which of two changes to them was made last
+    filename_time: HybridTimestamp,
+    attribute_times: HashMap<String, HybridTimestamp>
 }
 
 pub struct FileHistory<FU: FileUpdater> {
-    pub filename: (u32, Vec<String>),
-    pub attributes: HashMap<String, (u32, String)>,
+    pub filename: (u64, Vec<String>),
+    pub attributes: HashMap<String, (u64, String)>,
     pub operation_history: FU::FileTransaction
 }
 
-#[derive(Debug)]
+// A state also serves as the correlation id of the operation carrying it,
+// since no two operations generated by the same site share a timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct State {
-    pub time_stamp: u32,
-    pub site_id: u32,
+    pub time_stamp: u64,
+    pub site_id: SiteId,
 }
 
+#[derive(Debug)]
 pub enum FileSetError {
     IOError(io::Error),
-    IDNotFound(u32, u32)
+    IDNotFound(SiteId, u64),
+    PathNotFound(PathBuf),
+    TimestampConflict(u64),
+    ReservedAttribute(String),
+    InvalidTransaction(io::Error),
+    PathLimitExceeded(PathBuf),
+    PathLimitsDiffer(SiteId),
+    IDsExhausted,
+    PathIgnored(PathBuf),
+    DirectoryNotEmpty(PathBuf),
+    NotConflictCopies(FileID, FileID),
+    HistoryCompacted,
+    InsideFileSet(PathBuf),
+    InOperation(OperationContext, Box<FileSetError>)
+}
+
+#[derive(Debug, Clone)]
+pub struct OperationContext {
+    pub operation: String,
+    pub site_id: Option<SiteId>,
+    pub path: Option<PathBuf>,
+    // The software the originating site last announced it was running
+    pub producer: Option<String>
+}
+
+// The software that generated an operation, so that when a later version gets
+// an earlier one's operations wrong, it can tell which version made them
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Producer {
+    pub crate_version: String,
+    pub application_version: String
 }
 
 #[derive(Debug)]
 pub struct CreateOperation {
     pub state: State,
+    pub producer: Producer,
+    // Stamped by the hybrid clock of the site making the change, for deciding
+    // which of two changes to a name or attribute was made last
+    pub hybrid_time: HybridTimestamp,
     pub filename: Vec<String>,
-    pub id: FileID
+    pub id: FileID,
+    pub directory: bool
 }
 
 #[derive(Debug)]
 pub struct RemoveOperation {
-    pub id: FileID
+    pub state: State,
+    pub producer: Producer,
+    pub id: FileID,
+    // The last update to the file the removing site knew about
+    pub last_update: Option<State>,
+    // Everything the removing site had seen when it removed the file, which tells
+    // which changes it couldn't have known about
+    pub seen: VersionVector
 }
 
 #[derive(Debug)]
 pub struct UpdateOperation<FU: FileUpdater> {
+    pub state: State,
+    pub producer: Producer,
     pub id: FileID,
     pub data: FU::FileTransaction
 }
@@ -88,20 +348,50 @@ pub struct UpdateOperation<FU: FileUpdater> {
 #[derive(Debug)]
 pub struct UpdateMetadata {
     pub state: State,
+    pub producer: Producer,
+    pub hybrid_time: HybridTimestamp,
     pub id: FileID,
     pub data: MetadataTransaction
 }
+
+#[derive(Debug, Clone)]
+pub struct FolderMove {
+    pub state: State,
+    pub producer: Producer,
+    // Orders folder moves after every folder move the moving site had seen
+    pub clock: u64,
+    pub hybrid_time: HybridTimestamp,
+    pub seen: VersionVector,
+    pub old_path: Vec<String>,
+    pub new_path: Vec<String>,
+    // The new name of everything that was inside the folder when it was moved
+    pub files: Vec<(FileID, Vec<String>)>
+}
+
+#[derive(Debug)]
+pub struct SiteAnnouncement {
+    pub state: State,
+    pub producer: Producer,
+    pub site_id: SiteId,
+    pub info: SiteInfo,
+    // The limits of the announcing site, which every site it syncs with has to share
+    pub path_limits: PathLimits
+}
+
 #[derive(Debug)]
 pub enum FileSetOperation<FU:FileUpdater> {
     Create(CreateOperation),
     Remove(RemoveOperation),
-    Update(UpdateOperation<FU>, BTreeMap<u32, (u32, u32)>),
+    Update(UpdateOperation<FU>, TimestampMap),
     UpdateMetadata(UpdateMetadata),
+    MoveFolder(FolderMove),
+    Bundle(Vec<FileSetOperation<FU>>),
+    AnnounceSite(SiteAnnouncement),
 }
 
 impl<FU: FileUpdater> FileHistory<FU> {
     #[inline]
-    pub fn new(filename_timestamp: u32, filename: Vec<String>, attributes: HashMap<String, (u32, String)>, operations: FU::FileTransaction) -> FileHistory<FU> {
+    pub fn new(filename_timestamp: u64, filename: Vec<String>, attributes: HashMap<String, (u64, String)>, operations: FU::FileTransaction) -> FileHistory<FU> {
         FileHistory {
             filename: (filename_timestamp, filename),
             attributes: attributes,
@@ -110,7 +400,95 @@ impl<FU: FileUpdater> FileHistory<FU> {
     }
 }
 
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.site_id, self.time_stamp)
+    }
+}
+
+impl<FU: FileUpdater> FileSetOperation<FU> {
+    pub fn state(&self) -> Option<&State> {
+        match *self {
+            FileSetOperation::Create(ref o) => Some(&o.state),
+            FileSetOperation::Remove(ref o) => Some(&o.state),
+            FileSetOperation::Update(ref o, _) => Some(&o.state),
+            FileSetOperation::UpdateMetadata(ref o) => Some(&o.state),
+            FileSetOperation::MoveFolder(ref o) => Some(&o.state),
+            FileSetOperation::Bundle(_) => None,
+            FileSetOperation::AnnounceSite(ref o) => Some(&o.state)
+        }
+    }
+
+    pub fn producer(&self) -> Option<&Producer> {
+        match *self {
+            FileSetOperation::Create(ref o) => Some(&o.producer),
+            FileSetOperation::Remove(ref o) => Some(&o.producer),
+            FileSetOperation::Update(ref o, _) => Some(&o.producer),
+            FileSetOperation::UpdateMetadata(ref o) => Some(&o.producer),
+            FileSetOperation::MoveFolder(ref o) => Some(&o.producer),
+            FileSetOperation::Bundle(_) => None,
+            FileSetOperation::AnnounceSite(ref o) => Some(&o.producer)
+        }
+    }
+
+    pub fn hybrid_time(&self) -> Option<HybridTimestamp> {
+        match *self {
+            FileSetOperation::Create(ref o) => Some(o.hybrid_time),
+            FileSetOperation::UpdateMetadata(ref o) => Some(o.hybrid_time),
+            FileSetOperation::MoveFolder(ref o) => Some(o.hybrid_time),
+            FileSetOperation::Bundle(ref o) => o.iter().filter_map(|operation| operation.hybrid_time()).max(),
+            _ => None
+        }
+    }
+}
+
 impl FileMetadata {
+    fn new_entry(filename: Vec<String>, printed_filename: String, directory: bool, state: &State, hybrid_time: HybridTimestamp) -> FileMetadata {
+        let attributes = entry_attributes(directory, state);
+        FileMetadata {
+            filename: (state.time_stamp, filename),
+            printed_filename: printed_filename,
+            attribute_sites: attributes.keys().map(|key| (key.clone(), state.site_id)).collect(),
+            attribute_times: attributes.keys().map(|key| (key.clone(), hybrid_time)).collect(),
+            attributes: attributes,
+            filename_site: Some(state.site_id),
+            filename_time: hybrid_time
+        }
+    }
+
+    fn set_filename(&mut self, filename: Vec<String>, state: &State, hybrid_time: HybridTimestamp) {
+        self.filename = (state.time_stamp, filename);
+        self.filename_site = Some(state.site_id);
+        self.filename_time = hybrid_time;
+    }
+
+    fn entry_name(&self) -> EntryName {
+        EntryName {
+            filename: self.filename.clone(),
+            site_id: self.filename_site,
+            hybrid_time: self.filename_time
+        }
+    }
+
+    fn set_entry_name(&mut self, name: EntryName) {
+        self.filename = name.filename;
+        self.filename_site = name.site_id;
+        self.filename_time = name.hybrid_time;
+    }
+
+    fn filename_state(&self) -> Option<State> {
+        self.filename_site.map(|site_id| State {
+            site_id: site_id,
+            time_stamp: self.filename.0
+        })
+    }
+
+    fn set_attribute(&mut self, key: String, value: String, state: &State, hybrid_time: HybridTimestamp) {
+        self.attribute_sites.insert(key.clone(), state.site_id);
+        self.attribute_times.insert(key.clone(), hybrid_time);
+        self.attributes.insert(key, (state.time_stamp, value));
+    }
+
     fn get_local_filename(&self) -> PathBuf {
         let mut path = PathBuf::new();
         for component in self.filename.1[0..self.filename.1.len() - 1].iter() {
@@ -123,360 +501,4492 @@ impl FileMetadata {
     pub fn get_file_path(&self)-> &Vec<String> {
         &self.filename.1
     }
-    pub fn get_file_timestamp(&self) -> u32 {
+    pub fn get_file_timestamp(&self) -> u64 {
         self.filename.0
     }
+
+    pub fn is_conflicted(&self) -> bool {
+        self.filename.1.last().map_or(false, |name| *name != self.printed_filename)
+    }
+
+    fn create_intent(&self) -> Intent {
+        if self.is_directory() {
+            Intent::CreateDirectory(self.get_local_filename())
+        } else {
+            Intent::Create(self.get_local_filename())
+        }
+    }
+
+    fn remove_intent(&self) -> Intent {
+        if self.is_directory() {
+            Intent::RemoveDirectory(self.get_local_filename())
+        } else {
+            Intent::Remove(self.get_local_filename())
+        }
+    }
 }
 
 impl<FU: FileUpdater> FileSet<FU> {
-    pub fn new<P: AsRef<Path>>(updater: FU, site_id: u32, storage_path: P) -> io::Result<FileSet<FU>> {
-        let storage_path = storage_path.as_ref().to_path_buf();
-        match fs::File::open(storage_path.join("crdt").as_path()) {
+    pub fn new<P: AsRef<Path>>(updater: FU, site_id: SiteId, storage_path: P) -> io::Result<FileSet<FU>> {
+        FileSet::with_id_allocation(updater, site_id, storage_path, IdAllocation::Sequential)
+    }
+
+    pub fn with_id_allocation<P: AsRef<Path>>(updater: FU, site_id: SiteId, storage_path: P, id_allocation: IdAllocation) -> io::Result<FileSet<FU>> {
+        FileSet::open_store(updater, site_id, storage_path.as_ref(), id_allocation, false).map(|(fileset, _)| fileset)
+    }
+
+    // Like with_id_allocation, but first checks the path index against the file
+    // table, and rebuilds it if they disagree. The check walks every file, so it
+    // is for opening after a crash or when something looks wrong, not every start.
+    pub fn open_verified<P: AsRef<Path>>(updater: FU, site_id: SiteId, storage_path: P, id_allocation: IdAllocation) -> io::Result<FileSet<FU>> {
+        let mut fileset = try!(FileSet::with_id_allocation(updater, site_id, storage_path, id_allocation));
+        fileset.repair_index();
+        Ok(fileset)
+    }
+
+    // Opens a store that failed to open normally, keeping as much of it as can be
+    // read. Whatever was salvaged is saved straight away, so the damage is gone
+    // the next time the store is opened.
+    pub fn salvage<P: AsRef<Path>>(updater: FU, site_id: SiteId, storage_path: P, id_allocation: IdAllocation) -> io::Result<(FileSet<FU>, SalvageReport)> {
+        let (mut fileset, report) = try!(FileSet::open_store(updater, site_id, storage_path.as_ref(), id_allocation, true));
+        fileset.repair_index();
+        if !report.is_intact() {
+            warn!("Salvaged {} files from the store, losing {}", report.recovered_files, report.lost_files);
+            try!(fileset.save());
+        }
+        Ok((fileset, report))
+    }
+
+    fn open_store(updater: FU, site_id: SiteId, storage_path: &Path, id_allocation: IdAllocation, salvage: bool) -> io::Result<(FileSet<FU>, SalvageReport)> {
+        let storage_path = storage_path.to_path_buf();
+        let (mut fileset, report) = match fs::File::open(storage_path.join("crdt").as_path()) {
             Ok(mut store_file) => {
-                FileSet::expand_from(&mut store_file, updater, storage_path)
+                if salvage {
+                    try!(FileSet::salvage_from(&mut store_file, updater, storage_path))
+                } else {
+                    (try!(FileSet::expand_from(&mut store_file, updater, storage_path)), SalvageReport::default())
+                }
             },
             Err(_) => {
-                Ok(FileSet{
+                (FileSet{
                     files: HashMap::new(),
                     id_lookup: IDLookup::new(),
                     site_id: site_id,
                     last_timestamp: 0,
                     last_id: 0,
                     updater: updater,
-                    storage_path: storage_path.to_path_buf()
-                })
+                    intents: try!(IntentLog::open(storage_path.join("intents"))),
+                    operation_log: try!(OperationLog::open(storage_path.join("oplog"))),
+                    storage_path: storage_path,
+                    listeners: Vec::new(),
+                    conflict_handler: None,
+                    outbound_filters: HashMap::new(),
+                    excluded: HashSet::new(),
+                    generation: 0,
+                    content_generations: HashMap::new(),
+                    modified_at: HashMap::new(),
+                    indexers: Vec::new(),
+                    pending_index_changes: HashMap::new(),
+                    index_debounce: Duration::from_secs(0),
+                    last_index_change: None,
+                    id_allocation: id_allocation,
+                    roster: SiteRoster::new(),
+                    pending_operations: Vec::new(),
+                    applied: AppliedOperations::new(),
+                    adopted: Vec::new(),
+                    conflict_policy: ConflictPolicy::RemoveWins,
+                    rename_policy: RenamePolicy::RemoveWins,
+                    last_updates: HashMap::new(),
+                    removed: HashMap::new(),
+                    removed_at: HashMap::new(),
+                    removed_seen: HashMap::new(),
+                    acknowledgements: HashMap::new(),
+                    merge_concurrent_creates: false,
+                    aliases: HashMap::new(),
+                    quarantined: Vec::new(),
+                    folder_moves: MoveLog::new(),
+                    path_limits: PathLimits::unlimited(),
+                    resurrection_folder: None,
+                    clock: HybridClock::new(),
+                    metadata_history: MetadataHistory::new(),
+                    ignored_paths: Vec::new(),
+                    scan_mtimes: HashMap::new(),
+                    standby: false,
+                    undone: HashSet::new(),
+                    tie_breaker: Box::new(SitePriority)
+                }, SalvageReport::default())
             }
+        };
+        fileset.id_allocation = id_allocation;
+        let storage_path = fileset.storage_path.clone();
+        fileset.add_ignored_path(storage_path);
+        try!(fileset.recover_intents());
+        Ok((fileset, report))
+    }
+
+    fn repair_index(&mut self) {
+        let damaged = self.verify_index();
+        if !damaged.is_empty() {
+            warn!("Path index is inconsistent for {:?}, rebuilding", damaged);
+            self.rebuild_index();
         }
     }
 
     pub fn integrate_remote(&mut self, remote: FileSetOperation<FU>) -> Result<(), FileSetError> {
-        let result = match remote {
-            FileSetOperation::Create(o) => self.integrate_create(o),
-            FileSetOperation::Remove(o) => self.integrate_remove(o),
-            FileSetOperation::Update(mut o, lookup) => self.integrate_update(&mut o, &lookup),
-            FileSetOperation::UpdateMetadata(o) => self.integrate_update_metadata(o),
-        };
+        if let Some(id) = self.get_missing_predecessor(&remote) {
+            // Operations can overtake the create they depend on, so hold them until it arrives
+            trace!("Holding {} until {:?} is created", self.operation_context(&remote).operation, id);
+            self.pending_operations.push(remote);
+            return Ok(())
+        }
+        let result = self.integrate_operation(remote);
+        if result.is_ok() {
+            self.replay_pending();
+        }
         self.save().unwrap();
+        // Everything a bundle logged goes to the disk together
+        self.sync_log();
         result
 
     }
 
+    pub fn set_conflict_policy(&mut self, conflict_policy: ConflictPolicy) {
+        self.conflict_policy = conflict_policy;
+    }
+
+    pub fn get_conflict_policy(&self) -> ConflictPolicy {
+        self.conflict_policy
+    }
+
+    pub fn set_rename_policy(&mut self, rename_policy: RenamePolicy) {
+        self.rename_policy = rename_policy;
+    }
+
+    pub fn get_rename_policy(&self) -> RenamePolicy {
+        self.rename_policy
+    }
+
+    // Every site has to use the same folder, just like the conflict policy
+    pub fn set_resurrection_folder(&mut self, resurrection_folder: Option<String>) {
+        self.resurrection_folder = resurrection_folder;
+    }
+
+    pub fn get_resurrection_folder(&self) -> Option<&str> {
+        self.resurrection_folder.as_ref().map(|folder| folder.as_str())
+    }
+
+    // The hybrid time of the latest local change. Operations already carry theirs,
+    // so this is for ordering whatever the application sends alongside them
+    pub fn get_hybrid_time(&self) -> HybridTimestamp {
+        self.clock.get_last()
+    }
+
+    pub fn observe_hybrid_time(&mut self, remote: HybridTimestamp) {
+        self.clock.observe(remote);
+    }
+
+    // Every site sharing the fileset has to use the same one
+    pub fn set_tie_breaker<T: TieBreaker + 'static>(&mut self, tie_breaker: T) {
+        self.tie_breaker = Box::new(tie_breaker);
+    }
+
+    pub fn set_merge_concurrent_creates(&mut self, merge_concurrent_creates: bool) {
+        self.merge_concurrent_creates = merge_concurrent_creates;
+    }
+
+    // Sites announce the limits they use, and refuse every operation from a site
+    // that announced different ones, so the limits are set before announcing
+    pub fn set_path_limits(&mut self, path_limits: PathLimits) {
+        self.path_limits = path_limits;
+        self.id_lookup.set_max_name_length(path_limits.max_name_length);
+    }
+
+    pub fn get_path_limits(&self) -> PathLimits {
+        self.path_limits
+    }
+
+    pub fn take_quarantined(&mut self) -> Vec<FileSetOperation<FU>> {
+        mem::replace(&mut self.quarantined, Vec::new())
+    }
+
+    pub fn get_pending_operation_count(&self) -> usize {
+        self.pending_operations.len()
+    }
+
+    // Gives held operations until the timeout to be released, sends the indexers
+    // whatever they haven't seen and writes the store out to disk. Held and
+    // quarantined operations aren't stored, so whatever is left of them is handed
+    // back for the application to keep or ask for again.
+    pub fn shutdown(mut self, timeout: Duration) -> Result<Vec<FileSetOperation<FU>>, FileSetError> {
+        trace!("Shutting down with {} held operations", self.pending_operations.len());
+        let deadline = Instant::now() + timeout;
+        self.replay_pending_until(Some(deadline));
+        self.flush_index_changes();
+        try!(self.save().and_then(|_| fs::File::open(self.storage_path.join("crdt"))).and_then(|store_file| store_file.sync_all()).map_err(|e| FileSetError::IOError(e)));
+        let mut unpersisted = mem::replace(&mut self.pending_operations, Vec::new());
+        unpersisted.append(&mut self.quarantined);
+        if !unpersisted.is_empty() {
+            warn!("Shut down with {} operations that were not stored", unpersisted.len());
+        }
+        Ok(unpersisted)
+    }
+
+    pub fn begin_transaction<'a>(&'a mut self) -> FileSetTransaction<'a, FU> {
+        FileSetTransaction::new(self)
+    }
+
+    pub fn add_sync_listener<L: SyncListener + 'static>(&mut self, listener: L) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    pub fn set_conflict_handler<H: ConflictHandler + 'static>(&mut self, handler: H) {
+        self.conflict_handler = Some(Box::new(handler));
+    }
+
+    pub fn add_indexer<I: Indexer + 'static>(&mut self, indexer: I) {
+        self.indexers.push(Box::new(indexer));
+    }
+
+    // Batches normally end with each reconciliation or file list integration. With
+    // a debounce, they are only sent once that long has passed without a change,
+    // so a busy tree isn't indexed over and over. Either way, flush_index_changes
+    // sends whatever is waiting straight away.
+    pub fn set_index_debounce(&mut self, debounce: Duration) {
+        self.index_debounce = debounce;
+    }
+
+    // For calling from a timer, to send changes that have settled since the last batch
+    pub fn flush_due_index_changes(&mut self) {
+        if self.last_index_change.map_or(false, |last_change| last_change.elapsed() >= self.index_debounce) {
+            self.flush_index_changes();
+        }
+    }
+
+    pub fn flush_index_changes(&mut self) {
+        self.last_index_change = None;
+        if self.pending_index_changes.is_empty() {
+            return
+        }
+        let pending = mem::replace(&mut self.pending_index_changes, HashMap::new());
+        let changes: Vec<IndexChange> = pending.into_iter().map(|(id, content_changed)| {
+            match self.files.get(&id) {
+                Some(file_metadata) if !self.excluded.contains(&id) => IndexChange::Changed {
+                    id: id,
+                    path: file_metadata.get_local_filename(),
+                    content_changed: content_changed
+                },
+                _ => IndexChange::Removed(id)
+            }
+        }).collect();
+        trace!("Sending {} changes to indexers", changes.len());
+        for indexer in self.indexers.iter_mut() {
+            indexer.index(&changes);
+        }
+    }
+
     pub fn has_path(&self, path: &PathBuf) -> bool {
-        self.id_lookup.get_id_for(path.iter()).is_some()
+        !self.is_ignored(path) && self.id_lookup.get_id_for(path.iter()).is_some()
+    }
+
+    // The path may be relative to the base path or absolute. The storage path is
+    // always ignored, and the application can add its own, such as a trash folder.
+    pub fn add_ignored_path<P: AsRef<Path>>(&mut self, path: P) {
+        let path = self.relative_to_base(path.as_ref());
+        if !self.ignored_paths.contains(&path) {
+            self.ignored_paths.push(path);
+        }
+    }
+
+    pub fn get_ignored_paths(&self) -> &[PathBuf] {
+        &self.ignored_paths
+    }
+
+    pub fn is_ignored<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = self.relative_to_base(path.as_ref());
+        self.ignored_paths.iter().any(|ignored| path.starts_with(ignored))
+    }
+
+    fn relative_to_base(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(self.updater.get_base_path()).unwrap_or(path).to_path_buf()
+    }
+
+    pub fn resolve_on_disk(&self, path: &Path) -> Option<PathBuf> {
+        // Files with the same logical path are siblings in the lookup, so only the
+        // parent directory needs searching. Prefer the copy that kept its name.
+        let filename: Vec<String> = path.iter().map(|c| c.to_string_lossy().into_owned()).collect();
+        let mut candidates: Vec<&FileMetadata> = self.id_lookup.get_child_ids(path.parent().unwrap_or(Path::new("")).iter())
+            .iter()
+            .filter_map(|id| self.files.get(id))
+            .filter(|md| md.filename.1 == filename)
+            .collect();
+        candidates.sort_by_key(|md| (md.is_conflicted(), md.printed_filename.clone()));
+        candidates.first().map(|md| md.get_local_filename())
+    }
+
+    pub fn logical_for_on_disk(&self, path: &Path) -> Option<PathBuf> {
+        self.id_lookup.get_id_for(path.iter())
+            .and_then(|id| self.files.get(&id))
+            .map(|md| md.filename.1.iter().collect())
     }
 
-    pub fn process_create(&mut self, path: &Path) -> FileSetOperation<FU> {
+    pub fn process_create(&mut self, path: &Path) -> Result<FileSetOperation<FU>, FileSetError> {
         trace!("Processing create on {:?}", path);
+        self.create_entry(path, false)
+    }
+
+    pub fn process_create_directory(&mut self, path: &Path) -> Result<FileSetOperation<FU>, FileSetError> {
+        trace!("Processing directory create on {:?}", path);
+        self.create_entry(path, true)
+    }
+
+    fn create_entry(&mut self, path: &Path, directory: bool) -> Result<FileSetOperation<FU>, FileSetError> {
+        if self.is_ignored(path) {
+            return Err(FileSetError::PathIgnored(path.to_path_buf()))
+        }
+        try!(self.check_path_limits(path));
         let path = path.to_path_buf();
         let filename: Vec<&OsStr> = path.into_iter().collect();
-        let id = self.get_next_id();
+        let id = try!(self.get_next_id());
         let state = self.create_state();
+        let hybrid_time = self.clock.get_last();
         let printed = self.id_lookup.add_file(filename.clone().into_iter(), (self.site_id, id), self.site_id);
         let filename:Vec<_> = filename.iter().map(|c| c.to_str().unwrap().to_string()).collect();
-        self.files.insert((self.site_id, id), FileMetadata {
-            filename: (state.time_stamp, filename.clone()),
-            printed_filename: printed,
-            attributes: HashMap::new()
-        });
+        self.files.insert((self.site_id, id), FileMetadata::new_entry(filename.clone(), printed, directory, &state, hybrid_time));
+        self.record_change((self.site_id, id), !directory);
         self.save().unwrap();
-        FileSetOperation::Create(CreateOperation {
+        trace!("Generated create {}", state);
+        Ok(self.logged(FileSetOperation::Create(CreateOperation {
             state: state,
+            producer: self.producer(),
+            hybrid_time: hybrid_time,
             id: (self.site_id, id),
-            filename: filename
-        })
+            filename: filename,
+            directory: directory
+        })))
     }
 
     pub fn process_remove(&mut self, path: &Path) -> FileSetOperation<FU> {
         trace!("Processing remove on {:?}", path);
         let (site_id, id) = self.id_lookup.remove_file(path).unwrap();
-        self.files.remove(&(self.site_id, id));
+        let seen = self.get_version_vector();
+        let state = self.create_state();
+        let last_update = self.bury((site_id, id), state, seen.clone());
+        self.record_change((site_id, id), false);
         self.save().unwrap();
-        FileSetOperation::Remove(RemoveOperation {
+        trace!("Generated remove {}", state);
+        self.logged(FileSetOperation::Remove(RemoveOperation {
+            state: state,
+            producer: self.producer(),
             id: (site_id, id),
-        })
+            last_update: last_update,
+            seen: seen
+        }))
     }
 
     pub fn process_remove_folder(&mut self, path: &Path) -> Vec<FileSetOperation<FU>> {
         trace!("Processing remove on {:?}", path);
         let ids = self.id_lookup.remove_folder(path);
-        for id in ids.iter() {
-            self.files.remove(id);
+        let mut operations = Vec::with_capacity(ids.len());
+        for id in ids.into_iter() {
+            let seen = self.get_version_vector();
+            let state = self.create_state();
+            let last_update = self.bury(id, state, seen.clone());
+            self.record_change(id, false);
+            trace!("Generated remove {}", state);
+            operations.push(self.logged(FileSetOperation::Remove(RemoveOperation{
+                state: state,
+                producer: self.producer(),
+                id: id,
+                last_update: last_update,
+                seen: seen
+            })));
         }
         self.save().unwrap();
-        ids.into_iter().map(|id| FileSetOperation::Remove(RemoveOperation{
-            id: id
-        })).collect()
+        operations
     }
 
-    pub fn process_update(&mut self, path: &Path, transaction: FU::FileTransaction, timestamp_lookup: BTreeMap<u32, (u32, u32)>) -> FileSetOperation<FU> {
+    pub fn process_update(&mut self, path: &Path, transaction: FU::FileTransaction, timestamp_lookup: TimestampMap) -> FileSetOperation<FU> {
         trace!("Processing update on {:?}", path);
         let (site_id, id) = self.id_lookup.get_id_for(path).unwrap();
+        let state = self.create_state();
+        self.last_updates.insert((site_id, id), state);
+        self.classify_content((site_id, id), &state);
+        self.record_change((site_id, id), true);
         self.save().unwrap();
-        FileSetOperation::Update(UpdateOperation{
+        trace!("Generated update {}", state);
+        self.logged(FileSetOperation::Update(UpdateOperation{
+            state: state,
+            producer: self.producer(),
             id: (site_id, id),
             data: transaction
-        }, timestamp_lookup)
+        }, timestamp_lookup))
     }
 
-    pub fn process_file_move(&mut self, old_path: &Path, new_path: &Path) -> FileSetOperation<FU> {
+    pub fn process_file_move(&mut self, old_path: &Path, new_path: &Path) -> Result<FileSetOperation<FU>, FileSetError> {
         trace!("Processing file_move on {:?}", old_path);
+        try!(self.check_path_limits(new_path));
         let (site_id, id) = self.id_lookup.remove_file(old_path).unwrap();
         let state = self.create_state();
+        let hybrid_time = self.clock.get_last();
         let printed = self.id_lookup.add_file(new_path, (site_id, id), site_id);
         let filename:Vec<_> = new_path.iter().map(|c| c.to_str().unwrap().to_string()).collect();
         {
             let metadata = self.files.get_mut(&(site_id, id)).unwrap();
-            metadata.filename = (state.time_stamp, filename.clone());
+            metadata.set_filename(filename.clone(), &state, hybrid_time);
             metadata.printed_filename = printed;
         }
+        self.metadata_history.record((site_id, id), state, MetadataValue::Filename(filename.clone()));
+        self.record_change((site_id, id), false);
         self.save().unwrap();
-        FileSetOperation::UpdateMetadata(UpdateMetadata {
+        trace!("Generated move {}", state);
+        Ok(self.logged(FileSetOperation::UpdateMetadata(UpdateMetadata {
             state: state,
+            producer: self.producer(),
+            hybrid_time: hybrid_time,
             id: (site_id, id),
             data: MetadataTransaction::Filename(filename)
-        })
+        })))
     }
 
-    pub fn get_changes_since(&self, timestamp: Option<(u32, u32)>) -> HashMap<(u32, u32), FileHistory<FU>> {
-        self.files.iter().map(|(&key, file_metadata)| {
-            (key, FileHistory {
-                filename: file_metadata.filename.clone(),
-                attributes: file_metadata.attributes.clone(),
-                operation_history: self.updater.get_changes_since(file_metadata.get_local_filename().as_path(), timestamp)
+    // Every conflict copy that still has another file at its path, which can be
+    // settled with resolve_conflict. Copies stop being listed once either of them
+    // is renamed or removed.
+    pub fn list_conflicts(&self) -> Vec<ConflictRecord> {
+        let mut conflicts: Vec<ConflictRecord> = self.files.iter().filter(|&(id, file_metadata)| {
+            file_metadata.is_conflicted() && !self.excluded.contains(id)
+        }).filter_map(|(&copy, file_metadata)| {
+            let kept = match self.id_lookup.get_id_for(file_metadata.filename.1.iter().map(OsStr::new)) {
+                Some(kept) if kept != copy => kept,
+                _ => return None
+            };
+            Some(ConflictRecord {
+                path: file_metadata.filename.1.iter().collect(),
+                kept: kept,
+                kept_on_disk: self.files[&kept].get_local_filename(),
+                copy: copy,
+                copy_on_disk: file_metadata.get_local_filename()
             })
-        }).collect()
+        }).collect();
+        conflicts.sort_by(|a, b| compare_paths(&a.copy_on_disk, &b.copy_on_disk));
+        conflicts
     }
 
-    pub fn get_all_files(&self) -> &HashMap<(u32, u32), FileMetadata> {
-        &self.files
+    // Settles the conflict the copy is listed under, on disk as well as in the
+    // fileset, and returns the operations to send on
+    pub fn resolve_conflict(&mut self, copy: FileID, resolution: Resolution) -> Result<Vec<FileSetOperation<FU>>, FileSetError> {
+        let record = match self.list_conflicts().into_iter().find(|record| record.copy == copy) {
+            Some(record) => record,
+            None => return Err(FileSetError::IDNotFound(copy.0, copy.1))
+        };
+        trace!("Resolving {:?} with {:?}", record, resolution);
+        match resolution {
+            Resolution::KeepLocal => self.merge_conflict_copies(record.kept, record.copy),
+            Resolution::KeepRemote => self.merge_conflict_copies(record.copy, record.kept),
+            Resolution::MergeInto(target) if target == record.kept => self.merge_conflict_copies(record.kept, record.copy),
+            Resolution::MergeInto(target) if target == record.copy => self.merge_conflict_copies(record.copy, record.kept),
+            Resolution::KeepBoth => {
+                // Naming the copy after itself makes its conflict name the real one
+                let operation = try!(self.process_file_move(&record.copy_on_disk, &record.copy_on_disk));
+                Ok(vec![operation])
+            },
+            Resolution::MergeInto(target) => {
+                let target_path = match self.files.get(&target) {
+                    Some(file_metadata) if !self.excluded.contains(&target) => file_metadata.get_local_filename(),
+                    _ => return Err(FileSetError::IDNotFound(target.0, target.1))
+                };
+                let mut operations = Vec::with_capacity(3);
+                for &(id, on_disk) in [(record.kept, &record.kept_on_disk), (record.copy, &record.copy_on_disk)].iter() {
+                    let intent = self.files[&id].remove_intent();
+                    operations.push(self.process_remove(on_disk));
+                    try!(self.apply_intent(intent).map_err(|e| FileSetError::IOError(e)));
+                }
+                let mut previous_paths = HashMap::new();
+                previous_paths.insert(target, target_path.clone());
+                operations.push(try!(self.process_file_move(&target_path, &record.path)));
+                try!(self.move_entries_on_disk(previous_paths, None));
+                Ok(operations)
+            }
+        }
     }
 
-    pub fn get_file_history_for(&self, file: (u32, u32)) -> Option<FU::FileTransaction> {
-        if let Some(file_metadata) = self.files.get(&file) {
-            Some(self.updater.get_changes_since(file_metadata.get_local_filename().as_path(), None))
-        } else {
-            None
+    // Collapses two conflict copies of a file into one, keeping the history of the
+    // one kept. If the application or the user hasn't already done so, the discarded
+    // copy is deleted from the disk and the kept one is moved to where it now belongs.
+    // The two have to be different files that are both in the fileset at the same path.
+    pub fn merge_conflict_copies(&mut self, keep: FileID, discard: FileID) -> Result<Vec<FileSetOperation<FU>>, FileSetError> {
+        trace!("Merging conflict copy {:?} into {:?}", discard, keep);
+        let same_path = match (self.files.get(&keep), self.files.get(&discard)) {
+            (Some(kept), Some(discarded)) => kept.filename.1 == discarded.filename.1,
+            _ => false
+        };
+        if keep == discard || !same_path {
+            return Err(FileSetError::NotConflictCopies(keep, discard))
         }
-    }
+        let old_keep = try!(get_target(&mut self.files, keep)).get_local_filename();
+        let old_discard = try!(get_target(&mut self.files, discard)).get_local_filename();
+        self.id_lookup.remove_file(old_keep.iter());
+        self.id_lookup.remove_file(old_discard.iter());
+        let seen = self.get_version_vector();
+        let remove_state = self.create_state();
+        let last_update = self.bury(discard, remove_state, seen.clone());
+        self.record_change(discard, false);
 
-    pub fn integrate_remote_file_list(&mut self, mut file_list: HashMap<(u32, u32), FileHistory<FU>>, timestamp_lookup: BTreeMap<u32, (u32, u32)>) -> Vec<FileSetOperation<FU>> {
-        // Recursively go through every file in the directory
-        // If the file is in the local list,
-        //      If the file is also in the remote list, then process local changes
-        // Otherwise, create the file in the list, and process the local changes
-        let mut operations = Vec::new();
+        // Naming the kept copy again gives it the name the discarded one was using,
+        // here and, with a newer timestamp, everywhere else
+        let rename_state = self.create_state();
+        let hybrid_time = self.clock.get_last();
+        let filename = self.files[&keep].filename.1.clone();
+        let printed = self.id_lookup.add_file(filename.iter().map(OsStr::new), keep, keep.0);
+        let new_keep = {
+            let metadata = self.files.get_mut(&keep).unwrap();
+            metadata.set_filename(filename.clone(), &rename_state, hybrid_time);
+            metadata.printed_filename = printed;
+            metadata.get_local_filename()
+        };
+        self.metadata_history.record(keep, rename_state, MetadataValue::Filename(filename.clone()));
+        self.record_change(keep, false);
         let base_path = self.updater.get_base_path().to_path_buf();
-        self.scan_dir(base_path.as_path(), base_path.as_path(), &mut file_list, &timestamp_lookup, &mut operations).unwrap();
-        // For each file in the local list, if it is not in the remote list, then delete the file in the local list and on the file system
-        trace!("Current files are: {:?}", self.files);
-        let mut new_file_list = HashMap::new();
-        for ((site_id, id), file) in self.files.drain() {
-            if file_list.contains_key(&(site_id, id)) {
-                new_file_list.insert((site_id, id), file);
-            } else {
-                let filename = file.get_local_filename();
-                self.id_lookup.remove_file(filename.iter());
-                self.updater.remove_file(filename).unwrap();
+        if base_path.join(&old_keep).exists() {
+            if base_path.join(&old_discard).exists() {
+                try!(self.updater.remove_file(&old_discard).map_err(|e| FileSetError::IOError(e)));
+            }
+            if new_keep != old_keep {
+                try!(self.updater.move_file(&old_keep, &new_keep).map_err(|e| FileSetError::IOError(e)));
             }
         }
-        self.files = new_file_list;
+        try!(self.save().map_err(|e| FileSetError::IOError(e)));
+        Ok(vec![self.logged(FileSetOperation::Remove(RemoveOperation {
+            state: remove_state,
+            producer: self.producer(),
+            id: discard,
+            last_update: last_update,
+            seen: seen
+        })), self.logged(FileSetOperation::UpdateMetadata(UpdateMetadata {
+            state: rename_state,
+            producer: self.producer(),
+            hybrid_time: hybrid_time,
+            id: keep,
+            data: MetadataTransaction::Filename(filename)
+        }))])
+    }
 
-        // For each file in the remote list, if it is not in the local list, then create it in the local list and on the file system
-        for  ((site_id, id), mut file_history) in file_list.into_iter() {
-            if !self.files.contains_key(&(site_id, id)) {
-                let printed = self.id_lookup.add_file(file_history.filename.1.iter().map(OsStr::new), (site_id, id), site_id);
-                let file = FileMetadata {
-                    filename: file_history.filename,
-                    printed_filename: printed,
-                    attributes: file_history.attributes.clone() // TODO consider retrieving these separately when they are needed
-                };
-                let actual_filename = file.get_local_filename();
-                self.files.insert((site_id, id), file);
-                self.updater.create_file(&actual_filename).unwrap();
-                self.updater.update_file(&actual_filename, &timestamp_lookup, &mut file_history.operation_history).unwrap();
-            }
+    pub fn process_folder_move(&mut self, old_path: &Path, new_path: &Path) -> Result<FileSetOperation<FU>, FileSetError> {
+        trace!("Processing folder_move on {:?}", old_path);
+        let old_folder:Vec<_> = old_path.iter().map(|c| c.to_str().unwrap().to_string()).collect();
+        let new_folder:Vec<_> = new_path.iter().map(|c| c.to_str().unwrap().to_string()).collect();
+        let mut ids = self.id_lookup.get_folder_ids(old_path);
+        // Files excluded here aren't in the lookup, but still move along with the folder
+        ids.extend(self.excluded.iter().filter(|id| self.files[id].filename.1.starts_with(&old_folder)).cloned());
+        for id in ids.iter() {
+            let filename:PathBuf = new_folder.iter().chain(self.files[id].filename.1.iter().skip(old_folder.len())).collect();
+            try!(self.check_path_limits(&filename));
+        }
+        let seen = self.get_version_vector();
+        let state = self.create_state();
+        let hybrid_time = self.clock.get_last();
+        let mut files = Vec::with_capacity(ids.len());
+        let mut changed = Vec::with_capacity(ids.len());
+        for id in ids {
+            let filename:Vec<_> = new_folder.iter().chain(self.files[&id].filename.1.iter().skip(old_folder.len())).cloned().collect();
+            changed.push((id, self.files[&id].entry_name(), EntryName {
+                filename: (state.time_stamp, filename.clone()),
+                site_id: Some(state.site_id),
+                hybrid_time: hybrid_time
+            }));
+            self.metadata_history.record(id, state, MetadataValue::Filename(filename.clone()));
+            self.record_change(id, false);
+            files.push((id, filename));
         }
+        self.rename_entries(changed.iter().map(|&(id, _, ref name)| (id, name.clone())).collect(), &mut HashMap::new());
+        let operation = FolderMove {
+            state: state,
+            producer: self.producer(),
+            clock: self.folder_moves.next_clock(),
+            hybrid_time: hybrid_time,
+            seen: seen,
+            old_path: old_folder.clone(),
+            new_path: new_folder.clone(),
+            files: files
+        };
+        self.folder_moves.push(MoveRecord {
+            operation: operation.clone(),
+            resolved: Some((old_folder, new_folder)),
+            changed: changed
+        });
         self.save().unwrap();
-        operations
+        trace!("Generated folder move {}", state);
+        Ok(self.logged(FileSetOperation::MoveFolder(operation)))
     }
 
+    pub fn check_path_limits(&self, path: &Path) -> Result<(), FileSetError> {
+        let filename:Vec<_> = path.iter().map(|c| c.to_string_lossy().into_owned()).collect();
+        if self.path_limits.allows(&filename) {
+            Ok(())
+        } else {
+            Err(FileSetError::PathLimitExceeded(path.to_path_buf()))
+        }
+    }
 
-
-}
-
-impl<FU: FileUpdater> FileSet<FU>  {
-
-    fn create_state(&mut self) -> State {
-        let timestamp = self.last_timestamp;
-        self.last_timestamp += 1;
-        State {
-            site_id: self.site_id,
-            time_stamp: timestamp
+    pub fn process_set_attribute(&mut self, path: &Path, key: &str, value: &str) -> Result<FileSetOperation<FU>, FileSetError> {
+        if attributes::is_system_attribute(key) {
+            return Err(FileSetError::ReservedAttribute(key.to_string()))
         }
+        let id = match self.id_lookup.get_id_for(path) {
+            Some(id) => id,
+            None => {return Err(FileSetError::PathNotFound(path.to_path_buf()))}
+        };
+        self.set_attribute(id, key, value)
     }
-    fn get_next_id(&mut self) -> u32 {
-        let id = self.last_id;
-        self.last_id += 1;
-        id
+
+    // Tells the other sites that the holder is editing the file, until the lease
+    // runs out or the lock is released
+    pub fn process_lock_for_editing(&mut self, path: &Path, holder: &str, lease: Duration) -> Result<FileSetOperation<FU>, FileSetError> {
+        let id = match self.id_lookup.get_id_for(path) {
+            Some(id) => id,
+            None => {return Err(FileSetError::PathNotFound(path.to_path_buf()))}
+        };
+        let lock = EditLock {
+            site_id: self.site_id,
+            expires: clock::wall_time() + clock::duration_millis(lease),
+            holder: holder.to_string()
+        };
+        self.set_attribute(id, attributes::EDIT_LOCK, &lock.to_value())
     }
 
-    fn integrate_create(&mut self, o: CreateOperation) -> Result<(), FileSetError> {
-        let actual_filename = self.id_lookup.add_file(o.filename.iter().map(OsStr::new), o.id, o.id.0);
-        let metadata = FileMetadata{
-            filename: (o.state.time_stamp, o.filename),
-            printed_filename: actual_filename,
-            attributes: HashMap::new()
+    pub fn process_release_lock(&mut self, path: &Path) -> Result<FileSetOperation<FU>, FileSetError> {
+        let id = match self.id_lookup.get_id_for(path) {
+            Some(id) => id,
+            None => {return Err(FileSetError::PathNotFound(path.to_path_buf()))}
         };
-        let path = metadata.get_local_filename();
-        self.files.insert(o.id, metadata);
-        self.updater.create_file(&path).map_err(|e| {FileSetError::IOError(e)})
+        self.set_attribute(id, attributes::EDIT_LOCK, "")
     }
 
+    pub fn get_edit_lock(&self, id: FileID) -> Option<EditLock> {
+        self.files.get(&id).and_then(|file_metadata| file_metadata.edit_lock(clock::wall_time()))
+    }
 
-    fn integrate_remove(&mut self, o: RemoveOperation) -> Result<(), FileSetError> {
-        let metadata = match self.files.remove(&o.id) {
-            Some(md) => md,
-            None => {return Err(FileSetError::IDNotFound(o.id.0, o.id.1))}
+    pub fn process_set_attributes(&mut self, path: &Path, values: HashMap<String, String>) -> Result<FileSetOperation<FU>, FileSetError> {
+        if let Some(key) = values.keys().find(|key| attributes::is_system_attribute(key)) {
+            return Err(FileSetError::ReservedAttribute(key.clone()))
+        }
+        let id = match self.id_lookup.get_id_for(path) {
+            Some(id) => id,
+            None => {return Err(FileSetError::PathNotFound(path.to_path_buf()))}
         };
-        let filename = metadata.get_local_filename();
-        self.id_lookup.remove_file(&filename);
-        self.updater.remove_file(filename).map_err(|e| {FileSetError::IOError(e)})
+        trace!("Setting {} attributes on {:?}", values.len(), id);
+        // Sorted, so the batch is logged and sent the same way every time
+        let mut values: Vec<_> = values.into_iter().collect();
+        values.sort();
+        let state = self.create_state();
+        let hybrid_time = self.clock.get_last();
+        {
+            let metadata = self.files.get_mut(&id).unwrap();
+            for &(ref key, ref value) in values.iter() {
+                metadata.set_attribute(key.clone(), value.clone(), &state, hybrid_time);
+                self.metadata_history.record(id, state, MetadataValue::Attribute(key.clone(), value.clone()));
+            }
+        }
+        self.record_change(id, false);
+        self.save().unwrap();
+        trace!("Generated attribute update {}", state);
+        Ok(self.logged(FileSetOperation::UpdateMetadata(UpdateMetadata {
+            state: state,
+            producer: self.producer(),
+            hybrid_time: hybrid_time,
+            id: id,
+            data: MetadataTransaction::CustomBatch(values)
+        })))
     }
 
-    fn integrate_update(&mut self, o: &mut UpdateOperation<FU>, timestamp_lookup: &BTreeMap<u32, (u32, u32)>) -> Result<(), FileSetError> {
-        let metadata = match self.files.get(&o.id) {
-            Some(md) => md,
-            None => {return Err(FileSetError::IDNotFound(o.id.0, o.id.1))}
-        };
-        self.updater.update_file(&metadata.get_local_filename(), timestamp_lookup, &mut o.data).map_err(|e| {FileSetError::IOError(e)})
+    pub fn process_materialization_failure(&mut self, file: (SiteId, u64), reason: &str) -> Result<FileSetOperation<FU>, FileSetError> {
+        // The file stays in the set for everyone else, but this site stops trying to hold a copy
+        warn!("Unable to materialize {:?}: {}", file, reason);
+        if !self.excluded.contains(&file) {
+            let filename = try!(get_target(&mut self.files, file)).get_local_filename();
+            self.id_lookup.remove_file(filename.iter());
+            self.excluded.insert(file);
+        }
+        let key = format!("{}{}", attributes::UNMATERIALIZED_PREFIX, self.site_id);
+        self.set_attribute(file, &key, reason)
     }
 
-    fn integrate_update_metadata(&mut self, o: UpdateMetadata) -> Result<(), FileSetError> {
-        {
+    pub fn process_announce_site(&mut self, site_id: SiteId, info: SiteInfo) -> FileSetOperation<FU> {
+        trace!("Processing announcement of site {}", site_id);
+        let state = self.create_state();
+        self.roster.integrate(state, site_id, info.clone(), self.path_limits);
+        self.save().unwrap();
+        trace!("Generated announcement {}", state);
+        self.logged(FileSetOperation::AnnounceSite(SiteAnnouncement {
+            state: state,
+            producer: self.producer(),
+            site_id: site_id,
+            info: info,
+            path_limits: self.path_limits
+        }))
+    }
 
-            match o.data{
-                MetadataTransaction::Filename(filename) => {
-                    let (old_filename, new_filename) = {
-                        let metadata = match self.files.get_mut(&o.id) {
-                            Some(md) => md,
-                            None => {return Err(FileSetError::IDNotFound(o.id.0, o.id.1))}
-                        };
-                        if metadata.filename.0 > o.state.time_stamp || metadata.filename.0 == o.state.time_stamp && self.site_id > o.state.site_id {
-                            return Ok(())
-                        }
-                        let old_filename = metadata.get_local_filename();
-                        self.id_lookup.remove_file(old_filename.iter());
-                        let actual_filename = self.id_lookup.add_file(filename.iter().map(OsStr::new), o.id, o.state.site_id);
-                        metadata.filename = (o.state.time_stamp, filename);
-                        metadata.printed_filename = actual_filename;
-                        (old_filename, metadata.get_local_filename())
-                    };
-                    self.updater.move_file(&old_filename, &new_filename).map_err(|e| {FileSetError::IOError(e)})
-                },
-                MetadataTransaction::Custom(key, value) => {
-                    let metadata = match self.files.get_mut(&o.id) {
-                        Some(md) => md,
-                        None => {return Err(FileSetError::IDNotFound(o.id.0, o.id.1))}
-                    };
-                    match metadata.attributes.entry(key) {
-                        Entry::Occupied(ref mut entry) => {
-                            {
-                                let val = entry.get();
-                                if val.0 > o.state.time_stamp || val.0 == o.state.time_stamp && self.site_id > o.state.site_id {
-                                    return Ok(())
-                                }
-                            }
-                            entry.insert((o.state.time_stamp, value));
-                            Ok(())
-                        },
-                        Entry::Vacant(entry) => {
-                            entry.insert((o.state.time_stamp, value));
-                            Ok(())
-                        }
-                    }
+    // Hands out a permanent id for a replica joining with a provisional one, and
+    // announces it, so the new site doesn't need its id arranged out of band
+    pub fn allocate_site_id(&mut self, info: SiteInfo) -> (SiteId, FileSetOperation<FU>) {
+        let site_id = self.unused_site_id();
+        (site_id, self.process_announce_site(site_id, info))
+    }
 
-                }
+    fn unused_site_id(&self) -> SiteId {
+        let known = self.get_version_vector();
+        loop {
+            let site_id = random_site_id();
+            if !self.roster.contains(site_id) && known.get(site_id) == 0 {
+                return site_id
             }
         }
     }
 
-    fn scan_dir(&mut self, base_path: &Path, actual_path: &Path, remote_files: &mut HashMap<(u32, u32), FileHistory<FU>>, timestamp_lookup: &BTreeMap<u32, (u32, u32)>, operations: &mut Vec<FileSetOperation<FU>>) -> io::Result<()> {
-        trace!("Scanning directory {:?}", actual_path);
-        if actual_path.starts_with(&self.storage_path) {
+    // A standby integrates everything sent to it but doesn't scan the disk, so it
+    // can take over from a hub that goes down
+    pub fn set_standby(&mut self, standby: bool) {
+        self.standby = standby;
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby
+    }
+
+    // Turns a standby into an active site. If another site has been announced
+    // under its id, it takes a fresh one, which is safe since a standby hasn't
+    // made any changes of its own. Whatever happened on disk while it was standing
+    // by is picked up and returned after the announcement.
+    pub fn promote(&mut self, info: SiteInfo) -> (SiteId, Vec<FileSetOperation<FU>>) {
+        if !self.standby {
+            return (self.site_id, Vec::new())
+        }
+        if self.roster.get(self.site_id).map_or(false, |current| *current != info) {
+            let site_id = self.unused_site_id();
+            warn!("Site id {} belongs to another site, so promoting as {}", self.site_id, site_id);
+            self.site_id = site_id;
+        }
+        self.standby = false;
+        let site_id = self.site_id;
+        let mut operations = vec![self.process_announce_site(site_id, info)];
+        operations.extend(self.reconcile_local());
+        (site_id, operations)
+    }
+
+    pub fn get_roster(&self) -> &SiteRoster {
+        &self.roster
+    }
+
+    pub fn site_info(&self, site_id: SiteId) -> Option<&SiteInfo> {
+        self.roster.get(site_id)
+    }
+
+    pub fn get_changes_since(&self, timestamp: Option<(SiteId, u64)>) -> HashMap<(SiteId, u64), FileHistory<FU>> {
+        self.changes_since(None, timestamp)
+    }
+
+    pub fn get_changes_since_vector(&self, seen: &VersionVector) -> HashMap<(SiteId, u64), FileHistory<FU>> {
+        self.changes_since_vector(None, seen)
+    }
+
+    // Only the files changed by an operation the vector doesn't include, so a peer
+    // that is nearly up to date doesn't have every file's history gathered for it.
+    // Since files that haven't changed are left out, a missing file doesn't mean
+    // it was removed, so this can't be used to plan a reconciliation.
+    pub fn get_delta_since_vector(&self, seen: &VersionVector) -> HashMap<FileID, FileHistory<FU>> {
+        self.delta_since_vector(None, seen)
+    }
+
+    pub fn get_version_vector(&self) -> VersionVector {
+        // Remote operations held back or applied out of order leave gaps, so only
+        // count up to the first one missing from each site
+        let mut version_vector = self.applied.high_water_marks();
+        version_vector.observe(self.site_id, self.last_timestamp);
+        version_vector
+    }
+
+    pub fn get_changes_for_peer(&self, peer: SiteId, timestamp: Option<(SiteId, u64)>) -> HashMap<(SiteId, u64), FileHistory<FU>> {
+        self.changes_since(Some(peer), timestamp)
+    }
+
+    pub fn get_changes_since_vector_for_peer(&self, peer: SiteId, seen: &VersionVector) -> HashMap<(SiteId, u64), FileHistory<FU>> {
+        self.changes_since_vector(Some(peer), seen)
+    }
+
+    pub fn get_delta_since_vector_for_peer(&self, peer: SiteId, seen: &VersionVector) -> HashMap<FileID, FileHistory<FU>> {
+        self.delta_since_vector(Some(peer), seen)
+    }
+
+    pub fn set_outbound_filter<P: AsRef<Path>>(&mut self, peer: SiteId, shared_prefixes: Vec<P>) {
+        let shared_prefixes = shared_prefixes.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        self.outbound_filters.insert(peer, shared_prefixes);
+    }
+
+    pub fn clear_outbound_filter(&mut self, peer: SiteId) {
+        self.outbound_filters.remove(&peer);
+    }
+
+    pub fn is_shared_with(&self, peer: SiteId, file: (SiteId, u64)) -> bool {
+        match self.files.get(&file) {
+            Some(file_metadata) => self.is_metadata_shared_with(Some(peer), file_metadata),
+            None => false
+        }
+    }
+
+    pub fn get_all_files(&self) -> &HashMap<(SiteId, u64), FileMetadata> {
+        &self.files
+    }
+
+    // The latest changes to the file's name and attributes, oldest first
+    pub fn get_metadata_history(&self, id: FileID) -> Vec<MetadataChange> {
+        self.metadata_history.get(id).map_or(Vec::new(), |changes| changes.iter().cloned().collect())
+    }
+
+    // The operations applied here from `range.start` up to `range.end`, numbered in
+    // the order they were applied
+    pub fn get_logged_operations(&self, range: Range<u64>) -> Result<Vec<OperationRecord>, FileSetError> {
+        self.operation_log.read_range(range).map_err(|e| FileSetError::IOError(e))
+    }
+
+    #[inline]
+    pub fn get_logged_operation_count(&self) -> u64 {
+        self.operation_log.count()
+    }
+
+    // Files in byte-wise order of their path components, which doesn't depend on
+    // the platform or locale, with the file ID settling ties. A page is the files
+    // from `start` on, so the same fileset pages the same way everywhere.
+    pub fn list_files(&self, start: usize, limit: usize) -> Vec<(FileID, PathBuf)> {
+        let mut files: Vec<_> = self.files.iter().map(|(&id, file)| (id, file.get_local_filename())).collect();
+        files.sort_by(|&(id1, ref path1), &(id2, ref path2)| compare_paths(path1, path2).then(id1.cmp(&id2)));
+        files.into_iter().skip(start).take(limit).collect()
+    }
+
+    pub fn verify_index(&self) -> Vec<(SiteId, u64)> {
+        // Every materialized file must be found at its local path, and the index
+        // must not lead anywhere else
+        let mut damaged: Vec<FileID> = self.files.iter().filter(|&(id, file_metadata)| {
+            !self.excluded.contains(id) && self.id_lookup.get_id_for(file_metadata.get_local_filename().iter()) != Some(*id)
+        }).map(|(&id, _)| id).collect();
+        for id in self.id_lookup.get_all_ids() {
+            if !self.files.contains_key(&id) || self.excluded.contains(&id) {
+                damaged.push(id);
+            }
+        }
+        damaged
+    }
+
+    pub fn rebuild_index(&mut self) {
+        trace!("Rebuilding path index from {} files", self.files.len());
+        self.id_lookup = build_id_lookup(&self.files, &self.excluded);
+        self.id_lookup.set_max_name_length(self.path_limits.max_name_length);
+    }
+
+    pub fn get_generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn get_content_generation(&self, file: (SiteId, u64)) -> Option<u64> {
+        if self.files.contains_key(&file) {
+            self.content_generations.get(&file).cloned()
+        } else {
+            None
+        }
+    }
+
+    // Data derived from a file's contents (previews, thumbnails) is stale if the
+    // contents have changed since the generation it was derived at
+    pub fn is_derived_stale(&self, file: (SiteId, u64), derived_generation: u64) -> bool {
+        self.get_content_generation(file).map_or(true, |generation| generation > derived_generation)
+    }
+
+    pub fn get_stale_derived(&self, derived_generations: &HashMap<(SiteId, u64), u64>) -> Vec<(SiteId, u64)> {
+        self.files.keys().filter(|id| !self.excluded.contains(*id)).filter(|&id| {
+            match derived_generations.get(id) {
+                Some(&derived_generation) => self.is_derived_stale(*id, derived_generation),
+                None => true
+            }
+        }).cloned().collect()
+    }
+
+    pub fn exclude_locally(&mut self, file: (SiteId, u64)) -> Result<(), FileSetError> {
+        trace!("Excluding {:?} locally", file);
+        if self.excluded.contains(&file) {
             return Ok(())
         }
-        for entry in try!(fs::read_dir(actual_path)) {
-            let entry = try!(entry);
-            let path = entry.path();
-            if path.is_dir() {
-                try!(self.scan_dir(base_path, path.as_path(), remote_files, timestamp_lookup, operations));
-            } else {
-                try!(self.check_for_file(base_path, path.as_path(), remote_files, timestamp_lookup, operations));
+        let intent = {
+            let metadata = try!(get_target(&mut self.files, file));
+            self.id_lookup.remove_file(metadata.get_local_filename().iter());
+            metadata.remove_intent()
+        };
+        self.excluded.insert(file);
+        self.generation += 1;
+        self.record_change(file, false);
+        self.apply_intent(intent).map_err(|e| {FileSetError::IOError(e)})
+    }
+
+    pub fn include_locally(&mut self, file: (SiteId, u64)) -> Result<(), FileSetError> {
+        // The file comes back empty, and gets its contents with the next file list sync
+        trace!("Including {:?} locally", file);
+        if !self.excluded.contains(&file) {
+            return Ok(())
+        }
+        let intent = {
+            let metadata = try!(get_target(&mut self.files, file));
+            metadata.printed_filename = self.id_lookup.add_file(metadata.filename.1.iter().map(OsStr::new), file, file.0);
+            metadata.create_intent()
+        };
+        self.excluded.remove(&file);
+        self.generation += 1;
+        self.record_change(file, true);
+        self.apply_intent(intent).map_err(|e| {FileSetError::IOError(e)})
+    }
+
+    pub fn is_excluded_locally(&self, file: (SiteId, u64)) -> bool {
+        self.excluded.contains(&file)
+    }
+
+    // Writes the plain tree, without the store, into a folder outside the fileset,
+    // as it is now or as of the vector. Names as of the vector come from
+    // materialize_at, and contents from the updater. Files removed here since then
+    // have no contents left to give, and are skipped. Returns how many files were written.
+    pub fn export_materialized<P: AsRef<Path>>(&self, dest_dir: P, at: Option<VersionVector>) -> Result<usize, FileSetError> {
+        let dest_dir = try!(absolute_path(dest_dir.as_ref()).map_err(|e| FileSetError::IOError(e)));
+        let base_path = try!(absolute_path(self.updater.get_base_path()).map_err(|e| FileSetError::IOError(e)));
+        // An export the next scan can see would be picked up as new files
+        if dest_dir.starts_with(&base_path) || self.is_ignored(&dest_dir) {
+            return Err(FileSetError::InsideFileSet(dest_dir))
+        }
+        trace!("Exporting fileset to {:?}", dest_dir);
+        let mut files: Vec<(FileID, FileMetadata)> = match at {
+            Some(ref seen) => try!(self.materialize_at(seen)).into_iter().collect(),
+            None => self.files.iter().map(|(&id, file_metadata)| (id, file_metadata.clone())).collect()
+        };
+        if at.is_some() {
+            // Conflicts are printed the same way every time for the same files
+            files.sort_by(|&(id1, ref file1), &(id2, ref file2)| (file1.filename.1.len(), id1).cmp(&(file2.filename.1.len(), id2)));
+            let mut id_lookup = IDLookup::new();
+            for &mut (id, ref mut file_metadata) in files.iter_mut() {
+                file_metadata.printed_filename = id_lookup.add_file(file_metadata.filename.1.iter().map(OsStr::new), id, id.0);
             }
         }
-        trace!("Directory {:?} complete", actual_path);
-        Ok(())
+        let mut exported = 0;
+        for (id, file_metadata) in files {
+            let source = match self.files.get(&id) {
+                Some(current) if !self.excluded.contains(&id) => current.get_local_filename(),
+                _ => {
+                    trace!("Not exporting {:?}, which has no copy here", id);
+                    continue
+                }
+            };
+            let dest_path = dest_dir.join(file_metadata.get_local_filename());
+            if file_metadata.is_directory() {
+                try!(fs::create_dir_all(dest_path).map_err(|e| FileSetError::IOError(e)));
+                continue
+            }
+            if let Some(parent) = dest_path.parent() {
+                try!(fs::create_dir_all(parent).map_err(|e| FileSetError::IOError(e)));
+            }
+            try!(self.updater.export_file(&source, at.as_ref(), &dest_path).map_err(|e| FileSetError::IOError(e)));
+            exported += 1;
+        }
+        Ok(exported)
+    }
+
+    pub fn get_file_history_for(&self, file: (SiteId, u64)) -> Option<FU::FileTransaction> {
+        if let Some(file_metadata) = self.files.get(&file) {
+            Some(self.updater.get_changes_since(file_metadata.get_local_filename().as_path(), None))
+        } else {
+            None
+        }
+    }
+
+    pub fn integrate_remote_file_list(&mut self, file_list: HashMap<(SiteId, u64), FileHistory<FU>>, timestamp_lookup: TimestampMap) -> Vec<FileSetOperation<FU>> {
+        self.integrate_remote_file_list_with_report(file_list, timestamp_lookup).0
+    }
+
+    pub fn integrate_remote_file_list_with_report(&mut self, file_list: HashMap<(SiteId, u64), FileHistory<FU>>, timestamp_lookup: TimestampMap) -> (Vec<FileSetOperation<FU>>, ReconciliationReport) {
+        let mut plan = self.plan_reconciliation(file_list, timestamp_lookup);
+        self.execute_reconciliation(&mut plan, |_, _| true).unwrap()
+    }
+
+    // For the first site, setting up a fileset over a folder that already has files
+    // in it. Every folder and file not yet in the fileset is added at one state and
+    // saved once, without any operations. Other sites start by merging the returned
+    // state, which counts that state as applied, and contents follow as updates.
+    pub fn adopt_existing_tree(&mut self) -> Result<SerializedFileSet, FileSetError> {
+        let base_path = self.updater.get_base_path().to_path_buf();
+        let mut found_folders = Vec::new();
+        try!(self.scan_folders(base_path.as_path(), base_path.as_path(), &mut found_folders).map_err(|e| FileSetError::IOError(e)));
+        let mut found_files = Vec::new();
+        try!(self.scan_dir(base_path.as_path(), base_path.as_path(), &mut |_, relative_path| {
+            found_files.push(relative_path);
+            Ok(())
+        }));
+        let state = self.create_state();
+        let hybrid_time = self.clock.get_last();
+        // Folders go first, outermost first, so they are there before what's in them
+        found_folders.sort_by_key(|relative_path| relative_path.iter().count());
+        let found = found_folders.into_iter().map(|relative_path| (relative_path, true)).chain(found_files.into_iter().map(|relative_path| (relative_path, false)));
+        for (relative_path, directory) in found {
+            if self.id_lookup.get_id_for(relative_path.iter()).is_some() {
+                continue
+            }
+            if let Err(e) = self.check_path_limits(&relative_path) {
+                warn!("Not adopting {:?}: {}", relative_path, e);
+                continue
+            }
+            let id = (self.site_id, try!(self.get_next_id()));
+            let printed = self.id_lookup.add_file(relative_path.iter(), id, self.site_id);
+            let filename: Vec<_> = relative_path.iter().map(|c| c.to_str().unwrap().to_string()).collect();
+            self.files.insert(id, FileMetadata::new_entry(filename, printed, directory, &state, hybrid_time));
+            self.record_change_at(id, !directory, &state);
+        }
+        self.adopted.push(state);
+        try!(self.save().map_err(|e| FileSetError::IOError(e)));
+        trace!("Adopted the existing tree at {}", state);
+        Ok(self.get_serialized_state())
+    }
+
+    pub fn reconcile_local(&mut self) -> Vec<FileSetOperation<FU>> {
+        let mut operations = Vec::new();
+        self.reconcile_local_with(|operation| operations.push(operation));
+        operations
     }
 
-    fn check_for_file(&mut self, base_path: &Path, actual_path: &Path, remote_files: &mut HashMap<(u32, u32), FileHistory<FU>>, timestamp_lookup: &BTreeMap<u32, (u32, u32)>, operations: &mut Vec<FileSetOperation<FU>>) -> io::Result<()> {
-        trace!("Checking file {:?}", actual_path);
-        let relative_path = actual_path.strip_prefix(base_path).unwrap();
-        match self.id_lookup.get_id_for(relative_path) {
-            Some((site_id, id)) => {
-                if let Some(remote_file) = remote_files.get_mut(&(site_id, id)) {
-                    trace!("Getting local changes");
-                    let (local_changes, local_timestamps) = try!(self.updater.get_local_changes(relative_path));
-                    operations.push(FileSetOperation::Update(UpdateOperation {
-                        id: (site_id, id),
-                        data: local_changes
-                    }, local_timestamps));
-                    trace!("Updating the file with remote operations");
-                    try!(self.updater.update_file(&relative_path, timestamp_lookup, &mut remote_file.operation_history))
+    // Hands each operation over as soon as it is made, starting with the folders
+    // where things last changed, so a single change in a large tree doesn't wait
+    // for the rest of the tree to be scanned
+    pub fn reconcile_local_with<F: FnMut(FileSetOperation<FU>)>(&mut self, mut emit: F) {
+        // Recursively go through every file in the directory
+        // If the file is in the local list, then process local changes
+        // Otherwise, create the file in the list, and process the local changes
+        // Any file left in the local list that wasn't found has been removed
+        if self.standby {
+            trace!("Not reconciling a standby");
+            return
+        }
+        let base_path = self.updater.get_base_path().to_path_buf();
+        // Scanning only finds files, so directories are checked for directly
+        let mut vanished: HashSet<FileID> = self.files.iter().filter(|&(id, file_metadata)| {
+            !self.excluded.contains(id) && !(file_metadata.is_directory() && base_path.join(file_metadata.get_local_filename()).is_dir())
+        }).map(|(&id, _)| id).collect();
+        self.scan_dir(base_path.as_path(), base_path.as_path(), &mut |fileset, relative_path| {
+            trace!("Reconciling file {:?}", relative_path);
+            match fileset.id_lookup.get_id_for(relative_path.iter()) {
+                Some(id) => match fileset.local_update_operation(relative_path.as_path(), id) {
+                    Ok(operation) => {
+                        vanished.remove(&id);
+                        emit(operation);
+                    },
+                    // Gone since it was found, so it is removed below
+                    Err(e) => warn!("Not updating {:?}: {}", relative_path, e)
+                },
+                None => {
+                    let mut operations = Vec::new();
+                    if let Err(e) = fileset.local_create_operations(base_path.as_path(), relative_path.as_path(), &mut operations) {
+                        warn!("Not adding {:?}: {}", relative_path, e);
+                    }
+                    for operation in operations {
+                        emit(operation);
+                    }
                 }
-            }, None => {
-                operations.push(self.process_create(relative_path));
-                if fs::metadata(actual_path).unwrap().len() > 0 {
-                    let mut id = (0, 0);
-                    if let Some(&FileSetOperation::Create(ref co)) = operations.get(operations.len() - 1)
-                    {
-                        id = co.id
+            }
+            Ok(())
+        }).unwrap();
+        for id in vanished {
+            let filename = self.files[&id].get_local_filename();
+            trace!("File {:?} vanished from disk", filename);
+            self.id_lookup.remove_file(filename.iter());
+            let seen = self.get_version_vector();
+            let state = self.create_state();
+            let last_update = self.bury(id, state, seen.clone());
+            self.record_change(id, false);
+            trace!("Generated remove {}", state);
+            let operation = self.logged(FileSetOperation::Remove(RemoveOperation {
+                state: state,
+                producer: self.producer(),
+                id: id,
+                last_update: last_update,
+                seen: seen
+            }));
+            emit(operation);
+        }
+        self.save().unwrap();
+        self.flush_due_index_changes();
+    }
+
+    // Finds untracked files on disk with a conflict suffix anywhere in their path, and
+    // adopts or moves them according to the policy. Returns the operations for any
+    // files that were adopted.
+    pub fn collect_orphaned_conflicts(&mut self, policy: &OrphanPolicy) -> Result<Vec<FileSetOperation<FU>>, FileSetError> {
+        let base_path = self.updater.get_base_path().to_path_buf();
+        if let OrphanPolicy::MoveInto(ref folder) = *policy {
+            // Otherwise the next scan would find what was moved there all over again
+            if base_path.join(folder).starts_with(&base_path) {
+                self.add_ignored_path(base_path.join(folder));
+            }
+        }
+        let mut operations = Vec::new();
+        try!(self.scan_dir(base_path.as_path(), base_path.as_path(), &mut |fileset, relative_path| {
+            if fileset.id_lookup.get_id_for(relative_path.iter()).is_some() || !relative_path.iter().any(|component| is_conflict_name(&component.to_string_lossy())) {
+                return Ok(())
+            }
+            trace!("Found orphaned conflict file {:?}", relative_path);
+            match *policy {
+                OrphanPolicy::Adopt => try!(fileset.local_create_operations(base_path.as_path(), relative_path.as_path(), &mut operations)),
+                OrphanPolicy::MoveInto(ref folder) => {
+                    let destination = base_path.join(folder).join(&relative_path);
+                    if let Some(parent) = destination.parent() {
+                        try!(fs::create_dir_all(parent).map_err(|e| FileSetError::IOError(e)));
                     }
-                    let (local_changes, local_lookup) = try!(self.updater.get_local_changes(relative_path));
-                    operations.push(FileSetOperation::Update(UpdateOperation {
-                        id: id,
-                        data: local_changes
-                    }, local_lookup));
+                    try!(fs::rename(base_path.join(&relative_path), destination).map_err(|e| FileSetError::IOError(e)));
+                }
+            }
+            Ok(())
+        }));
+        Ok(operations)
+    }
+
+}
+
+impl<FU: FileUpdater> FileSet<FU>  {
+
+    fn producer(&self) -> Producer {
+        Producer {
+            crate_version: CRATE_VERSION.to_string(),
+            application_version: self.roster.get(self.site_id).map_or(String::new(), |info| info.application_version.clone())
+        }
+    }
+
+    fn create_state(&mut self) -> State {
+        // Every local change is stamped with a new state, so this is where local changes are counted
+        // At a billion changes a second this would take centuries, but reusing a
+        // state would silently corrupt every site, so make sure it never happens
+        let timestamp = self.last_timestamp;
+        self.last_timestamp = timestamp.checked_add(1).expect("timestamps exhausted");
+        self.generation += 1;
+        self.clock.tick();
+        State {
+            site_id: self.site_id,
+            time_stamp: timestamp
+        }
+    }
+    fn set_attribute(&mut self, id: FileID, key: &str, value: &str) -> Result<FileSetOperation<FU>, FileSetError> {
+        trace!("Setting attribute {} on {:?}", key, id);
+        try!(get_target(&mut self.files, id));
+        let state = self.create_state();
+        let hybrid_time = self.clock.get_last();
+        self.files.get_mut(&id).unwrap().set_attribute(key.to_string(), value.to_string(), &state, hybrid_time);
+        self.metadata_history.record(id, state, MetadataValue::Attribute(key.to_string(), value.to_string()));
+        self.record_change(id, false);
+        self.save().unwrap();
+        trace!("Generated attribute update {}", state);
+        Ok(self.logged(FileSetOperation::UpdateMetadata(UpdateMetadata {
+            state: state,
+            producer: self.producer(),
+            hybrid_time: hybrid_time,
+            id: id,
+            data: MetadataTransaction::Custom(key.to_string(), value.to_string())
+        })))
+    }
+
+    fn get_file_history(&self, file_metadata: &FileMetadata, timestamp: Option<(SiteId, u64)>) -> FileHistory<FU> {
+        FileHistory {
+            filename: file_metadata.filename.clone(),
+            attributes: file_metadata.attributes.clone(),
+            operation_history: self.updater.get_changes_since(file_metadata.get_local_filename().as_path(), timestamp)
+        }
+    }
+
+    fn changes_since(&self, peer: Option<SiteId>, timestamp: Option<(SiteId, u64)>) -> HashMap<(SiteId, u64), FileHistory<FU>> {
+        self.files.iter().filter(|&(_, file_metadata)| {
+            self.is_metadata_shared_with(peer, file_metadata)
+        }).map(|(&key, file_metadata)| {
+            (key, self.get_file_history(file_metadata, timestamp))
+        }).collect()
+    }
+
+    fn changes_since_vector(&self, peer: Option<SiteId>, seen: &VersionVector) -> HashMap<(SiteId, u64), FileHistory<FU>> {
+        self.files.iter().filter(|&(_, file_metadata)| {
+            self.is_metadata_shared_with(peer, file_metadata)
+        }).map(|(&key, file_metadata)| {
+            (key, self.get_file_history_since_vector(file_metadata, seen))
+        }).collect()
+    }
+
+    fn delta_since_vector(&self, peer: Option<SiteId>, seen: &VersionVector) -> HashMap<FileID, FileHistory<FU>> {
+        self.files.iter().filter(|&(id, file_metadata)| {
+            self.is_metadata_shared_with(peer, file_metadata) &&
+                self.modified_at.get(id).map_or(true, |modified| modified.iter().any(|(&site_id, &next_time_stamp)| seen.get(site_id) < next_time_stamp))
+        }).map(|(&key, file_metadata)| {
+            (key, self.get_file_history_since_vector(file_metadata, seen))
+        }).collect()
+    }
+
+    fn get_file_history_since_vector(&self, file_metadata: &FileMetadata, seen: &VersionVector) -> FileHistory<FU> {
+        FileHistory {
+            filename: file_metadata.filename.clone(),
+            attributes: file_metadata.attributes.clone(),
+            operation_history: self.updater.get_changes_since_vector(file_metadata.get_local_filename().as_path(), seen)
+        }
+    }
+
+    // With no peer to filter for, everything is shared
+    fn is_metadata_shared_with(&self, peer: Option<SiteId>, file_metadata: &FileMetadata) -> bool {
+        match peer.and_then(|peer| self.outbound_filters.get(&peer)) {
+            Some(shared_prefixes) => {
+                let path: PathBuf = file_metadata.filename.1.iter().collect();
+                shared_prefixes.iter().any(|prefix| path.starts_with(prefix))
+            },
+            None => true
+        }
+    }
+
+    fn apply_intent(&mut self, intent: Intent) -> io::Result<()> {
+        // The metadata must already describe the change before the change is made,
+        // so that recovery knows the change is wanted
+        let sequence = try!(self.intents.begin(&intent));
+        try!(self.save());
+        try!(match intent {
+            Intent::Create(ref path) => self.updater.create_file(path),
+            Intent::Remove(ref path) => self.updater.remove_file(path),
+            Intent::Move(ref old_path, ref new_path) => self.updater.move_file(old_path, new_path),
+            Intent::Update(_) => Ok(()),
+            Intent::CreateDirectory(ref path) => self.updater.create_directory(path),
+            Intent::RemoveDirectory(ref path) => self.remove_directory_if_empty(path)
+        });
+        self.intents.complete(sequence)
+    }
+
+    fn remove_directory_if_empty(&mut self, path: &Path) -> io::Result<()> {
+        // Anything left inside is either not part of the fileset, or waiting on its own removal
+        if try!(fs::read_dir(self.updater.get_base_path().join(path))).next().is_some() {
+            warn!("Leaving directory {:?} in place, since it isn't empty", path);
+            return Ok(())
+        }
+        self.updater.remove_directory(path)
+    }
 
+    fn recover_intents(&mut self) -> io::Result<()> {
+        let base_path = self.updater.get_base_path().to_path_buf();
+        for (sequence, intent) in self.intents.pending() {
+            warn!("Recovering interrupted change {:?}", intent);
+            match intent {
+                Intent::Create(ref path) => {
+                    if !base_path.join(path).exists() {
+                        try!(self.updater.create_file(path));
+                    }
+                },
+                Intent::Remove(ref path) => {
+                    if base_path.join(path).exists() {
+                        try!(self.updater.remove_file(path));
+                    }
+                },
+                Intent::Move(ref old_path, ref new_path) => {
+                    if base_path.join(old_path).exists() && !base_path.join(new_path).exists() {
+                        try!(self.updater.move_file(old_path, new_path));
+                    }
+                },
+                Intent::Update(ref path) => {
+                    // The transaction itself is gone, so the file will have to be brought
+                    // up to date by the next full sync
+                    warn!("Update to {:?} was interrupted and may be incomplete", path);
+                },
+                Intent::CreateDirectory(ref path) => {
+                    if !base_path.join(path).exists() {
+                        try!(self.updater.create_directory(path));
+                    }
+                },
+                Intent::RemoveDirectory(ref path) => {
+                    if base_path.join(path).exists() {
+                        try!(self.remove_directory_if_empty(path));
+                    }
                 }
             }
+            try!(self.intents.complete(sequence));
         }
-        trace!("File {:?} complete", actual_path);
         Ok(())
     }
 
+    // Logs an operation generated here on its way out
+    fn logged(&mut self, operation: FileSetOperation<FU>) -> FileSetOperation<FU> {
+        if let (Some(state), Some(logged_operation)) = (operation.state().cloned(), LoggedOperation::from_operation(&operation)) {
+            self.log_operation(&state, operation.hybrid_time().unwrap_or_default(), &logged_operation);
+            self.sync_log();
+        }
+        operation
+    }
 
-        fn save(&self) -> io::Result<()> {
-            let store_path = self.storage_path.join("crdt");
-            trace!("Saving fileset to {:?}", store_path);
-            let mut store_file = try!(fs::File::create(store_path.as_path()));
-            try!(self.compress_to(&mut store_file));
-            Ok(())
+    fn log_operation(&mut self, state: &State, hybrid_time: HybridTimestamp, operation: &LoggedOperation) {
+        // The operation has already been applied, so a log that can't be written
+        // to shouldn't stop it going out
+        if let Err(e) = self.operation_log.append(state, hybrid_time, operation) {
+            warn!("Could not log {}: {}", state, e);
         }
+    }
 
+    fn sync_log(&mut self) {
+        if let Err(e) = self.operation_log.sync() {
+            warn!("Could not sync the operation log: {}", e);
+        }
+    }
 
+    fn record_change(&mut self, id: FileID, content_changed: bool) {
+        // Local changes are recorded just after their state is stamped. Changes
+        // from a file list sync are counted as local too, which only means they
+        // might be sent to a site that already has them.
+        let state = State {
+            site_id: self.site_id,
+            time_stamp: self.last_timestamp.saturating_sub(1)
+        };
+        self.record_change_at(id, content_changed, &state)
+    }
 
-}
+    fn record_change_at(&mut self, id: FileID, content_changed: bool, state: &State) {
+        self.modified_at.entry(id).or_insert_with(VersionVector::new).observe(state.site_id, state.time_stamp + 1);
+        if content_changed {
+            self.content_generations.insert(id, self.generation);
+        }
+        if self.indexers.is_empty() {
+            return
+        }
+        let pending = self.pending_index_changes.entry(id).or_insert(false);
+        *pending = *pending || content_changed;
+        self.last_index_change = Some(Instant::now());
+    }
 
-impl<FU:FileUpdater> fmt::Debug for FileSet<FU> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // files: HashMap<(u32, u32), FileMetadata>,
-        // id_lookup: HashMap<String, (u32, u32)>,
-        // updater: FU,
-        // last_timestamp: u32,
-        // last_id: u32,
-        // site_id: u32,
-        // storage_path: PathBuf
-        try!(writeln!(f, "files: {:?}", self.files));
-        writeln!(f, "last_timestamp: {:?}, last_id: {:?}", self.last_timestamp, self.last_id)
+    fn notify(&mut self, event: SyncEvent) {
+        trace!("Sync event {:?}", event);
+        for listener in self.listeners.iter_mut() {
+            listener.on_sync_event(&event);
+        }
+    }
+
+    fn report_conflict(&mut self, conflict: ResolvedConflict) {
+        trace!("Resolved conflict {:?}", conflict);
+        if let Some(ref mut handler) = self.conflict_handler {
+            handler.on_conflict_resolved(&conflict);
+        }
+    }
+
+    fn get_next_id(&mut self) -> Result<u64, FileSetError> {
+        match self.id_allocation {
+            IdAllocation::Sequential => {
+                let id = self.last_id;
+                self.last_id = try!(id.checked_add(1).ok_or(FileSetError::IDsExhausted));
+                Ok(id)
+            },
+            IdAllocation::Random => {
+                let random_state = RandomState::new();
+                loop {
+                    let mut hasher = random_state.build_hasher();
+                    hasher.write_u64(self.last_id);
+                    self.last_id = self.last_id.wrapping_add(1);
+                    let id = hasher.finish();
+                    if !self.files.contains_key(&(self.site_id, id)) {
+                        return Ok(id)
+                    }
+                }
+            }
+        }
+    }
+
+    fn integrate_operation(&mut self, mut remote: FileSetOperation<FU>) -> Result<(), FileSetError> {
+        self.resolve_aliases(&mut remote);
+        let context = self.operation_context(&remote);
+        if self.is_own_operation(&remote) {
+            // Forwarded back to us by another site, and already applied when it was generated
+            trace!("Skipping {}, which originated here", context.operation);
+            return Ok(())
+        }
+        if self.is_duplicate(&remote) {
+            // A transport retried a delivery that had already succeeded
+            trace!("Skipping {}, which has already been applied", context.operation);
+            return Ok(())
+        }
+        if let Some(site_id) = self.refused_site(&remote) {
+            // Applying it could leave the two sites with different files, so nothing
+            // from the site is taken until it announces the same limits as this one
+            warn!("Refusing {} from site {}, which uses different path limits", context.operation, site_id);
+            return Err(FileSetError::InOperation(context, Box::new(FileSetError::PathLimitsDiffer(site_id))))
+        }
+        if let Err(e) = self.validate_operation(&remote) {
+            warn!("Quarantining {} ({}): {}", context.operation, context.producer.as_ref().map_or("unknown producer", |producer| producer.as_str()), e);
+            self.quarantined.push(remote);
+            return Err(FileSetError::InOperation(context, Box::new(e)))
+        }
+        let state = remote.state().cloned();
+        let hybrid_time = remote.hybrid_time();
+        if let Some(hybrid_time) = hybrid_time {
+            // Whatever is changed here next comes after this change
+            self.clock.observe(hybrid_time);
+        }
+        let logged_operation = LoggedOperation::from_operation(&remote);
+        trace!("Integrating {}", context.operation);
+        self.generation += 1;
+        match remote {
+            FileSetOperation::Create(ref o) => self.record_change_at(o.id, true, &o.state),
+            FileSetOperation::Remove(ref o) => self.record_change_at(o.id, false, &o.state),
+            FileSetOperation::Update(ref o, _) => self.record_change_at(o.id, true, &o.state),
+            FileSetOperation::UpdateMetadata(ref o) => self.record_change_at(o.id, false, &o.state),
+            FileSetOperation::MoveFolder(ref o) => {
+                for &(id, _) in o.files.iter() {
+                    self.record_change_at(id, false, &o.state);
+                }
+            },
+            FileSetOperation::Bundle(_) | FileSetOperation::AnnounceSite(_) => {}
+        }
+        let result = match remote {
+            FileSetOperation::Create(o) => self.integrate_create(o),
+            FileSetOperation::Remove(o) => self.integrate_remove(o),
+            FileSetOperation::Update(mut o, lookup) => self.integrate_update(&mut o, &lookup),
+            FileSetOperation::UpdateMetadata(o) => self.integrate_update_metadata(o),
+            FileSetOperation::MoveFolder(o) => self.integrate_folder_move(o),
+            FileSetOperation::Bundle(o) => self.integrate_bundle(o),
+            FileSetOperation::AnnounceSite(o) => self.integrate_announce_site(o),
+        };
+        if let (true, Some(state)) = (result.is_ok(), state) {
+            self.applied.insert(&state);
+            if let Some(logged_operation) = logged_operation {
+                self.log_operation(&state, hybrid_time.unwrap_or_default(), &logged_operation);
+            }
+        }
+        result.map_err(|e| {
+            let error = FileSetError::InOperation(context, Box::new(e));
+            warn!("{}", error);
+            error
+        })
+    }
+
+    fn get_missing_predecessor(&self, operation: &FileSetOperation<FU>) -> Option<FileID> {
+        if self.is_own_operation(operation) || self.is_duplicate(operation) {
+            return None
+        }
+        match self.check_bundle(slice::from_ref(operation), &mut HashSet::new(), &mut HashSet::new()) {
+            Err(FileSetError::IDNotFound(site_id, id)) => Some((site_id, id)),
+            _ => None
+        }
+    }
+
+    fn replay_pending(&mut self) {
+        self.replay_pending_until(None)
+    }
+
+    fn replay_pending_until(&mut self, deadline: Option<Instant>) {
+        // Keep going until a pass makes no progress, since each replayed create may release more
+        loop {
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                warn!("Stopped replaying with {} operations still held", self.pending_operations.len());
+                break
+            }
+            let pending = mem::replace(&mut self.pending_operations, Vec::new());
+            let pending_count = pending.len();
+            for operation in pending {
+                if self.get_missing_predecessor(&operation).is_some() {
+                    self.pending_operations.push(operation);
+                } else if let Err(e) = self.integrate_operation(operation) {
+                    warn!("Dropping held operation: {}", e);
+                }
+            }
+            if self.pending_operations.len() == pending_count {
+                break
+            }
+        }
+        self.sync_log();
+    }
+
+    fn validate_operation(&self, operation: &FileSetOperation<FU>) -> Result<(), FileSetError> {
+        match *operation {
+            FileSetOperation::Update(ref o, _) => {
+                match self.files.get(&o.id) {
+                    Some(file_metadata) if !self.excluded.contains(&o.id) => {
+                        self.updater.validate(file_metadata.get_local_filename(), &o.data).map_err(|e| FileSetError::InvalidTransaction(e))
+                    },
+                    _ => Ok(())
+                }
+            },
+            // Every site refuses the same paths, so a create refused here was refused everywhere
+            FileSetOperation::Create(ref o) => self.check_filename_limits(&o.filename),
+            FileSetOperation::UpdateMetadata(UpdateMetadata { data: MetadataTransaction::Filename(ref filename), .. }) => self.check_filename_limits(filename),
+            FileSetOperation::MoveFolder(ref o) => {
+                for &(_, ref filename) in o.files.iter() {
+                    try!(self.check_filename_limits(filename));
+                }
+                Ok(())
+            },
+            FileSetOperation::Bundle(ref o) => {
+                for operation in o.iter() {
+                    try!(self.validate_operation(operation));
+                }
+                Ok(())
+            },
+            _ => Ok(())
+        }
+    }
+
+    // The site an operation came from, if it announced path limits other than these
+    fn refused_site(&self, operation: &FileSetOperation<FU>) -> Option<SiteId> {
+        match *operation {
+            FileSetOperation::Bundle(ref o) => o.iter().filter_map(|operation| self.refused_site(operation)).next(),
+            // A site announcing the same limits again is taken back
+            FileSetOperation::AnnounceSite(_) => None,
+            _ => operation.state().map(|state| state.site_id).filter(|&site_id| {
+                self.roster.get_path_limits(site_id).map_or(false, |path_limits| path_limits != self.path_limits)
+            })
+        }
+    }
+
+    fn check_filename_limits(&self, filename: &[String]) -> Result<(), FileSetError> {
+        if self.path_limits.allows(filename) {
+            Ok(())
+        } else {
+            Err(FileSetError::PathLimitExceeded(filename.iter().collect()))
+        }
+    }
+
+    fn is_duplicate(&self, operation: &FileSetOperation<FU>) -> bool {
+        match *operation {
+            FileSetOperation::Bundle(ref o) => !o.is_empty() && o.iter().all(|operation| self.is_duplicate(operation)),
+            _ => operation.state().map_or(false, |state| self.applied.contains(state))
+        }
+    }
+
+    fn is_own_operation(&self, operation: &FileSetOperation<FU>) -> bool {
+        match *operation {
+            FileSetOperation::Bundle(ref o) => !o.is_empty() && o.iter().all(|operation| self.is_own_operation(operation)),
+            _ => operation.state().map_or(false, |state| state.site_id == self.site_id)
+        }
+    }
+
+    fn operation_context(&self, operation: &FileSetOperation<FU>) -> OperationContext {
+        let local_path = |id: &FileID| self.files.get(id).map(|md| md.get_local_filename());
+        let (description, site_id, path) = match *operation {
+            FileSetOperation::Create(ref o) => (
+                format!("create {} of {:?}", o.state, o.id),
+                Some(o.state.site_id),
+                Some(o.filename.iter().collect())
+            ),
+            FileSetOperation::Remove(ref o) => (
+                format!("remove {} of {:?}", o.state, o.id),
+                Some(o.state.site_id),
+                local_path(&o.id)
+            ),
+            FileSetOperation::Update(ref o, _) => (
+                format!("update {} of {:?}", o.state, o.id),
+                Some(o.state.site_id),
+                local_path(&o.id)
+            ),
+            FileSetOperation::UpdateMetadata(ref o) => (
+                format!("metadata update {} of {:?}", o.state, o.id),
+                Some(o.state.site_id),
+                local_path(&o.id)
+            ),
+            FileSetOperation::MoveFolder(ref o) => (
+                format!("folder move {} of {:?}", o.state, o.old_path),
+                Some(o.state.site_id),
+                Some(o.old_path.iter().collect())
+            ),
+            FileSetOperation::Bundle(ref o) => (
+                format!("bundle of {} operations", o.len()),
+                None,
+                None
+            ),
+            FileSetOperation::AnnounceSite(ref o) => (
+                format!("announcement {} of site {}", o.state, o.site_id),
+                Some(o.state.site_id),
+                None
+            )
+        };
+        OperationContext {
+            operation: description,
+            site_id: site_id,
+            path: path,
+            // The versions stamped on the operation, not whatever the site has announced since
+            producer: operation.producer().and_then(|producer| {
+                if producer.crate_version.is_empty() {
+                    return None
+                }
+                let platform = site_id.and_then(|site_id| self.roster.get(site_id)).map_or("", |info| &info.platform);
+                Some(format!("{} {} with crdt_fileset {}", platform, producer.application_version, producer.crate_version))
+            })
+        }
+    }
+
+    fn integrate_bundle(&mut self, operations: Vec<FileSetOperation<FU>>) -> Result<(), FileSetError> {
+        // Make sure every operation in the bundle can be applied before applying any of them
+        try!(self.check_bundle(&operations, &mut HashSet::new(), &mut HashSet::new()));
+        for operation in operations {
+            try!(self.integrate_operation(operation));
+        }
+        Ok(())
+    }
+
+    fn check_bundle(&self, operations: &[FileSetOperation<FU>], created: &mut HashSet<FileID>, removed: &mut HashSet<FileID>) -> Result<(), FileSetError> {
+        for operation in operations {
+            let id = match *operation {
+                FileSetOperation::Create(ref o) => {
+                    created.insert(o.id);
+                    removed.remove(&o.id);
+                    continue;
+                },
+                FileSetOperation::Remove(ref o) => o.id,
+                FileSetOperation::Update(ref o, _) => o.id,
+                FileSetOperation::UpdateMetadata(ref o) => o.id,
+                FileSetOperation::MoveFolder(ref o) => {
+                    for &(id, _) in o.files.iter() {
+                        if !created.contains(&id) && (!self.is_known(id) || removed.contains(&id)) {
+                            return Err(FileSetError::IDNotFound(id.0, id.1))
+                        }
+                    }
+                    continue;
+                },
+                FileSetOperation::Bundle(ref o) => {
+                    try!(self.check_bundle(o, created, removed));
+                    continue;
+                },
+                FileSetOperation::AnnounceSite(_) => continue
+            };
+            if !created.contains(&id) && (!self.is_known(id) || removed.contains(&id)) {
+                return Err(FileSetError::IDNotFound(id.0, id.1))
+            }
+            if let FileSetOperation::Remove(_) = *operation {
+                created.remove(&id);
+                removed.insert(id);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_known(&self, id: FileID) -> bool {
+        self.files.contains_key(&id) || self.removed.contains_key(&id) || self.aliases.contains_key(&id)
+    }
+
+    fn integrate_announce_site(&mut self, o: SiteAnnouncement) -> Result<(), FileSetError> {
+        if self.roster.integrate(o.state, o.site_id, o.info.clone(), o.path_limits) {
+            trace!("Site {} is now known as {:?}", o.site_id, o.info.display_name);
+            self.notify(SyncEvent::SiteAnnounced {
+                site_id: o.site_id,
+                info: o.info
+            });
+        }
+        // Kept in the roster either way, so that the site's later operations are refused
+        if o.path_limits != self.path_limits {
+            return Err(FileSetError::PathLimitsDiffer(o.site_id))
+        }
+        Ok(())
+    }
+
+    fn integrate_create(&mut self, o: CreateOperation) -> Result<(), FileSetError> {
+        if self.files.contains_key(&o.id) || self.removed.contains_key(&o.id) {
+            // Brought in by a state merge before its create arrived
+            trace!("{:?} is already known", o.id);
+            return Ok(())
+        }
+        if self.merge_concurrent_creates {
+            if let Some(existing) = self.id_lookup.get_id_for(o.filename.iter().map(OsStr::new)) {
+                if existing != o.id {
+                    return self.merge_create(existing, o)
+                }
+            }
+        }
+        if self.is_ignored(o.filename.iter().collect::<PathBuf>()) {
+            // Every other site has it, but here it would land among files that
+            // aren't part of the fileset, so it's kept off the disk
+            trace!("Keeping {:?} off the disk, since its path is ignored", o.id);
+            let printed = o.filename[o.filename.len() - 1].clone();
+            self.files.insert(o.id, FileMetadata::new_entry(o.filename, printed, o.directory, &o.state, o.hybrid_time));
+            self.excluded.insert(o.id);
+            return Ok(())
+        }
+        let existing = self.id_lookup.get_id_for(o.filename.iter().map(OsStr::new));
+        let actual_filename = self.id_lookup.add_file(o.filename.iter().map(OsStr::new), o.id, o.id.0);
+        let metadata = FileMetadata::new_entry(o.filename.clone(), actual_filename, o.directory, &o.state, o.hybrid_time);
+        let path = metadata.get_local_filename();
+        let conflicted = metadata.is_conflicted();
+        let intent = metadata.create_intent();
+        self.files.insert(o.id, metadata);
+        try!(self.apply_intent(intent).map_err(|e| {FileSetError::IOError(e)}));
+        if conflicted {
+            self.notify(SyncEvent::ConflictDetected {
+                id: o.id,
+                path: path,
+                generation: self.generation
+            });
+            if let Some(existing) = existing {
+                self.report_conflict(ResolvedConflict::Create {
+                    path: o.filename,
+                    kept: existing,
+                    other: o.id,
+                    merged: false
+                });
+            }
+        }
+        Ok(())
+    }
+
+
+    fn merge_create(&mut self, existing: FileID, o: CreateOperation) -> Result<(), FileSetError> {
+        // Every site picks the same survivor, and operations on the other id are redirected to it
+        let (survivor, merged) = if o.id < existing { (o.id, existing) } else { (existing, o.id) };
+        trace!("Merging {:?} into {:?}, which was created at the same path", merged, survivor);
+        self.aliases.insert(merged, survivor);
+        self.report_conflict(ResolvedConflict::Create {
+            path: o.filename.clone(),
+            kept: survivor,
+            other: merged,
+            merged: true
+        });
+        if survivor == o.id {
+            let mut metadata = self.files.remove(&existing).unwrap();
+            let filename = metadata.get_local_filename();
+            self.id_lookup.remove_file(filename.iter());
+            self.id_lookup.add_file(filename.iter(), survivor, survivor.0);
+            metadata.set_filename(o.filename, &o.state, o.hybrid_time);
+            self.files.insert(survivor, metadata);
+            for (_, file) in self.aliases.iter_mut().filter(|&(_, ref file)| **file == existing) {
+                *file = survivor;
+            }
+            if let Some(content_generation) = self.content_generations.remove(&existing) {
+                self.content_generations.insert(survivor, content_generation);
+            }
+            if let Some(last_update) = self.last_updates.remove(&existing) {
+                self.last_updates.insert(survivor, last_update);
+            }
+            self.metadata_history.transfer(existing, survivor);
+        }
+        Ok(())
+    }
+
+    fn resolve_aliases(&self, operation: &mut FileSetOperation<FU>) {
+        let id = match *operation {
+            FileSetOperation::Create(_) | FileSetOperation::AnnounceSite(_) => return,
+            FileSetOperation::Remove(ref mut o) => &mut o.id,
+            FileSetOperation::Update(ref mut o, _) => &mut o.id,
+            FileSetOperation::UpdateMetadata(ref mut o) => &mut o.id,
+            FileSetOperation::MoveFolder(ref mut o) => {
+                for &mut (ref mut id, _) in o.files.iter_mut() {
+                    if let Some(&survivor) = self.aliases.get(id) {
+                        *id = survivor;
+                    }
+                }
+                return
+            },
+            FileSetOperation::Bundle(ref mut o) => {
+                for operation in o.iter_mut() {
+                    self.resolve_aliases(operation);
+                }
+                return
+            }
+        };
+        if let Some(&survivor) = self.aliases.get(id) {
+            *id = survivor;
+        }
+    }
+
+    fn integrate_remove(&mut self, o: RemoveOperation) -> Result<(), FileSetError> {
+        if !self.files.contains_key(&o.id) && self.removed.contains_key(&o.id) {
+            trace!("{:?} has already been removed", o.id);
+            return Ok(())
+        }
+        let outcome = {
+            let file_metadata = try!(get_target(&mut self.files, o.id));
+            remove_outcome(self.conflict_policy, self.rename_policy, self.last_updates.get(&o.id), file_metadata, &o.seen)
+        };
+        match outcome {
+            RemoveOutcome::KeepUpdated => {
+                // The removing site hadn't seen the latest update, which it will bring the file back for
+                trace!("Keeping {:?}, which was updated concurrently with its removal", o.id);
+                // The site the update came from puts it in the same place when it gets this remove
+                if let Some(filename) = self.resurrection_filename(&self.files[&o.id].filename.1) {
+                    let mut name = self.files[&o.id].entry_name();
+                    name.filename.1 = filename;
+                    let mut previous_paths = HashMap::new();
+                    self.rename_entries(vec![(o.id, name)], &mut previous_paths);
+                    return self.move_entries_on_disk(previous_paths, None)
+                }
+                return Ok(())
+            },
+            RemoveOutcome::KeepRenamed => {
+                trace!("Keeping {:?}, which was renamed concurrently with its removal", o.id);
+                if self.rename_policy == RenamePolicy::KeepAsConflict {
+                    return self.keep_as_conflict(o.id)
+                }
+                return Ok(())
+            },
+            RemoveOutcome::Remove => {}
+        }
+        let filename = self.files[&o.id].get_local_filename();
+        let intent = self.files[&o.id].remove_intent();
+        self.bury(o.id, o.state, o.seen);
+        if self.excluded.remove(&o.id) {
+            return Ok(())
+        }
+        self.id_lookup.remove_file(&filename);
+        self.apply_intent(intent).map_err(|e| {FileSetError::IOError(e)})
+    }
+
+    fn integrate_update(&mut self, o: &mut UpdateOperation<FU>, timestamp_lookup: &TimestampMap) -> Result<(), FileSetError> {
+        if !self.files.contains_key(&o.id) && self.removed.contains_key(&o.id) {
+            match self.conflict_policy {
+                // An update the removing site had already seen lost to the removal everywhere
+                ConflictPolicy::AddWins if !self.removal_saw(o.id, &o.state) => try!(self.resurrect(o.id)),
+                _ => {
+                    trace!("Discarding update to {:?}, which has been removed", o.id);
+                    return Ok(())
+                }
+            }
+        }
+        let path = try!(get_target(&mut self.files, o.id)).get_local_filename();
+        try!(timestamp_lookup.validate());
+        self.last_updates.insert(o.id, o.state);
+        if self.excluded.contains(&o.id) {
+            trace!("Skipping update to {:?}, which is excluded locally", o.id);
+            return Ok(())
+        }
+        let sequence = try!(self.intents.begin(&Intent::Update(path.clone())).map_err(|e| {FileSetError::IOError(e)}));
+        try!(self.updater.update_file(&path, timestamp_lookup, &mut o.data).map_err(|e| {FileSetError::IOError(e)}));
+        self.classify_content(o.id, &o.state);
+        self.intents.complete(sequence).map_err(|e| {FileSetError::IOError(e)})
+    }
+
+    // Every site looks at the same contents the first time they are updated, so
+    // they all come to the same answer without it being sent
+    fn classify_content(&mut self, id: FileID, state: &State) {
+        let path = match self.files.get(&id) {
+            Some(file_metadata) if file_metadata.get_attribute(attributes::CONTENT_TYPE).is_none() && !file_metadata.is_directory() && !self.excluded.contains(&id) => file_metadata.get_local_filename(),
+            _ => return
+        };
+        let mut start = Vec::with_capacity(attributes::SNIFF_LENGTH);
+        let read = fs::File::open(self.updater.get_base_path().join(&path)).and_then(|file| file.take(attributes::SNIFF_LENGTH as u64).read_to_end(&mut start));
+        if let Err(e) = read {
+            trace!("Not classifying {:?} yet, since it couldn't be read: {}", path, e);
+            return
+        }
+        let content_type = attributes::classify_content(&path, &start);
+        trace!("Classified {:?} as {}", path, content_type);
+        // Every site classifies it the same way, so there's nothing for the hybrid time to decide
+        self.files.get_mut(&id).unwrap().set_attribute(attributes::CONTENT_TYPE.to_string(), content_type.to_string(), state, HybridTimestamp::default());
+    }
+
+    fn removal_saw(&self, id: FileID, state: &State) -> bool {
+        self.removed_seen.get(&id).map_or(false, |seen| seen.includes(state))
+    }
+
+    // The version vector is what the removing site had seen, which decides whether
+    // later updates and renames bring the file back
+    fn bury(&mut self, id: FileID, state: State, seen: VersionVector) -> Option<State> {
+        if let Some(metadata) = self.files.remove(&id) {
+            self.removed.insert(id, metadata);
+            self.removed_at.insert(id, state);
+            self.removed_seen.insert(id, seen);
+        }
+        self.last_updates.get(&id).cloned()
+    }
+
+    fn resurrect(&mut self, id: FileID) -> Result<(), FileSetError> {
+        // The file comes back as it was when removed, and the rest of its contents
+        // arrive with the next file list sync
+        let mut metadata = self.removed.remove(&id).unwrap();
+        self.removed_at.remove(&id);
+        self.removed_seen.remove(&id);
+        trace!("Bringing back {:?}, which was updated after being removed", id);
+        if let Some(filename) = self.resurrection_filename(&metadata.filename.1) {
+            metadata.filename.1 = filename;
+        }
+        self.restore(id, metadata)
+    }
+
+    // A rename the removing site hadn't seen brings the file back under its new
+    // name, empty until the next file list sync, just as if it had arrived first
+    fn resurrect_renamed(&mut self, id: FileID, filename: Vec<String>, state: &State, hybrid_time: HybridTimestamp) -> Result<(), FileSetError> {
+        let mut metadata = self.removed.remove(&id).unwrap();
+        self.removed_at.remove(&id);
+        self.removed_seen.remove(&id);
+        trace!("Bringing back {:?}, which was renamed concurrently with its removal", id);
+        self.metadata_history.record(id, *state, MetadataValue::Filename(filename.clone()));
+        metadata.set_filename(filename, state, hybrid_time);
+        try!(self.restore(id, metadata));
+        if self.rename_policy == RenamePolicy::KeepAsConflict {
+            return self.keep_as_conflict(id)
+        }
+        Ok(())
+    }
+
+    fn keep_as_conflict(&mut self, id: FileID) -> Result<(), FileSetError> {
+        if let Some(filename) = self.resurrection_filename(&self.files[&id].filename.1) {
+            let mut name = self.files[&id].entry_name();
+            name.filename.1 = filename;
+            let mut previous_paths = HashMap::new();
+            self.rename_entries(vec![(id, name)], &mut previous_paths);
+            try!(self.move_entries_on_disk(previous_paths, None));
+        }
+        let path = self.files[&id].get_local_filename();
+        self.notify(SyncEvent::ConflictDetected {
+            id: id,
+            path: path,
+            generation: self.generation
+        });
+        Ok(())
+    }
+
+    fn restore(&mut self, id: FileID, mut metadata: FileMetadata) -> Result<(), FileSetError> {
+        metadata.printed_filename = self.id_lookup.add_file(metadata.filename.1.iter().map(OsStr::new), id, id.0);
+        let intent = metadata.create_intent();
+        self.files.insert(id, metadata);
+        self.apply_intent(intent).map_err(|e| {FileSetError::IOError(e)})
+    }
+
+    fn resurrection_filename(&self, filename: &[String]) -> Option<Vec<String>> {
+        match self.resurrection_folder {
+            Some(ref folder) if filename.first() != Some(folder) => {
+                Some(iter::once(folder.clone()).chain(filename.iter().cloned()).collect())
+            },
+            _ => None
+        }
+    }
+
+    fn integrate_update_metadata(&mut self, o: UpdateMetadata) -> Result<(), FileSetError> {
+        if !self.files.contains_key(&o.id) && self.removed.contains_key(&o.id) {
+            return match o.data {
+                // A rename the removing site had already seen lost to the removal everywhere
+                MetadataTransaction::Filename(filename) if self.rename_policy != RenamePolicy::RemoveWins && !self.removal_saw(o.id, &o.state) => {
+                    self.resurrect_renamed(o.id, filename, &o.state, o.hybrid_time)
+                },
+                _ => {
+                    trace!("Discarding metadata update to {:?}, which has been removed", o.id);
+                    Ok(())
+                }
+            }
+        }
+        {
+
+            match o.data{
+                MetadataTransaction::Filename(filename) => {
+                    let (superseded, conflict) = {
+                        let metadata = try!(get_target(&mut self.files, o.id));
+                        let superseded = filename_superseded(&*self.tie_breaker, metadata, o.state.time_stamp, Some(o.state.site_id), o.hybrid_time, &filename);
+                        // Only a tie, or a change that arrives after a later one, is a conflict
+                        let conflict = if metadata.filename.1 == filename || (metadata.filename_time, metadata.filename.0) < (o.hybrid_time, o.state.time_stamp) {
+                            None
+                        } else if superseded {
+                            Some((metadata.filename.1.clone(), filename.clone()))
+                        } else {
+                            Some((filename.clone(), metadata.filename.1.clone()))
+                        };
+                        (superseded, conflict)
+                    };
+                    if let Some((kept, discarded)) = conflict {
+                        self.report_conflict(ResolvedConflict::Rename {
+                            id: o.id,
+                            kept: kept,
+                            discarded: discarded
+                        });
+                    }
+                    if superseded {
+                        return Ok(())
+                    }
+                    if self.is_ignored(filename.iter().collect::<PathBuf>()) {
+                        try!(self.exclude_locally(o.id));
+                    }
+                    let (old_filename, new_filename, conflicted) = {
+                        let metadata = try!(get_target(&mut self.files, o.id));
+                        self.metadata_history.record(o.id, o.state, MetadataValue::Filename(filename.clone()));
+                        if self.excluded.contains(&o.id) {
+                            metadata.printed_filename = filename[filename.len() - 1].clone();
+                            metadata.set_filename(filename, &o.state, o.hybrid_time);
+                            return Ok(())
+                        }
+                        let old_filename = metadata.get_local_filename();
+                        self.id_lookup.remove_file(old_filename.iter());
+                        let actual_filename = self.id_lookup.add_file(filename.iter().map(OsStr::new), o.id, o.state.site_id);
+                        metadata.set_filename(filename, &o.state, o.hybrid_time);
+                        metadata.printed_filename = actual_filename;
+                        (old_filename, metadata.get_local_filename(), metadata.is_conflicted())
+                    };
+                    try!(self.apply_intent(Intent::Move(old_filename, new_filename.clone())).map_err(|e| {FileSetError::IOError(e)}));
+                    if conflicted {
+                        self.notify(SyncEvent::ConflictDetected {
+                            id: o.id,
+                            path: new_filename,
+                            generation: self.generation
+                        });
+                    }
+                    Ok(())
+                },
+                MetadataTransaction::Custom(key, value) => {
+                    let mut conflicts = Vec::new();
+                    let lock_changed = {
+                        let metadata = try!(get_target(&mut self.files, o.id));
+                        let applied = integrate_attribute(metadata, o.id, key.clone(), value.clone(), o.state.time_stamp, Some(o.state.site_id), o.hybrid_time, &*self.tie_breaker, &mut conflicts);
+                        if applied {
+                            self.metadata_history.record(o.id, o.state, MetadataValue::Attribute(key.clone(), value));
+                        }
+                        applied && key == attributes::EDIT_LOCK
+                    };
+                    for conflict in conflicts {
+                        self.report_conflict(conflict);
+                    }
+                    if lock_changed {
+                        self.notify_edit_lock(o.id);
+                    }
+                    Ok(())
+                },
+                MetadataTransaction::CustomBatch(values) => {
+                    let mut conflicts = Vec::new();
+                    let mut lock_changed = false;
+                    {
+                        let metadata = try!(get_target(&mut self.files, o.id));
+                        for (key, value) in values {
+                            if integrate_attribute(metadata, o.id, key.clone(), value.clone(), o.state.time_stamp, Some(o.state.site_id), o.hybrid_time, &*self.tie_breaker, &mut conflicts) {
+                                lock_changed = lock_changed || key == attributes::EDIT_LOCK;
+                                self.metadata_history.record(o.id, o.state, MetadataValue::Attribute(key, value));
+                            }
+                        }
+                    }
+                    for conflict in conflicts {
+                        self.report_conflict(conflict);
+                    }
+                    if lock_changed {
+                        self.notify_edit_lock(o.id);
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn notify_edit_lock(&mut self, id: FileID) {
+        let (path, lock) = {
+            let file_metadata = &self.files[&id];
+            (file_metadata.get_local_filename(), file_metadata.edit_lock(clock::wall_time()))
+        };
+        self.notify(SyncEvent::EditLockChanged {
+            id: id,
+            path: path,
+            lock: lock
+        });
+    }
+
+    fn integrate_folder_move(&mut self, o: FolderMove) -> Result<(), FileSetError> {
+        // Every site applies folder moves in the same order, so any that belong
+        // after this one are undone, and then redone once it has been applied
+        let mut previous_paths = HashMap::new();
+        let later = self.folder_moves.split_later(&o);
+        for record in later.iter().rev() {
+            self.undo_folder_move(record, &mut previous_paths);
+        }
+        let record = self.apply_folder_move(o, &mut previous_paths);
+        for &(id, _, ref name) in record.changed.iter() {
+            self.metadata_history.record(id, record.operation.state, MetadataValue::Filename(name.filename.1.clone()));
+        }
+        let whole_folder = match record.resolved {
+            Some((ref source, ref destination)) if later.is_empty() => Some((source.iter().collect(), destination.iter().collect())),
+            _ => None
+        };
+        self.folder_moves.push(record);
+        for record in later {
+            let record = self.apply_folder_move(record.operation, &mut previous_paths);
+            self.folder_moves.push(record);
+        }
+        self.move_entries_on_disk(previous_paths, whole_folder)
+    }
+
+    fn apply_folder_move(&mut self, o: FolderMove, previous_paths: &mut HashMap<FileID, PathBuf>) -> MoveRecord {
+        let record = self.folder_moves.plan(o, &self.files, &*self.tie_breaker);
+        self.rename_entries(record.changed.iter().map(|&(id, _, ref name)| (id, name.clone())).collect(), previous_paths);
+        record
+    }
+
+    fn undo_folder_move(&mut self, record: &MoveRecord, previous_paths: &mut HashMap<FileID, PathBuf>) {
+        let renames = record.undo_renames(&self.files);
+        self.rename_entries(renames, previous_paths);
+    }
+
+    fn rename_entries(&mut self, renames: Vec<(FileID, EntryName)>, previous_paths: &mut HashMap<FileID, PathBuf>) {
+        // Take everything out of the lookup before putting anything back, since
+        // the new names may overlap the old ones
+        for &(id, _) in renames.iter() {
+            if !self.excluded.contains(&id) {
+                let old_filename = self.files[&id].get_local_filename();
+                self.id_lookup.remove_file(old_filename.iter());
+                previous_paths.entry(id).or_insert(old_filename);
+            }
+        }
+        for (id, name) in renames {
+            let metadata = self.files.get_mut(&id).unwrap();
+            metadata.printed_filename = if self.excluded.contains(&id) {
+                name.filename.1[name.filename.1.len() - 1].clone()
+            } else {
+                self.id_lookup.add_file(name.filename.1.iter().map(OsStr::new), id, id.0)
+            };
+            metadata.set_entry_name(name);
+        }
+    }
+
+    fn move_entries_on_disk(&mut self, previous_paths: HashMap<FileID, PathBuf>, whole_folder: Option<(PathBuf, PathBuf)>) -> Result<(), FileSetError> {
+        let mut moves = Vec::with_capacity(previous_paths.len());
+        for (id, old_filename) in previous_paths {
+            let metadata = &self.files[&id];
+            let new_filename = metadata.get_local_filename();
+            if new_filename != old_filename {
+                moves.push((id, old_filename, new_filename, metadata.is_directory(), metadata.is_conflicted()));
+            }
+        }
+
+        let base_path = self.updater.get_base_path().to_path_buf();
+        // If the whole folder moved here without any renaming, it can go in one step
+        let whole_folder = whole_folder.and_then(|(old_folder, new_folder)| {
+            let in_one_step = !moves.is_empty() && !new_folder.starts_with(&old_folder) &&
+                base_path.join(&old_folder).is_dir() && !base_path.join(&new_folder).exists() &&
+                self.id_lookup.get_folder_ids(old_folder.iter()).is_empty() &&
+                moves.iter().all(|&(_, ref old_filename, ref new_filename, _, _)| {
+                    old_filename.strip_prefix(&old_folder).ok().map(|rest| new_folder.join(rest)).as_ref() == Some(new_filename)
+                });
+            if in_one_step { Some((old_folder, new_folder)) } else { None }
+        });
+        if let Some((old_folder, new_folder)) = whole_folder {
+            try!(self.apply_intent(Intent::Move(old_folder, new_folder)).map_err(|e| {FileSetError::IOError(e)}));
+        } else {
+            moves.sort_by_key(|&(_, _, ref new_filename, _, _)| new_filename.components().count());
+            for &(_, ref old_filename, ref new_filename, directory, _) in moves.iter() {
+                let intent = if directory {
+                    Intent::CreateDirectory(new_filename.clone())
+                } else {
+                    Intent::Move(old_filename.clone(), new_filename.clone())
+                };
+                try!(self.apply_intent(intent).map_err(|e| {FileSetError::IOError(e)}));
+            }
+            // The old directories are only emptied once everything inside has moved out
+            moves.sort_by_key(|&(_, ref old_filename, _, _, _)| usize::max_value() - old_filename.components().count());
+            for &(_, ref old_filename, _, directory, _) in moves.iter() {
+                if directory {
+                    try!(self.apply_intent(Intent::RemoveDirectory(old_filename.clone())).map_err(|e| {FileSetError::IOError(e)}));
+                }
+            }
+        }
+        for (id, _, new_filename, _, conflicted) in moves {
+            if conflicted {
+                self.notify(SyncEvent::ConflictDetected {
+                    id: id,
+                    path: new_filename,
+                    generation: self.generation
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_folders(&self, base_path: &Path, actual_path: &Path, found_folders: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in try!(fs::read_dir(actual_path)) {
+            let path = try!(entry).path();
+            if path.is_dir() && !self.is_ignored(&path) {
+                found_folders.push(path.strip_prefix(base_path).unwrap().to_path_buf());
+                try!(self.scan_folders(base_path, path.as_path(), found_folders));
+            }
+        }
+        Ok(())
+    }
+
+    // Hands every file under actual_path to visit as it is found. Folders where
+    // something changed most recently as of the last scan go first, and entries
+    // that disappear while being scanned are skipped.
+    fn scan_dir(&mut self, base_path: &Path, actual_path: &Path, visit: &mut dyn FnMut(&mut FileSet<FU>, PathBuf) -> Result<(), FileSetError>) -> Result<Option<SystemTime>, FileSetError> {
+        trace!("Scanning directory {:?}", actual_path);
+        if self.is_ignored(actual_path) {
+            return Ok(None)
+        }
+        let entries = match fs::read_dir(actual_path) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && actual_path != base_path => {
+                trace!("Directory {:?} vanished while scanning", actual_path);
+                return Ok(None)
+            },
+            Err(e) => return Err(FileSetError::IOError(e))
+        };
+        let mut folders = Vec::new();
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = match entry.and_then(|entry| entry.file_type().map(|file_type| (entry.path(), file_type))) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    trace!("Skipping an entry of {:?}: {}", actual_path, e);
+                    continue
+                }
+            };
+            match entry {
+                (path, ref file_type) if file_type.is_dir() => folders.push(path),
+                (path, _) => files.push(path)
+            }
+        }
+        // Folders not seen before go first, since they are new
+        folders.sort_by_key(|path| {
+            let cached = self.scan_mtimes.get(path.strip_prefix(base_path).unwrap());
+            (cached.is_none(), cached.cloned(), cmp::Reverse(path.clone()))
+        });
+        files.sort();
+        let mut latest = fs::metadata(actual_path).and_then(|metadata| metadata.modified()).ok();
+        for path in folders.into_iter().rev() {
+            let modified = try!(self.scan_dir(base_path, path.as_path(), visit));
+            latest = cmp::max(latest, modified);
+        }
+        for path in files {
+            try!(visit(self, path.strip_prefix(base_path).unwrap().to_path_buf()));
+        }
+        if let Some(latest) = latest {
+            self.scan_mtimes.insert(actual_path.strip_prefix(base_path).unwrap().to_path_buf(), latest);
+        }
+        trace!("Directory {:?} complete", actual_path);
+        Ok(latest)
+    }
+
+    fn local_create_operations(&mut self, base_path: &Path, relative_path: &Path, operations: &mut Vec<FileSetOperation<FU>>) -> Result<(), FileSetError> {
+        operations.push(try!(self.process_create(relative_path)));
+        if try!(fs::metadata(base_path.join(relative_path)).map_err(|e| FileSetError::IOError(e))).len() > 0 {
+            let mut id = (0, 0);
+            if let Some(&FileSetOperation::Create(ref co)) = operations.get(operations.len() - 1)
+            {
+                id = co.id
+            }
+            operations.push(try!(self.local_update_operation(relative_path, id).map_err(|e| FileSetError::IOError(e))));
+        }
+        Ok(())
+    }
+
+    fn local_update_operation(&mut self, relative_path: &Path, id: FileID) -> io::Result<FileSetOperation<FU>> {
+        let (local_changes, local_timestamps) = try!(self.updater.get_local_changes(relative_path));
+        let state = self.create_state();
+        self.last_updates.insert(id, state);
+        self.classify_content(id, &state);
+        self.record_change(id, true);
+        trace!("Generated update {}", state);
+        Ok(self.logged(FileSetOperation::Update(UpdateOperation {
+            state: state,
+            producer: self.producer(),
+            id: id,
+            data: local_changes
+        }, local_timestamps)))
+    }
+
+
+        fn save(&self) -> io::Result<()> {
+            let store_path = self.storage_path.join("crdt");
+            trace!("Saving fileset to {:?}", store_path);
+            let mut store_file = try!(fs::File::create(store_path.as_path()));
+            try!(self.compress_to(&mut store_file));
+            Ok(())
+        }
+
+
+
+}
+
+fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    if path.is_absolute() {
+        return Ok(path.to_path_buf())
+    }
+    env::current_dir().map(|current_dir| current_dir.join(path))
+}
+
+fn build_id_lookup(files: &HashMap<FileID, FileMetadata>, excluded: &HashSet<FileID>) -> IDLookup {
+    let mut id_lookup = IDLookup::new();
+    // Files excluded from this site have no local copy to look up
+    for (&id, file_metadata) in files.iter() {
+        if !excluded.contains(&id) {
+            id_lookup.add_file(file_metadata.get_local_filename().iter(), id, id.0);
+        }
+    }
+    id_lookup
+}
+
+fn entry_attributes(directory: bool, state: &State) -> HashMap<String, (u64, String)> {
+    let mut attributes = HashMap::new();
+    if directory {
+        attributes.insert(attributes::KIND.to_string(), (state.time_stamp, attributes::DIRECTORY.to_string()));
+    }
+    attributes
+}
+
+// Returns whether the value was newer than the one already there, and so was kept
+// Changes that lose to a value that is already there, or that win a tie with a
+// different one, are added to the conflicts
+// Changes to a name or attribute are ordered by hybrid time first, so the one made
+// last by the wall clock is kept, then by timestamp, and the tie breaker settles the rest
+// The site of the incoming change isn't known when it comes from a merged state
+fn integrate_attribute(metadata: &mut FileMetadata, id: FileID, key: String, value: String, time_stamp: u64, site_id: Option<SiteId>, hybrid_time: HybridTimestamp, tie_breaker: &dyn TieBreaker, conflicts: &mut Vec<ResolvedConflict>) -> bool {
+    if let Some(&(current_stamp, ref current)) = metadata.attributes.get(&key) {
+        let current_time = (metadata.attribute_times.get(&key).cloned().unwrap_or_default(), current_stamp);
+        let incoming_time = (hybrid_time, time_stamp);
+        let prefers_incoming = if current_time == incoming_time {
+            let current = Contender {
+                site_id: metadata.attribute_sites.get(&key).cloned(),
+                value: current
+            };
+            let incoming = Contender {
+                site_id: site_id,
+                value: &value
+            };
+            tie_breaker.prefers_incoming(Some(&key), &current, &incoming)
+        } else {
+            current_time < incoming_time
+        };
+        if *current != value && current_time >= incoming_time {
+            let (kept, discarded) = if prefers_incoming { (value.clone(), current.clone()) } else { (current.clone(), value.clone()) };
+            conflicts.push(ResolvedConflict::Attribute {
+                id: id,
+                key: key.clone(),
+                kept: kept,
+                discarded: discarded
+            });
+        }
+        if !prefers_incoming {
+            return false
+        }
+    }
+    match site_id {
+        Some(site_id) => metadata.attribute_sites.insert(key.clone(), site_id),
+        None => metadata.attribute_sites.remove(&key)
+    };
+    metadata.attribute_times.insert(key.clone(), hybrid_time);
+    metadata.attributes.insert(key, (time_stamp, value));
+    true
+}
+
+// What a remove does to a file that is still here. Integrating the remove and
+// replaying it from the operation log both decide it here, so they agree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RemoveOutcome {
+    Remove,
+    // The removing site hadn't seen the latest update
+    KeepUpdated,
+    // The removing site hadn't seen the latest rename
+    KeepRenamed
+}
+
+fn remove_outcome(conflict_policy: ConflictPolicy, rename_policy: RenamePolicy, last_update: Option<&State>, metadata: &FileMetadata, seen: &VersionVector) -> RemoveOutcome {
+    let concurrent_update = last_update.map_or(false, |update| !seen.includes(update));
+    if conflict_policy == ConflictPolicy::AddWins && concurrent_update {
+        return RemoveOutcome::KeepUpdated
+    }
+    let concurrent_rename = metadata.filename_state().map_or(false, |rename| !seen.includes(&rename));
+    if rename_policy != RenamePolicy::RemoveWins && concurrent_rename {
+        return RemoveOutcome::KeepRenamed
+    }
+    RemoveOutcome::Remove
+}
+
+fn filename_superseded(tie_breaker: &dyn TieBreaker, metadata: &FileMetadata, time_stamp: u64, site_id: Option<SiteId>, hybrid_time: HybridTimestamp, filename: &[String]) -> bool {
+    let current_time = (metadata.filename_time, metadata.filename.0);
+    let incoming_time = (hybrid_time, time_stamp);
+    if current_time != incoming_time {
+        return current_time > incoming_time
+    }
+    let current_value = metadata.filename.1.join("/");
+    let incoming_value = filename.join("/");
+    let current = Contender {
+        site_id: metadata.filename_site,
+        value: &current_value
+    };
+    let incoming = Contender {
+        site_id: site_id,
+        value: &incoming_value
+    };
+    !tie_breaker.prefers_incoming(None, &current, &incoming)
+}
+
+fn compare_paths(path1: &Path, path2: &Path) -> cmp::Ordering {
+    path1.iter().map(|component| component.to_string_lossy()).cmp(path2.iter().map(|component| component.to_string_lossy()))
+}
+
+fn get_target(files: &mut HashMap<(SiteId, u64), FileMetadata>, id: FileID) -> Result<&mut FileMetadata, FileSetError> {
+    match files.get_mut(&id) {
+        Some(md) => Ok(md),
+        None => Err(FileSetError::IDNotFound(id.0, id.1))
+    }
+}
+
+impl fmt::Display for FileSetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileSetError::IOError(ref e) => write!(f, "I/O error: {}", e),
+            FileSetError::IDNotFound(site_id, id) => write!(f, "no file with id ({}, {})", site_id, id),
+            FileSetError::PathNotFound(ref path) => write!(f, "no file at {:?}", path),
+            FileSetError::TimestampConflict(timestamp) => write!(f, "conflicting mappings for timestamp {}", timestamp),
+            FileSetError::ReservedAttribute(ref key) => write!(f, "attribute {} is reserved", key),
+            FileSetError::InvalidTransaction(ref e) => write!(f, "invalid transaction: {}", e),
+            FileSetError::PathLimitExceeded(ref path) => write!(f, "{:?} is beyond the fileset's path limits", path),
+            FileSetError::PathLimitsDiffer(site_id) => write!(f, "Site {} announced different path limits", site_id),
+            FileSetError::IDsExhausted => write!(f, "no file ids left to allocate"),
+            FileSetError::PathIgnored(ref path) => write!(f, "{:?} is ignored by the fileset", path),
+            FileSetError::DirectoryNotEmpty(ref path) => write!(f, "directory {:?} isn't empty", path),
+            FileSetError::NotConflictCopies(keep, discard) => write!(f, "{:?} and {:?} aren't conflict copies of one file", keep, discard),
+            FileSetError::HistoryCompacted => write!(f, "the history needed went with the last snapshot"),
+            FileSetError::InsideFileSet(ref path) => write!(f, "{:?} is inside the fileset or its ignored paths", path),
+            FileSetError::InOperation(ref context, ref e) => {
+                try!(write!(f, "{}", context.operation));
+                if let Some(site_id) = context.site_id {
+                    try!(write!(f, " from site {}", site_id));
+                }
+                if let Some(ref producer) = context.producer {
+                    try!(write!(f, " running {}", producer));
+                }
+                if let Some(ref path) = context.path {
+                    try!(write!(f, " on {:?}", path));
+                }
+                write!(f, ": {}", e)
+            }
+        }
+    }
+}
+
+impl<FU:FileUpdater> fmt::Debug for FileSet<FU> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // files: HashMap<(SiteId, u64), FileMetadata>,
+        // id_lookup: HashMap<String, (SiteId, u64)>,
+        // updater: FU,
+        // last_timestamp: u64,
+        // last_id: u64,
+        // site_id: SiteId,
+        // storage_path: PathBuf
+        try!(writeln!(f, "files: {:?}", self.files));
+        writeln!(f, "last_timestamp: {:?}, last_id: {:?}", self.last_timestamp, self.last_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileSet, FileUpdater, FileSetOperation, CreateOperation, RemoveOperation, UpdateOperation, MetadataTransaction, State, SyncEvent, SyncListener, TimestampMap, Indexer, IndexChange, IdAllocation, SiteInfo, ConflictPolicy, FileSetError, PathLimits, SiteId, OrphanPolicy, MetadataValue, SerializedFileSet, VersionVector, LoggedOperation, UpdateMetadata, TieBreaker, SitePriority, GreatestValue, ResolvedConflict, ConflictHandler, ConflictRecord, Resolution, RenamePolicy, SalvageReport, HybridTimestamp, Producer, CRATE_VERSION};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::path::{Path, PathBuf};
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::env;
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+    use std::thread;
+
+    #[derive(Debug)]
+    pub struct TestUpdater {
+        base_path: PathBuf
+    }
+
+    impl FileUpdater for TestUpdater {
+        type FileTransaction = Vec<u8>;
+        fn create_file<P: AsRef<Path>>(&mut self, filename: P) -> io::Result<()> {
+            let path = self.base_path.join(filename);
+            if let Some(parent) = path.parent() {
+                try!(fs::create_dir_all(parent));
+            }
+            fs::File::create(path).map(|_| ())
+        }
+        fn remove_file<P: AsRef<Path>>(&mut self, filename: P) -> io::Result<()> {
+            fs::remove_file(self.base_path.join(filename))
+        }
+        fn update_file<P: AsRef<Path>>(&mut self, filename: P, _: &TimestampMap, transaction: &mut Vec<u8>) -> io::Result<()> {
+            let mut file = try!(fs::File::create(self.base_path.join(filename)));
+            file.write_all(transaction)
+        }
+        fn move_file<P: AsRef<Path>>(&mut self, old_filename: P, new_filename: P) -> io::Result<()> {
+            let new_path = self.base_path.join(new_filename);
+            if let Some(parent) = new_path.parent() {
+                try!(fs::create_dir_all(parent));
+            }
+            fs::rename(self.base_path.join(old_filename), new_path)
+        }
+        fn get_local_changes<P: AsRef<Path>>(&mut self, filename: P) -> io::Result<(Vec<u8>, TimestampMap)> {
+            let mut content = Vec::new();
+            try!(try!(fs::File::open(self.base_path.join(filename))).read_to_end(&mut content));
+            Ok((content, TimestampMap::new()))
+        }
+        fn get_changes_since<P: AsRef<Path>>(&self, filename: P, _: Option<(SiteId, u64)>) -> Vec<u8> {
+            let mut content = Vec::new();
+            let path = self.base_path.join(filename);
+            if path.is_file() {
+                fs::File::open(path).unwrap().read_to_end(&mut content).unwrap();
+            }
+            content
+        }
+        fn get_base_path(&self) -> &Path {
+            &self.base_path
+        }
+        fn validate<P: AsRef<Path>>(&self, _: P, transaction: &Vec<u8>) -> io::Result<()> {
+            if transaction.starts_with(b"corrupt") {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt transaction"))
+            }
+            Ok(())
+        }
+    }
+
+    pub fn test_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("crdt_fileset_test_{}", name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join(".crdt")).unwrap();
+        path
+    }
+
+    pub fn open_fileset(base_path: &Path, site_id: SiteId) -> FileSet<TestUpdater> {
+        let updater = TestUpdater {
+            base_path: base_path.to_path_buf()
+        };
+        FileSet::new(updater, site_id, base_path.join(".crdt")).unwrap()
+    }
+
+    pub struct RecordingListener {
+        events: Rc<RefCell<Vec<SyncEvent>>>
+    }
+
+    impl SyncListener for RecordingListener {
+        fn on_sync_event(&mut self, event: &SyncEvent) {
+            self.events.borrow_mut().push(event.clone());
+        }
+    }
+
+    pub struct RecordingHandler {
+        conflicts: Rc<RefCell<Vec<ResolvedConflict>>>
+    }
+
+    impl ConflictHandler for RecordingHandler {
+        fn on_conflict_resolved(&mut self, conflict: &ResolvedConflict) {
+            self.conflicts.borrow_mut().push(conflict.clone());
+        }
+    }
+
+    pub struct RecordingIndexer {
+        batches: Rc<RefCell<Vec<Vec<IndexChange>>>>
+    }
+
+    impl Indexer for RecordingIndexer {
+        fn index(&mut self, changes: &[IndexChange]) {
+            self.batches.borrow_mut().push(changes.to_vec());
+        }
+    }
+
+    pub fn write_file(base_path: &Path, filename: &str, content: &[u8]) {
+        let path = base_path.join(filename);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::File::create(path).unwrap().write_all(content).unwrap();
+    }
+
+    #[test]
+    fn reconcile_local_changes() {
+        let base_path = test_dir("reconcile_local_changes");
+        write_file(&base_path, "file1", b"contents");
+        write_file(&base_path, "folder1/file2", b"");
+        let mut fileset = open_fileset(&base_path, 1);
+
+        let operations = fileset.reconcile_local();
+        assert_eq!(operations.iter().filter(|o| if let FileSetOperation::Create(_) = **o { true } else { false }).count(), 2);
+        assert_eq!(operations.iter().filter(|o| if let FileSetOperation::Update(..) = **o { true } else { false }).count(), 1);
+        assert!(fileset.has_path(&PathBuf::from("folder1/file2")));
+
+        let operations = fileset.reconcile_local();
+        assert_eq!(operations.len(), 2);
+        assert!(operations.iter().all(|o| if let FileSetOperation::Update(..) = *o { true } else { false }));
+    }
+
+    #[test]
+    fn reconcile_local_removes() {
+        let base_path = test_dir("reconcile_local_removes");
+        write_file(&base_path, "file1", b"contents");
+        write_file(&base_path, "folder1/file2", b"");
+        let mut fileset = open_fileset(&base_path, 1);
+        fileset.reconcile_local();
+
+        fs::remove_file(base_path.join("folder1/file2")).unwrap();
+        let operations = fileset.reconcile_local();
+        assert_eq!(operations.len(), 2);
+        assert_eq!(operations.iter().filter(|o| if let FileSetOperation::Remove(_) = **o { true } else { false }).count(), 1);
+        assert!(!fileset.has_path(&PathBuf::from("folder1/file2")));
+        assert_eq!(fileset.get_all_files().len(), 1);
+    }
+
+    #[test]
+    fn conflict_events() {
+        let base_path1 = test_dir("conflict_events_1");
+        let base_path2 = test_dir("conflict_events_2");
+        write_file(&base_path1, "file1", b"");
+        write_file(&base_path2, "file1", b"");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        fileset2.add_sync_listener(RecordingListener {
+            events: events.clone()
+        });
+        fileset2.reconcile_local();
+
+        let create = fileset1.process_create(Path::new("file1")).unwrap();
+        fileset2.integrate_remote(create).ok().unwrap();
+        assert_eq!(*events.borrow(), vec![SyncEvent::ConflictDetected {
+            id: (1, 0),
+            path: PathBuf::from("file1(site 1)"),
+            generation: fileset2.get_generation()
+        }]);
+        assert!(base_path2.join("file1(site 1)").exists());
+        assert_eq!(fileset2.logical_for_on_disk(Path::new("file1(site 1)")), Some(PathBuf::from("file1")));
+        assert_eq!(fileset2.resolve_on_disk(Path::new("file1")), Some(PathBuf::from("file1")));
+        assert_eq!(fileset2.resolve_on_disk(Path::new("file2")), None);
+    }
+
+    #[test]
+    fn bundles_apply_all_or_nothing() {
+        let base_path1 = test_dir("bundles_apply_1");
+        let base_path2 = test_dir("bundles_apply_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        write_file(&base_path1, "file1", b"");
+        let bundle = {
+            let mut transaction = fileset1.begin_transaction();
+            transaction.process_create(Path::new("file1")).unwrap();
+            transaction.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+            transaction.commit()
+        };
+        let bad_bundle = FileSetOperation::Bundle(vec![
+            fileset1.process_create(Path::new("file2")).unwrap(),
+            FileSetOperation::Remove(RemoveOperation {
+                state: State {
+                    site_id: 1,
+                    time_stamp: 57
+                },
+                producer: Producer::default(),
+                id: (1, 57),
+                last_update: None,
+                seen: VersionVector::new()
+            })
+        ]);
+        assert!(fileset2.integrate_operation(bad_bundle).is_err());
+        assert!(fileset2.get_all_files().is_empty());
+        assert!(!base_path2.join("file2").exists());
+
+        fileset2.integrate_remote(bundle).ok().unwrap();
+        assert_eq!(fileset2.get_all_files().len(), 1);
+        assert_eq!(fs::read(base_path2.join("file1")).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn excluded_files_stay_in_the_fileset() {
+        let base_path = test_dir("excluded_files");
+        write_file(&base_path, "file1", b"contents");
+        let mut fileset = open_fileset(&base_path, 1);
+        fileset.reconcile_local();
+
+        fileset.exclude_locally((1, 0)).ok().unwrap();
+        assert!(!base_path.join("file1").exists());
+        assert!(fileset.reconcile_local().is_empty());
+        assert_eq!(fileset.get_all_files().len(), 1);
+
+        let mut fileset = open_fileset(&base_path, 1);
+        assert!(fileset.is_excluded_locally((1, 0)));
+        assert!(!fileset.has_path(&PathBuf::from("file1")));
+        fileset.include_locally((1, 0)).ok().unwrap();
+        assert!(base_path.join("file1").exists());
+        assert!(fileset.has_path(&PathBuf::from("file1")));
+    }
+
+    #[test]
+    fn indexers_get_batched_changes() {
+        let base_path = test_dir("indexers_get_batched_changes");
+        write_file(&base_path, "file1", b"contents");
+        let mut fileset = open_fileset(&base_path, 1);
+        let batches = Rc::new(RefCell::new(Vec::new()));
+        fileset.add_indexer(RecordingIndexer {
+            batches: batches.clone()
+        });
+
+        fileset.reconcile_local();
+        assert_eq!(*batches.borrow(), vec![vec![IndexChange::Changed {
+            id: (1, 0),
+            path: PathBuf::from("file1"),
+            content_changed: true
+        }]]);
+
+        fileset.process_file_move(Path::new("file1"), Path::new("file2")).unwrap();
+        fileset.process_set_attribute(Path::new("file2"), "tag", "red").ok().unwrap();
+        assert_eq!(batches.borrow().len(), 1);
+        fileset.flush_index_changes();
+        assert_eq!(batches.borrow()[1], vec![IndexChange::Changed {
+            id: (1, 0),
+            path: PathBuf::from("file2"),
+            content_changed: false
+        }]);
+
+        fileset.process_remove(Path::new("file2"));
+        fileset.flush_index_changes();
+        fileset.flush_index_changes();
+        assert_eq!(batches.borrow().len(), 3);
+        assert_eq!(batches.borrow()[2], vec![IndexChange::Removed((1, 0))]);
+    }
+
+    #[test]
+    fn index_batches_wait_for_changes_to_settle() {
+        let base_path = test_dir("index_debounce");
+        write_file(&base_path, "file1", b"contents");
+        let mut fileset = open_fileset(&base_path, 1);
+        let batches = Rc::new(RefCell::new(Vec::new()));
+        fileset.add_indexer(RecordingIndexer {
+            batches: batches.clone()
+        });
+        fileset.set_index_debounce(Duration::from_millis(50));
+
+        fileset.reconcile_local();
+        write_file(&base_path, "file2", b"contents");
+        fileset.reconcile_local();
+        fileset.flush_due_index_changes();
+        assert!(batches.borrow().is_empty());
+        thread::sleep(Duration::from_millis(60));
+        fileset.flush_due_index_changes();
+        fileset.flush_due_index_changes();
+        assert_eq!(batches.borrow().len(), 1);
+        assert_eq!(batches.borrow()[0].len(), 2);
+    }
+
+    #[test]
+    fn derived_data_goes_stale_on_content_changes() {
+        let base_path = test_dir("derived_data_stale");
+        write_file(&base_path, "file1", b"contents");
+        let mut fileset = open_fileset(&base_path, 1);
+        fileset.reconcile_local();
+        let derived_generation = fileset.get_generation();
+        assert!(!fileset.is_derived_stale((1, 0), derived_generation));
+
+        fileset.process_file_move(Path::new("file1"), Path::new("file2")).unwrap();
+        assert!(!fileset.is_derived_stale((1, 0), derived_generation));
+        fileset.process_update(Path::new("file2"), b"new contents".to_vec(), TimestampMap::new());
+        assert!(fileset.is_derived_stale((1, 0), derived_generation));
+
+        let fileset = open_fileset(&base_path, 1);
+        let mut derived = HashMap::new();
+        derived.insert((1, 0), derived_generation);
+        assert_eq!(fileset.get_stale_derived(&derived), vec![(1, 0)]);
+        derived.insert((1, 0), fileset.get_generation());
+        assert!(fileset.get_stale_derived(&derived).is_empty());
+    }
+
+    #[test]
+    fn damaged_index_is_rebuilt() {
+        let base_path = test_dir("damaged_index");
+        write_file(&base_path, "folder1/file1", b"contents");
+        let mut fileset = open_fileset(&base_path, 1);
+        fileset.reconcile_local();
+        assert!(fileset.verify_index().is_empty());
+
+        fileset.id_lookup.remove_file(Path::new("folder1/file1"));
+        fileset.id_lookup.add_file(Path::new("file2"), (3, 7), 3);
+        let mut damaged = fileset.verify_index();
+        damaged.sort();
+        assert_eq!(damaged, vec![(1, 0), (3, 7)]);
+        fileset.rebuild_index();
+        assert!(fileset.verify_index().is_empty());
+        assert!(fileset.has_path(&PathBuf::from("folder1/file1")));
+
+        let updater = TestUpdater {
+            base_path: base_path.clone()
+        };
+        let fileset = FileSet::open_verified(updater, 1, base_path.join(".crdt"), IdAllocation::Sequential).unwrap();
+        assert!(fileset.verify_index().is_empty());
+        assert!(fileset.has_path(&PathBuf::from("folder1/file1")));
+    }
+
+    #[test]
+    fn random_ids_survive_a_lost_store() {
+        let base_path = test_dir("random_ids");
+        let updater = TestUpdater {
+            base_path: base_path.clone()
+        };
+        let mut fileset = FileSet::with_id_allocation(updater, 1, base_path.join(".crdt"), IdAllocation::Random).unwrap();
+        fileset.process_create(Path::new("file1")).unwrap();
+        let first_ids: Vec<_> = fileset.get_all_files().keys().cloned().collect();
+
+        fs::remove_file(base_path.join(".crdt").join("crdt")).unwrap();
+        let updater = TestUpdater {
+            base_path: base_path.clone()
+        };
+        let mut fileset = FileSet::with_id_allocation(updater, 1, base_path.join(".crdt"), IdAllocation::Random).unwrap();
+        fileset.process_create(Path::new("file2")).unwrap();
+        assert!(fileset.get_all_files().keys().all(|id| !first_ids.contains(id)));
+    }
+
+    #[test]
+    fn stores_keep_64_bit_timestamps() {
+        let base_path = test_dir("wide_timestamps");
+        let mut fileset = open_fileset(&base_path, 1);
+        fileset.last_timestamp = u32::max_value() as u64 + 5;
+        fileset.last_id = u32::max_value() as u64 + 7;
+        write_file(&base_path, "file1", b"");
+        fileset.process_create(Path::new("file1")).unwrap();
+        fileset.save().unwrap();
+
+        let fileset = open_fileset(&base_path, 1);
+        assert_eq!(fileset.get_all_files()[&(1, u32::max_value() as u64 + 7)].get_file_timestamp(), u32::max_value() as u64 + 5);
+        assert_eq!(fileset.last_timestamp, u32::max_value() as u64 + 6);
+
+        // A store from before the format was versioned is refused rather than misread
+        fs::write(base_path.join(".crdt").join("crdt"), &[0, 0, 0, 1, 0, 0, 0, 0]).unwrap();
+        let updater = TestUpdater {
+            base_path: base_path.clone()
+        };
+        assert_eq!(FileSet::new(updater, 1, base_path.join(".crdt")).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn operations_wait_for_their_create() {
+        let base_path1 = test_dir("operations_wait_1");
+        let base_path2 = test_dir("operations_wait_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        write_file(&base_path1, "file1", b"");
+        let create = fileset1.process_create(Path::new("file1")).unwrap();
+        let update = fileset1.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+        let rename = fileset1.process_file_move(Path::new("file1"), Path::new("file2")).unwrap();
+        fileset2.integrate_remote(rename).ok().unwrap();
+        fileset2.integrate_remote(update).ok().unwrap();
+        assert_eq!(fileset2.get_pending_operation_count(), 2);
+        assert!(fileset2.get_all_files().is_empty());
+
+        fileset2.integrate_remote(create).ok().unwrap();
+        assert_eq!(fileset2.get_pending_operation_count(), 0);
+        assert_eq!(fs::read(base_path2.join("file2")).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn renames_are_kept_in_the_metadata_history() {
+        let base_path1 = test_dir("metadata_history_1");
+        let base_path2 = test_dir("metadata_history_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        write_file(&base_path1, "file1", b"");
+        let create = fileset1.process_create(Path::new("file1")).unwrap();
+        let id = fileset1.get_all_files().keys().next().cloned().unwrap();
+        fileset2.integrate_remote(create).ok().unwrap();
+        let rename = fileset1.process_file_move(Path::new("file1"), Path::new("file2")).unwrap();
+        let rename_state = rename.state().cloned().unwrap();
+        fileset2.integrate_remote(rename).ok().unwrap();
+        let attribute = fileset2.process_set_attribute(Path::new("file2"), "colour", "red").unwrap();
+        fileset1.integrate_remote(attribute).ok().unwrap();
+
+        for history in vec![fileset1.get_metadata_history(id), open_fileset(&base_path2, 2).get_metadata_history(id)] {
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[0].state, rename_state);
+            assert_eq!(history[0].value, MetadataValue::Filename(vec!["file2".to_string()]));
+            assert_eq!(history[1].state.site_id, 2);
+            assert_eq!(history[1].value, MetadataValue::Attribute("colour".to_string(), "red".to_string()));
+        }
+    }
+
+    #[test]
+    fn updated_files_are_classified() {
+        let base_path1 = test_dir("classified_1");
+        let base_path2 = test_dir("classified_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        for &(name, contents) in [("notes", &b"hello"[..]), ("data", &b"he\0llo"[..]), ("photo.png", &b"hello"[..])].iter() {
+            write_file(&base_path1, name, contents);
+            let create = fileset1.process_create(Path::new(name)).unwrap();
+            fileset2.integrate_remote(create).ok().unwrap();
+            assert_eq!(fileset1.get_all_files().values().find(|file| file.get_file_path()[0] == name).unwrap().is_binary(), None);
+            let update = fileset1.process_update(Path::new(name), contents.to_vec(), TimestampMap::new());
+            fileset2.integrate_remote(update).ok().unwrap();
+        }
+
+        for fileset in vec![fileset1, fileset2] {
+            let is_binary = |name: &str| fileset.get_all_files().values().find(|file| file.get_file_path()[0] == name).unwrap().is_binary();
+            assert_eq!(is_binary("notes"), Some(false));
+            assert_eq!(is_binary("data"), Some(true));
+            assert_eq!(is_binary("photo.png"), Some(true));
+        }
+    }
+
+    #[test]
+    fn states_merge_after_a_partition() {
+        let base_path1 = test_dir("state_merge_1");
+        let base_path2 = test_dir("state_merge_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        write_file(&base_path1, "a", b"");
+        write_file(&base_path1, "b", b"");
+        let create = fileset1.process_create(Path::new("a")).unwrap();
+        fileset2.integrate_remote(create).ok().unwrap();
+        fileset1.process_create(Path::new("b")).unwrap();
+        fs::rename(base_path1.join("a"), base_path1.join("a2")).unwrap();
+        fileset1.process_file_move(Path::new("a"), Path::new("a2")).unwrap();
+        fs::remove_file(base_path1.join("b")).unwrap();
+        fileset1.process_remove(Path::new("b"));
+        write_file(&base_path2, "c", b"");
+        fileset2.process_create(Path::new("c")).unwrap();
+
+        let mut state1 = Vec::new();
+        fileset1.get_serialized_state().compress_to(&mut state1).unwrap();
+        let state2 = fileset2.get_serialized_state();
+        fileset2.merge(SerializedFileSet::expand_from(&mut &state1[..]).unwrap()).ok().unwrap();
+        fileset1.merge(state2).ok().unwrap();
+
+        for (fileset, base_path) in vec![(fileset1, base_path1), (fileset2, base_path2)] {
+            let mut names: Vec<_> = fileset.get_all_files().values().map(|file| file.get_file_path().join("/")).collect();
+            names.sort();
+            assert_eq!(names, vec!["a2", "c"]);
+            assert!(base_path.join("a2").exists());
+            assert!(base_path.join("c").exists());
+            assert!(!base_path.join("a").exists());
+            assert!(!base_path.join("b").exists());
+        }
+    }
+
+    #[test]
+    fn merging_agrees_with_replaying() {
+        let base_paths: Vec<_> = (1..6).map(|site_id| test_dir(&format!("merge_replay_{}", site_id))).collect();
+        let mut filesets: Vec<_> = base_paths.iter().zip(1..6).map(|(base_path, site_id)| {
+            let mut fileset = open_fileset(base_path, site_id);
+            fileset.set_conflict_policy(ConflictPolicy::AddWins);
+            fileset
+        }).collect();
+        // Each of the first two sites creates one, so their timestamps stay level
+        for &(site, other, name) in [(0, 1, "paint.txt"), (1, 0, "kept.txt")].iter() {
+            write_file(&base_paths[site], name, b"");
+            let create = filesets[site].process_create(Path::new(name)).unwrap();
+            filesets[2].integrate_remote(copy_operation(&create)).ok().unwrap();
+            filesets[other].integrate_remote(create).ok().unwrap();
+        }
+        for fileset in filesets.iter_mut() {
+            fileset.clock.observe_at(HybridTimestamp { wall_time: u64::max_value() / 2, logical: 0 }, 0);
+        }
+
+        // The colours tie, and the update wasn't seen by the removal
+        let mut operations1 = vec![filesets[0].process_set_attribute(Path::new("paint.txt"), "colour", "red").unwrap()];
+        fs::remove_file(base_paths[0].join("kept.txt")).unwrap();
+        operations1.push(filesets[0].process_remove(Path::new("kept.txt")));
+        let mut operations2 = vec![filesets[1].process_set_attribute(Path::new("paint.txt"), "colour", "blue").unwrap()];
+        operations2.push(filesets[1].process_update(Path::new("kept.txt"), b"contents".to_vec(), TimestampMap::new()));
+        for operation in operations1 {
+            filesets[2].integrate_remote(copy_operation(&operation)).ok().unwrap();
+            filesets[1].integrate_remote(operation).ok().unwrap();
+        }
+        for operation in operations2 {
+            filesets[2].integrate_remote(copy_operation(&operation)).ok().unwrap();
+            filesets[0].integrate_remote(operation).ok().unwrap();
+        }
+
+        // The last two sites catch up by merging states instead, in either order
+        let state1 = filesets[0].get_serialized_state();
+        let state2 = filesets[1].get_serialized_state();
+        filesets[3].merge(state1.clone()).unwrap();
+        filesets[3].merge(state2.clone()).unwrap();
+        filesets[4].merge(state2).unwrap();
+        filesets[4].merge(state1).unwrap();
+        let digest = filesets[0].digest();
+        for fileset in filesets.iter() {
+            assert_eq!(fileset.get_all_files().len(), 2);
+            assert_eq!(fileset.digest(), digest);
+        }
+        let paint = filesets[3].get_all_files().values().find(|file| file.get_file_path()[0] == "paint.txt").unwrap();
+        assert_eq!(paint.get_attribute("colour"), Some("blue"));
+    }
+
+    #[test]
+    fn attribute_batches_are_sent_in_key_order() {
+        let base_path = test_dir("attribute_batch");
+        let mut fileset = open_fileset(&base_path, 1);
+        write_file(&base_path, "file1", b"");
+        fileset.process_create(Path::new("file1")).unwrap();
+        let values: HashMap<_, _> = ["shape", "colour", "size", "owner"].iter().map(|key| (key.to_string(), "value".to_string())).collect();
+        match fileset.process_set_attributes(Path::new("file1"), values).unwrap() {
+            FileSetOperation::UpdateMetadata(UpdateMetadata { data: MetadataTransaction::CustomBatch(values), .. }) => {
+                let keys: Vec<_> = values.iter().map(|&(ref key, _)| key.as_str()).collect();
+                assert_eq!(keys, vec!["colour", "owner", "shape", "size"]);
+            },
+            operation => panic!("Expected an attribute batch, got {:?}", operation)
+        }
+    }
+
+    #[test]
+    fn exports_write_the_plain_tree_now_or_as_of_a_vector() {
+        let base_path = test_dir("export");
+        let export_path = test_dir("export_now");
+        let past_export_path = test_dir("export_past");
+        let mut fileset = open_fileset(&base_path, 1);
+        fs::create_dir(base_path.join("folder1")).unwrap();
+        fileset.process_create_directory(Path::new("folder1")).unwrap();
+        write_file(&base_path, "folder1/file1", b"contents");
+        fileset.process_create(Path::new("folder1/file1")).unwrap();
+        let seen = fileset.get_version_vector();
+        fileset.process_file_move(Path::new("folder1/file1"), Path::new("folder1/file2")).unwrap();
+        fs::rename(base_path.join("folder1/file1"), base_path.join("folder1/file2")).unwrap();
+
+        assert_eq!(fileset.export_materialized(&export_path, None).unwrap(), 1);
+        assert_eq!(fs::read(export_path.join("folder1/file2")).unwrap(), b"contents");
+        assert_eq!(fileset.export_materialized(&past_export_path, Some(seen)).unwrap(), 1);
+        assert_eq!(fs::read(past_export_path.join("folder1/file1")).unwrap(), b"contents");
+        assert!(!past_export_path.join("folder1/file2").exists());
+
+        // Nothing is written where the fileset would pick it up
+        match fileset.export_materialized(base_path.join("export"), None) {
+            Err(FileSetError::InsideFileSet(_)) => {},
+            result => panic!("Exported inside the fileset: {:?}", result)
+        }
+        assert!(!base_path.join("export").exists());
+        let trash_path = test_dir("export_trash");
+        fileset.add_ignored_path(&trash_path);
+        assert!(fileset.export_materialized(trash_path.join("export"), None).is_err());
+    }
+
+    #[test]
+    fn outbound_filters_apply_to_everything_sent() {
+        let base_path1 = test_dir("outbound_filter_1");
+        let base_path2 = test_dir("outbound_filter_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        for folder in ["shared", "private"].iter() {
+            fs::create_dir(base_path1.join(folder)).unwrap();
+            fileset1.process_create_directory(Path::new(folder)).unwrap();
+            for filename in ["file1", "file2"].iter() {
+                let path = format!("{}/{}", folder, filename);
+                write_file(&base_path1, &path, b"");
+                fileset1.process_create(Path::new(&path)).unwrap();
+            }
+        }
+        fileset1.process_remove(Path::new("shared/file2"));
+        fileset1.process_remove(Path::new("private/file2"));
+        fileset1.set_outbound_filter(2, vec!["shared"]);
+
+        let seen = VersionVector::new();
+        assert_eq!(fileset1.get_changes_since(None).len(), 4);
+        assert_eq!(fileset1.get_changes_for_peer(2, None).len(), 2);
+        assert_eq!(fileset1.get_changes_since_vector_for_peer(2, &seen).len(), 2);
+        assert_eq!(fileset1.get_delta_since_vector_for_peer(2, &seen).len(), 2);
+        assert_eq!(fileset1.get_changes_for_peer(3, None).len(), 4);
+        assert!(fileset1.digest_for_peer(2) != fileset1.digest());
+        assert_eq!(fileset1.digest_for_peer(3), fileset1.digest());
+
+        // Once the peer has everything it is sent, it agrees with what it was sent
+        fileset2.merge(fileset1.get_serialized_state_for_peer(2)).unwrap();
+        assert_eq!(fileset2.get_all_files().len(), 2);
+        assert!(base_path2.join("shared/file1").exists());
+        assert!(!base_path2.join("private").exists());
+        assert_eq!(fileset2.digest(), fileset1.digest_for_peer(2));
+        assert_eq!(fileset2.get_merkle_tree(), fileset1.get_merkle_tree_for_peer(2));
+        // Only the removal under the shared folder is passed on
+        assert_eq!(fileset2.removed.len(), 1);
+    }
+
+    #[test]
+    fn converged_filesets_have_the_same_digest() {
+        let base_path1 = test_dir("digest_1");
+        let base_path2 = test_dir("digest_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        assert_eq!(fileset1.digest(), fileset2.digest());
+
+        write_file(&base_path1, "file1", b"");
+        write_file(&base_path2, "file1", b"");
+        let create1 = fileset1.process_create(Path::new("file1")).unwrap();
+        let create2 = fileset2.process_create(Path::new("file1")).unwrap();
+        let attribute = fileset1.process_set_attribute(Path::new("file1"), "colour", "red").unwrap();
+        assert!(fileset1.digest() != fileset2.digest());
+        fileset2.integrate_remote(create1).ok().unwrap();
+        fileset2.integrate_remote(attribute).ok().unwrap();
+        fileset1.integrate_remote(create2).ok().unwrap();
+        // Each site prints the other's copy of file1 under a conflict name
+        assert_eq!(fileset1.digest(), fileset2.digest());
+    }
+
+    #[test]
+    fn ignored_paths_stay_out_of_the_fileset() {
+        let base_path1 = test_dir("ignored_1");
+        let base_path2 = test_dir("ignored_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        fileset1.add_ignored_path(base_path1.join("trash"));
+        assert!(fileset1.is_ignored(".crdt/crdt"));
+        assert!(fileset1.is_ignored("trash/file1"));
+
+        write_file(&base_path1, "trash/file1", b"");
+        write_file(&base_path1, "file2", b"");
+        let operations = fileset1.reconcile_local();
+        assert_eq!(operations.len(), 1);
+        match fileset1.process_create(Path::new("trash/file1")) {
+            Err(FileSetError::PathIgnored(path)) => assert_eq!(path, PathBuf::from("trash/file1")),
+            _ => panic!("ignored path was added")
+        }
+
+        write_file(&base_path2, "trash/file3", b"");
+        let create = fileset2.process_create(Path::new("trash/file3")).unwrap();
+        fileset1.integrate_remote(create).ok().unwrap();
+        assert_eq!(fileset1.get_all_files().len(), 2);
+        assert!(!fileset1.has_path(&PathBuf::from("trash/file3")));
+        assert!(!base_path1.join("trash/file3").exists());
+    }
+
+    #[test]
+    fn merkle_trees_find_diverging_files() {
+        let base_path1 = test_dir("merkle_1");
+        let base_path2 = test_dir("merkle_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        for name in ["folder1/file1", "folder1/file2", "folder2/file3", "file4"].iter() {
+            write_file(&base_path1, name, b"");
+            let create = fileset1.process_create(Path::new(name)).unwrap();
+            fileset2.integrate_remote(create).ok().unwrap();
+        }
+        assert_eq!(fileset1.get_merkle_tree(), fileset2.get_merkle_tree());
+
+        let file2 = fileset1.id_lookup.get_id_for(Path::new("folder1/file2")).unwrap();
+        let file3 = fileset1.id_lookup.get_id_for(Path::new("folder2/file3")).unwrap();
+        fileset1.process_set_attribute(Path::new("folder1/file2"), "colour", "red").unwrap();
+        fs::rename(base_path1.join("folder2/file3"), base_path1.join("file3")).unwrap();
+        fileset1.process_file_move(Path::new("folder2/file3"), Path::new("file3")).unwrap();
+        write_file(&base_path2, "folder2/file5", b"");
+        fileset2.process_create(Path::new("folder2/file5")).unwrap();
+        let file5 = fileset2.id_lookup.get_id_for(Path::new("folder2/file5")).unwrap();
+
+        let tree1 = fileset1.get_merkle_tree();
+        let tree2 = fileset2.get_merkle_tree();
+        assert_eq!(tree1.get_folder_hashes().keys().collect::<Vec<_>>(), vec!["folder1"]);
+        assert_eq!(tree2.get_folder_hashes().keys().collect::<Vec<_>>(), vec!["folder1", "folder2"]);
+        assert!(tree1.get_node("folder1").unwrap().get_hash() != tree2.get_node("folder1").unwrap().get_hash());
+        let mut expected = vec![file2, file3, file5];
+        expected.sort();
+        assert_eq!(tree1.diverging_files(&tree2), expected);
+        assert_eq!(tree2.diverging_files(&tree1), expected);
+    }
+
+    #[test]
+    fn deltas_hold_only_files_changed_since_the_vector() {
+        let base_path1 = test_dir("delta_1");
+        let base_path2 = test_dir("delta_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        for name in ["a", "b", "c"].iter() {
+            write_file(&base_path1, name, b"");
+            let create = fileset1.process_create(Path::new(name)).unwrap();
+            fileset2.integrate_remote(create).ok().unwrap();
+        }
+        let id_b = fileset1.id_lookup.get_id_for(Path::new("b")).unwrap();
+        let attribute = fileset1.process_set_attribute(Path::new("b"), "colour", "red").unwrap();
+        write_file(&base_path2, "a", b"contents");
+        let update = fileset2.process_update(Path::new("a"), b"contents".to_vec(), TimestampMap::new());
+        fileset1.integrate_remote(update).ok().unwrap();
+
+        let seen = fileset2.get_version_vector();
+        assert_eq!(fileset1.get_delta_since_vector(&seen).keys().collect::<Vec<_>>(), vec![&id_b]);
+        assert_eq!(open_fileset(&base_path1, 1).get_delta_since_vector(&seen).keys().collect::<Vec<_>>(), vec![&id_b]);
+        assert_eq!(fileset1.get_delta_since_vector(&VersionVector::new()).len(), 3);
+        fileset2.integrate_remote(attribute).ok().unwrap();
+        assert!(fileset1.get_delta_since_vector(&fileset2.get_version_vector()).is_empty());
+    }
+
+    #[test]
+    fn edit_locks_reach_other_sites() {
+        let base_path1 = test_dir("edit_lock_1");
+        let base_path2 = test_dir("edit_lock_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        fileset2.add_sync_listener(RecordingListener {
+            events: events.clone()
+        });
+
+        write_file(&base_path1, "budget.xlsx", b"");
+        let create = fileset1.process_create(Path::new("budget.xlsx")).unwrap();
+        let id = fileset1.id_lookup.get_id_for(Path::new("budget.xlsx")).unwrap();
+        fileset2.integrate_remote(create).ok().unwrap();
+        let lock = fileset1.process_lock_for_editing(Path::new("budget.xlsx"), "Anna", Duration::from_secs(60)).unwrap();
+        fileset2.integrate_remote(lock).ok().unwrap();
+        let lock = fileset2.get_edit_lock(id).unwrap();
+        assert_eq!((lock.site_id, lock.holder.as_str()), (1, "Anna"));
+        let release = fileset1.process_release_lock(Path::new("budget.xlsx")).unwrap();
+        fileset2.integrate_remote(release).ok().unwrap();
+        assert_eq!(fileset2.get_edit_lock(id), None);
+        assert_eq!(*events.borrow(), vec![SyncEvent::EditLockChanged {
+            id: id,
+            path: PathBuf::from("budget.xlsx"),
+            lock: Some(lock)
+        }, SyncEvent::EditLockChanged {
+            id: id,
+            path: PathBuf::from("budget.xlsx"),
+            lock: None
+        }]);
+
+        // A lease that has run out is no lock at all
+        fileset1.process_lock_for_editing(Path::new("budget.xlsx"), "Anna", Duration::from_secs(0)).unwrap();
+        assert_eq!(fileset1.get_edit_lock(id), None);
+    }
+
+    #[test]
+    fn applied_operations_are_logged() {
+        let base_path1 = test_dir("oplog_1");
+        let base_path2 = test_dir("oplog_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        write_file(&base_path1, "notes.txt", b"");
+        let create = fileset1.process_create(Path::new("notes.txt")).unwrap();
+        let id = fileset1.id_lookup.get_id_for(Path::new("notes.txt")).unwrap();
+        fs::rename(base_path1.join("notes.txt"), base_path1.join("todo.txt")).unwrap();
+        let rename = fileset1.process_file_move(Path::new("notes.txt"), Path::new("todo.txt")).unwrap();
+        fileset2.integrate_remote(FileSetOperation::Bundle(vec![create, rename])).ok().unwrap();
+        let seen = fileset2.get_version_vector();
+        fileset2.process_remove(Path::new("todo.txt"));
+        assert_eq!(fileset1.get_logged_operation_count(), 2);
+        drop(fileset2);
+
+        // A record cut short is dropped when the log is opened again
+        fs::OpenOptions::new().append(true).open(base_path2.join(".crdt").join("oplog")).unwrap().write_all(&[0, 0, 1]).unwrap();
+        let fileset2 = open_fileset(&base_path2, 2);
+        assert_eq!(fileset2.get_logged_operation_count(), 3);
+        let operations: Vec<_> = fileset2.get_logged_operations(0..3).unwrap().into_iter().map(|record| (record.sequence, record.state.site_id, record.operation)).collect();
+        assert_eq!(operations, vec![
+            (0, 1, LoggedOperation::Create { id: id, filename: vec!["notes.txt".to_string()], directory: false }),
+            (1, 1, LoggedOperation::Filename { id: id, filename: vec!["todo.txt".to_string()] }),
+            (2, 2, LoggedOperation::Remove { id: id, last_update: None, seen: seen })
+        ]);
+        assert_eq!(fileset2.get_logged_operations(1..2).unwrap()[0].sequence, 1);
+        assert!(fileset2.get_logged_operations(3..10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn earlier_states_are_materialized_from_the_log() {
+        let base_path = test_dir("materialize_at");
+        let mut fileset = open_fileset(&base_path, 1);
+
+        fs::create_dir_all(base_path.join("drafts")).unwrap();
+        write_file(&base_path, "drafts/report.txt", b"");
+        fileset.process_create_directory(Path::new("drafts")).unwrap();
+        fileset.process_create(Path::new("drafts/report.txt")).unwrap();
+        let id = fileset.id_lookup.get_id_for(Path::new("drafts/report.txt")).unwrap();
+        fileset.process_set_attribute(Path::new("drafts/report.txt"), "colour", "red").unwrap();
+        let created = fileset.get_version_vector();
+        fs::rename(base_path.join("drafts"), base_path.join("final")).unwrap();
+        fileset.process_folder_move(Path::new("drafts"), Path::new("final")).unwrap();
+        fileset.process_set_attribute(Path::new("final/report.txt"), "colour", "green").unwrap();
+        let moved = fileset.get_version_vector();
+        fs::remove_file(base_path.join("final/report.txt")).unwrap();
+        fileset.process_remove(Path::new("final/report.txt"));
+
+        let files = fileset.materialize_at(&created).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[&id].get_file_path(), &vec!["drafts".to_string(), "report.txt".to_string()]);
+        assert_eq!(files[&id].get_attribute("colour"), Some("red"));
+        let files = fileset.materialize_at(&moved).unwrap();
+        assert_eq!(files[&id].get_file_path(), &vec!["final".to_string(), "report.txt".to_string()]);
+        assert!(files.values().any(|file| file.get_file_path() == &vec!["final".to_string()]));
+        assert_eq!(files[&id].get_attribute("colour"), Some("green"));
+        assert!(!fileset.materialize_at(&fileset.get_version_vector()).unwrap().contains_key(&id));
+        assert!(fileset.materialize_at(&VersionVector::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn local_changes_are_undone_latest_first() {
+        let base_path1 = test_dir("undo_1");
+        let base_path2 = test_dir("undo_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        let mut operations = Vec::new();
+
+        fs::create_dir_all(base_path1.join("docs")).unwrap();
+        write_file(&base_path1, "docs/a.txt", b"");
+        operations.push(fileset1.process_create_directory(Path::new("docs")).unwrap());
+        operations.push(fileset1.process_create(Path::new("docs/a.txt")).unwrap());
+        let id = fileset1.id_lookup.get_id_for(Path::new("docs/a.txt")).unwrap();
+        operations.push(fileset1.process_set_attribute(Path::new("docs/a.txt"), "colour", "red").unwrap());
+        operations.push(fileset1.process_set_attribute(Path::new("docs/a.txt"), "colour", "green").unwrap());
+        fs::rename(base_path1.join("docs/a.txt"), base_path1.join("docs/b.txt")).unwrap();
+        operations.push(fileset1.process_file_move(Path::new("docs/a.txt"), Path::new("docs/b.txt")).unwrap());
+        fs::rename(base_path1.join("docs"), base_path1.join("papers")).unwrap();
+        operations.push(fileset1.process_folder_move(Path::new("docs"), Path::new("papers")).unwrap());
+
+        operations.push(fileset1.undo_last().unwrap().unwrap());
+        assert!(base_path1.join("docs/b.txt").is_file());
+        operations.push(fileset1.undo_last().unwrap().unwrap());
+        assert!(base_path1.join("docs/a.txt").is_file());
+        operations.push(fileset1.undo_last().unwrap().unwrap());
+        assert_eq!(fileset1.files[&id].get_attribute("colour"), Some("red"));
+
+        fs::remove_file(base_path1.join("docs/a.txt")).unwrap();
+        operations.push(fileset1.process_remove(Path::new("docs/a.txt")));
+        operations.push(fileset1.undo_last().unwrap().unwrap());
+        assert!(base_path1.join("docs/a.txt").is_file());
+        // What's left is the folder, which the file brought back is now in
+        match fileset1.undo_last() {
+            Err(FileSetError::DirectoryNotEmpty(ref path)) => assert_eq!(path, Path::new("docs")),
+            other => panic!("Expected the folder to be left alone, got {:?}", other)
+        }
+
+        for operation in operations {
+            fileset2.integrate_remote(operation).ok().unwrap();
+        }
+        assert!(base_path2.join("docs/a.txt").is_file());
+        assert!(!base_path2.join("papers").exists());
+    }
+
+    #[test]
+    fn existing_trees_are_adopted_at_one_state() {
+        let base_path1 = test_dir("adopt_1");
+        let base_path2 = test_dir("adopt_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        fs::create_dir_all(base_path1.join("photos/2019")).unwrap();
+        write_file(&base_path1, "photos/2019/beach.jpg", b"jpeg");
+        write_file(&base_path1, "photos/index.html", b"<html>");
+        write_file(&base_path1, "readme.txt", b"");
+        fs::create_dir_all(base_path1.join("empty")).unwrap();
+        let snapshot = fileset1.adopt_existing_tree().unwrap();
+        assert_eq!(fileset1.get_all_files().len(), 6);
+        assert_eq!(fileset1.get_all_files().values().filter(|file| file.is_directory()).count(), 3);
+        let time_stamp = fileset1.get_all_files().values().next().unwrap().get_file_timestamp();
+        assert!(fileset1.get_all_files().values().all(|file| file.get_file_timestamp() == time_stamp));
+        assert_eq!(fileset1.get_logged_operation_count(), 0);
+
+        fileset2.merge(snapshot).unwrap();
+        assert!(fileset2.has_path(&PathBuf::from("photos/2019/beach.jpg")));
+        assert!(base_path2.join("photos/index.html").is_file());
+        assert!(base_path2.join("empty").is_dir());
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        assert_eq!(fileset1.get_all_files().len(), 6);
+
+        // The state the tree was adopted at counts as applied, so what comes after it leaves no gap
+        write_file(&base_path1, "notes.txt", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("notes.txt")).unwrap()).ok().unwrap();
+        assert_eq!(fileset2.get_version_vector().get(1), fileset1.get_version_vector().get(1));
+
+        // Only what isn't in the fileset yet is adopted
+        write_file(&base_path1, "later.txt", b"");
+        fileset1.adopt_existing_tree().unwrap();
+        assert_eq!(fileset1.get_all_files().len(), 8);
+    }
+
+    #[test]
+    fn snapshots_compact_the_operation_log() {
+        let base_path1 = test_dir("snapshot_1");
+        let base_path2 = test_dir("snapshot_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        assert!(fileset1.get_snapshot().unwrap().is_none());
+
+        write_file(&base_path1, "a.txt", b"");
+        write_file(&base_path1, "b.txt", b"");
+        fileset1.process_create(Path::new("a.txt")).unwrap();
+        fileset1.process_create(Path::new("b.txt")).unwrap();
+        let seen = fileset1.snapshot().unwrap();
+        assert_eq!(fileset1.get_logged_operation_count(), 2);
+        assert!(fileset1.get_logged_operations(0..2).unwrap().is_empty());
+        write_file(&base_path1, "c.txt", b"");
+        let create = fileset1.process_create(Path::new("c.txt")).unwrap();
+
+        // Numbering carries on from before the snapshot, even once reopened
+        let fileset1 = open_fileset(&base_path1, 1);
+        let records = fileset1.get_logged_operations(0..10).unwrap();
+        assert_eq!(records.iter().map(|record| record.sequence).collect::<Vec<_>>(), vec![2]);
+
+        let (snapshot_seen, state) = fileset1.get_snapshot().unwrap().unwrap();
+        assert_eq!(snapshot_seen, seen);
+        fileset2.merge(state).unwrap();
+        fileset2.integrate_remote(create).ok().unwrap();
+        assert_eq!(fileset2.digest(), fileset1.digest());
+
+        // What the log no longer has is rebuilt from the snapshot, or refused
+        let mut fileset1 = fileset1;
+        assert_eq!(fileset1.materialize_at(&fileset1.get_version_vector()).unwrap().len(), 3);
+        assert_eq!(fileset1.materialize_at(&seen).unwrap().len(), 2);
+        match fileset1.materialize_at(&VersionVector::new()) {
+            Err(FileSetError::HistoryCompacted) => {},
+            result => panic!("Expected the history to be gone, got {:?}", result.map(|files| files.len()))
+        }
+        fs::rename(base_path1.join("c.txt"), base_path1.join("d.txt")).unwrap();
+        fileset1.process_file_move(Path::new("c.txt"), Path::new("d.txt")).unwrap();
+        fileset1.undo_last().unwrap().unwrap();
+        assert!(base_path1.join("c.txt").exists());
+        fileset1.undo_last().unwrap().unwrap();
+        assert!(!base_path1.join("c.txt").exists());
+        match fileset1.undo_last() {
+            Err(FileSetError::HistoryCompacted) => {},
+            result => panic!("Expected the history to be gone, got {:?}", result.map(|operation| operation.is_some()))
+        }
+    }
+
+    fn copy_operation(operation: &FileSetOperation<TestUpdater>) -> FileSetOperation<TestUpdater> {
+        match *operation {
+            FileSetOperation::Create(ref o) => FileSetOperation::Create(CreateOperation {
+                state: o.state,
+                producer: o.producer.clone(),
+                filename: o.filename.clone(),
+                id: o.id,
+                directory: o.directory,
+                hybrid_time: o.hybrid_time
+            }),
+            FileSetOperation::UpdateMetadata(ref o) => FileSetOperation::UpdateMetadata(UpdateMetadata {
+                state: o.state,
+                producer: o.producer.clone(),
+                id: o.id,
+                data: o.data.clone(),
+                hybrid_time: o.hybrid_time
+            }),
+            FileSetOperation::Update(ref o, ref timestamp_lookup) => FileSetOperation::Update(UpdateOperation {
+                state: o.state,
+                producer: o.producer.clone(),
+                id: o.id,
+                data: o.data.clone()
+            }, timestamp_lookup.clone()),
+            FileSetOperation::Remove(ref o) => FileSetOperation::Remove(RemoveOperation {
+                state: o.state,
+                producer: o.producer.clone(),
+                id: o.id,
+                last_update: o.last_update,
+                seen: o.seen.clone()
+            }),
+            ref o => panic!("Can't copy {:?}", o)
+        }
+    }
+
+    fn concurrent_colours<T: TieBreaker + Clone + 'static>(name: &str, tie_breaker: T) -> Vec<String> {
+        let base_paths: Vec<_> = (1..4).map(|site_id| test_dir(&format!("{}_{}", name, site_id))).collect();
+        let mut filesets: Vec<_> = base_paths.iter().zip(1..4).map(|(base_path, site_id)| {
+            let mut fileset = open_fileset(base_path, site_id);
+            fileset.set_tie_breaker(tie_breaker.clone());
+            fileset
+        }).collect();
+        write_file(&base_paths[0], "paint.txt", b"");
+        let create = filesets[0].process_create(Path::new("paint.txt")).unwrap();
+        filesets[2].integrate_remote(copy_operation(&create)).ok().unwrap();
+        filesets[1].integrate_remote(create).ok().unwrap();
+
+        // Timestamps are per site, so the second site catches up to the first
+        let shape = filesets[1].process_set_attribute(Path::new("paint.txt"), "shape", "round").unwrap();
+        filesets[2].integrate_remote(copy_operation(&shape)).ok().unwrap();
+        filesets[0].integrate_remote(shape).ok().unwrap();
+        // And both clocks are pinned to the same future time, so neither colour is later
+        for fileset in filesets.iter_mut() {
+            fileset.clock.observe_at(HybridTimestamp { wall_time: u64::max_value() / 2, logical: 0 }, 0);
+        }
+        let red = filesets[0].process_set_attribute(Path::new("paint.txt"), "colour", "red").unwrap();
+        let blue = filesets[1].process_set_attribute(Path::new("paint.txt"), "colour", "blue").unwrap();
+        assert_eq!(red.state().unwrap().time_stamp, blue.state().unwrap().time_stamp);
+        assert_eq!(red.hybrid_time(), blue.hybrid_time());
+        // The third site sees them in the other order from the second
+        filesets[2].integrate_remote(copy_operation(&red)).ok().unwrap();
+        filesets[2].integrate_remote(copy_operation(&blue)).ok().unwrap();
+        filesets[0].integrate_remote(blue).ok().unwrap();
+        filesets[1].integrate_remote(red).ok().unwrap();
+        filesets.iter().map(|fileset| {
+            let file = fileset.get_all_files().values().next().unwrap();
+            // Rebuilding from the log settles the tie the same way
+            let materialized = fileset.materialize_at(&fileset.get_version_vector()).unwrap();
+            assert_eq!(materialized.values().next().unwrap().get_attribute("colour"), file.get_attribute("colour"));
+            file.get_attribute("colour").unwrap().to_string()
+        }).collect()
+    }
+
+    #[test]
+    fn later_hybrid_times_win() {
+        let base_path1 = test_dir("later_hybrid_times_1");
+        let base_path2 = test_dir("later_hybrid_times_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        write_file(&base_path1, "paint.txt", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("paint.txt")).unwrap()).ok().unwrap();
+        // The first site has made more changes, but the second made its change later
+        fileset1.process_set_attribute(Path::new("paint.txt"), "shape", "round").unwrap();
+        fileset1.clock.observe_at(HybridTimestamp { wall_time: u64::max_value() / 2, logical: 0 }, 0);
+        fileset2.clock.observe_at(HybridTimestamp { wall_time: u64::max_value() / 2 + 1, logical: 0 }, 0);
+        let red = fileset1.process_set_attribute(Path::new("paint.txt"), "colour", "red").unwrap();
+        let blue = fileset2.process_set_attribute(Path::new("paint.txt"), "colour", "blue").unwrap();
+        assert!(red.state().unwrap().time_stamp > blue.state().unwrap().time_stamp);
+        fileset1.integrate_remote(blue).ok().unwrap();
+        fileset2.integrate_remote(red).ok().unwrap();
+        for fileset in &[&fileset1, &fileset2] {
+            let file = fileset.get_all_files().values().next().unwrap();
+            assert_eq!(file.get_attribute("colour"), Some("blue"));
+        }
+        assert!(fileset1.get_hybrid_time() > HybridTimestamp { wall_time: u64::max_value() / 2 + 1, logical: 0 });
+    }
+
+    #[test]
+    fn ties_are_broken_the_same_way_everywhere() {
+        assert_eq!(concurrent_colours("tie_site_priority", SitePriority), vec!["blue", "blue", "blue"]);
+        assert_eq!(concurrent_colours("tie_greatest_value", GreatestValue), vec!["red", "red", "red"]);
+    }
+
+    #[test]
+    fn resolved_conflicts_reach_the_handler() {
+        let base_path1 = test_dir("conflict_handler_1");
+        let base_path2 = test_dir("conflict_handler_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        let conflicts1 = Rc::new(RefCell::new(Vec::new()));
+        let conflicts2 = Rc::new(RefCell::new(Vec::new()));
+        fileset1.set_conflict_handler(RecordingHandler { conflicts: conflicts1.clone() });
+        fileset2.set_conflict_handler(RecordingHandler { conflicts: conflicts2.clone() });
+
+        write_file(&base_path1, "paint.txt", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("paint.txt")).unwrap()).ok().unwrap();
+        let id = *fileset1.get_all_files().keys().next().unwrap();
+        fileset1.integrate_remote(fileset2.process_set_attribute(Path::new("paint.txt"), "shape", "round").unwrap()).ok().unwrap();
+        fileset1.clock.observe_at(HybridTimestamp { wall_time: u64::max_value() / 2, logical: 0 }, 0);
+        fileset2.clock.observe_at(HybridTimestamp { wall_time: u64::max_value() / 2, logical: 0 }, 0);
+        let red = fileset1.process_set_attribute(Path::new("paint.txt"), "colour", "red").unwrap();
+        let blue = fileset2.process_set_attribute(Path::new("paint.txt"), "colour", "blue").unwrap();
+        fileset1.integrate_remote(blue).ok().unwrap();
+        fileset2.integrate_remote(red).ok().unwrap();
+        let expected = ResolvedConflict::Attribute {
+            id: id,
+            key: "colour".to_string(),
+            kept: "blue".to_string(),
+            discarded: "red".to_string()
+        };
+        assert_eq!(*conflicts1.borrow(), vec![expected.clone()]);
+        assert_eq!(*conflicts2.borrow(), vec![expected]);
+
+        write_file(&base_path1, "notes.txt", b"");
+        write_file(&base_path2, "notes.txt", b"");
+        fileset1.process_create(Path::new("notes.txt")).unwrap();
+        fileset1.integrate_remote(fileset2.process_create(Path::new("notes.txt")).unwrap()).ok().unwrap();
+        let conflicts = conflicts1.borrow();
+        match conflicts[1] {
+            ResolvedConflict::Create { ref path, kept, other, merged } => {
+                assert_eq!(*path, vec!["notes.txt".to_string()]);
+                assert_eq!((kept.0, other.0, merged), (1, 2, false));
+            },
+            ref conflict => panic!("Expected a create conflict, got {:?}", conflict)
+        }
+    }
+
+    #[test]
+    fn damaged_stores_are_salvaged() {
+        let base_path = test_dir("salvage");
+        {
+            let mut fileset = open_fileset(&base_path, 1);
+            write_file(&base_path, "alpha.txt", b"");
+            write_file(&base_path, "beta.txt", b"");
+            fileset.process_create(Path::new("alpha.txt")).unwrap();
+            fileset.process_create(Path::new("beta.txt")).unwrap();
+        }
+        // Cut the store off part way through whichever file was written second
+        let store_path = base_path.join(".crdt/crdt");
+        let store = fs::read(&store_path).unwrap();
+        let position = |name: &[u8]| store.windows(name.len()).position(|window| window == name).unwrap();
+        fs::write(&store_path, &store[..position(b"alpha.txt").max(position(b"beta.txt"))]).unwrap();
+
+        let updater = TestUpdater {
+            base_path: base_path.clone()
+        };
+        assert!(FileSet::new(updater, 1, base_path.join(".crdt")).is_err());
+        let updater = TestUpdater {
+            base_path: base_path.clone()
+        };
+        let (mut fileset, report) = FileSet::salvage(updater, 1, base_path.join(".crdt"), IdAllocation::Sequential).unwrap();
+        assert_eq!(report, SalvageReport {
+            recovered_files: 1,
+            lost_files: 1,
+            lost_history: true
+        });
+        assert_eq!(fileset.get_all_files().len(), 1);
+        // The lost file is found on disk again, under an id that wasn't used before
+        let created: Vec<_> = fileset.reconcile_local().into_iter().filter_map(|operation| match operation {
+            FileSetOperation::Create(o) => Some(o.id),
+            _ => None
+        }).collect();
+        assert_eq!(created, vec![(1, 2)]);
+        assert_eq!(open_fileset(&base_path, 1).get_all_files().len(), 2);
+    }
+
+    #[test]
+    fn standbys_leave_the_disk_alone_until_promoted() {
+        let base_path1 = test_dir("standby_1");
+        let base_path2 = test_dir("standby_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        fileset2.set_standby(true);
+        let info = SiteInfo {
+            display_name: "Hub".to_string(),
+            device_name: "Standby hub".to_string(),
+            platform: "linux".to_string(),
+            public_key: Vec::new(),
+            application_version: "1.0".to_string(),
+            crate_version: CRATE_VERSION.to_string()
+        };
+
+        write_file(&base_path1, "shared.txt", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("shared.txt")).unwrap()).ok().unwrap();
+        write_file(&base_path2, "stray.txt", b"");
+        assert!(fileset2.reconcile_local().is_empty());
+
+        // Another site has already been announced under the standby's id
+        let (_, announcement) = fileset1.allocate_site_id(info.clone());
+        let taken = match announcement {
+            FileSetOperation::AnnounceSite(ref o) => o.site_id,
+            _ => unreachable!()
+        };
+        fileset2.integrate_remote(announcement).ok().unwrap();
+        fileset2.site_id = taken;
+        let other_info = SiteInfo {
+            device_name: "Anna's laptop".to_string(),
+            ..info.clone()
+        };
+        fileset2.integrate_remote(fileset1.process_announce_site(taken, other_info)).ok().unwrap();
+
+        let (site_id, operations) = fileset2.promote(info.clone());
+        assert!(site_id != taken);
+        assert!(!fileset2.is_standby());
+        assert_eq!(fileset2.site_info(site_id), Some(&info));
+        match operations[0] {
+            FileSetOperation::AnnounceSite(ref o) => assert_eq!(o.state.site_id, site_id),
+            ref o => panic!("Expected an announcement, got {:?}", o)
+        }
+        assert!(operations.iter().any(|operation| match *operation {
+            FileSetOperation::Create(ref o) => o.id.0 == site_id && o.filename == vec!["stray.txt".to_string()],
+            _ => false
+        }));
+        for operation in operations {
+            fileset1.integrate_remote(operation).ok().unwrap();
+        }
+        assert!(fileset1.has_path(&PathBuf::from("stray.txt")));
+        assert!(fileset2.promote(info).1.is_empty());
+    }
+
+    #[test]
+    fn shutdown_hands_back_held_operations() {
+        let base_path1 = test_dir("shutdown_1");
+        let base_path2 = test_dir("shutdown_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        write_file(&base_path1, "file1", b"");
+        fileset1.process_create(Path::new("file1")).unwrap();
+        let update = fileset1.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+        fileset2.integrate_remote(update).ok().unwrap();
+        assert!(fileset1.shutdown(Duration::from_secs(1)).ok().unwrap().is_empty());
+        assert_eq!(fileset2.shutdown(Duration::from_secs(1)).ok().unwrap().len(), 1);
+
+        let fileset1 = open_fileset(&base_path1, 1);
+        assert_eq!(fileset1.get_all_files().len(), 1);
+        let fileset2 = open_fileset(&base_path2, 2);
+        assert_eq!(fileset2.get_pending_operation_count(), 0);
+    }
+
+    #[test]
+    fn site_announcements_reach_listeners() {
+        let mut fileset1 = open_fileset(&test_dir("site_announcements_1"), 1);
+        let mut fileset2 = open_fileset(&test_dir("site_announcements_2"), 2);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        fileset2.add_sync_listener(RecordingListener {
+            events: events.clone()
+        });
+        let info = SiteInfo {
+            display_name: "Anna".to_string(),
+            device_name: "Anna's laptop".to_string(),
+            platform: "linux".to_string(),
+            public_key: Vec::new(),
+            application_version: "1.0".to_string(),
+            crate_version: CRATE_VERSION.to_string()
+        };
+
+        let announcement = fileset1.process_announce_site(1, info.clone());
+        fileset2.integrate_remote(announcement).ok().unwrap();
+        assert_eq!(fileset2.site_info(1), Some(&info));
+        assert_eq!(*events.borrow(), vec![SyncEvent::SiteAnnounced {
+            site_id: 1,
+            info: info
+        }]);
+    }
+
+    #[test]
+    fn allocated_site_ids_are_announced() {
+        let base_path3 = test_dir("allocated_site_ids_3");
+        let mut fileset1 = open_fileset(&test_dir("allocated_site_ids_1"), 1);
+        let mut fileset2 = open_fileset(&test_dir("allocated_site_ids_2"), 2);
+        let info = SiteInfo {
+            display_name: "Ben".to_string(),
+            device_name: "Ben's NAS".to_string(),
+            platform: "linux".to_string(),
+            public_key: Vec::new(),
+            application_version: "1.0".to_string(),
+            crate_version: CRATE_VERSION.to_string()
+        };
+
+        let (site_id, announcement) = fileset1.allocate_site_id(info.clone());
+        assert!(site_id != 1 && site_id != 2);
+        fileset2.integrate_remote(announcement).ok().unwrap();
+        assert_eq!(fileset1.site_info(site_id), Some(&info));
+        assert_eq!(fileset2.site_info(site_id), Some(&info));
+
+        // The new replica's files carry its permanent id
+        let mut fileset3 = open_fileset(&base_path3, site_id);
+        write_file(&base_path3, "file1", b"");
+        fileset2.integrate_remote(fileset3.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+        assert!(fileset2.get_all_files().contains_key(&(site_id, 0)));
+    }
+
+    #[test]
+    fn acknowledged_tombstones_are_collected() {
+        let base_path1 = test_dir("tombstone_gc_1");
+        let base_path2 = test_dir("tombstone_gc_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+        let remove = fileset1.process_remove(Path::new("file1"));
+
+        // Site 2 hasn't seen the remove yet
+        let mut acked = HashMap::new();
+        acked.insert(2, fileset2.get_version_vector());
+        assert_eq!(fileset1.gc(&acked), 0);
+
+        // Site 2 has seen it, but made a change before saying so that hasn't arrived
+        fileset2.integrate_remote(remove).ok().unwrap();
+        write_file(&base_path2, "file2", b"");
+        let create = fileset2.process_create(Path::new("file2")).unwrap();
+        acked.insert(2, fileset2.get_version_vector());
+        assert_eq!(fileset1.gc(&acked), 0);
+
+        fileset1.integrate_remote(create).ok().unwrap();
+        assert_eq!(fileset1.gc(&acked), 1);
+        assert!(fileset1.removed.is_empty());
+        assert_eq!(fileset1.gc(&acked), 0);
+    }
+
+    #[test]
+    fn stable_frontier_follows_acknowledgements() {
+        let base_path1 = test_dir("stable_frontier_1");
+        let base_path2 = test_dir("stable_frontier_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+        let remove = fileset1.process_remove(Path::new("file1"));
+        assert_eq!(fileset1.stable_frontier().get(1), 2);
+
+        fileset1.acknowledge(2, &fileset2.get_version_vector());
+        assert_eq!(fileset1.stable_frontier().get(1), 1);
+        assert_eq!(fileset1.gc_acknowledged(), 0);
+
+        fileset2.integrate_remote(remove).ok().unwrap();
+        fileset1.acknowledge(2, &fileset2.get_version_vector());
+        assert_eq!(fileset1.stable_frontier().get(1), 2);
+        assert_eq!(fileset1.gc_acknowledged(), 1);
+
+        let fileset1 = open_fileset(&base_path1, 1);
+        assert_eq!(fileset1.get_acknowledgements()[&2].get(1), 2);
+    }
+
+    #[test]
+    fn silent_sites_hold_back_collection() {
+        let base_path1 = test_dir("silent_sites_1");
+        let base_path2 = test_dir("silent_sites_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+        fileset2.integrate_remote(fileset1.process_remove(Path::new("file1"))).ok().unwrap();
+        let info = SiteInfo {
+            display_name: "Anna".to_string(),
+            device_name: "Anna's laptop".to_string(),
+            platform: "linux".to_string(),
+            public_key: Vec::new(),
+            application_version: "1.0".to_string(),
+            crate_version: CRATE_VERSION.to_string()
+        };
+        let announcement = fileset1.process_announce_site(3, info);
+        fileset2.integrate_remote(announcement).ok().unwrap();
+
+        // The third site is in the roster but hasn't said what it has seen
+        fileset1.acknowledge(2, &fileset2.get_version_vector());
+        assert_eq!(fileset1.stable_frontier().get(1), 0);
+        assert_eq!(fileset1.gc_acknowledged(), 0);
+
+        let mut acked = fileset1.get_acknowledgements().clone();
+        acked.insert(3, fileset1.get_version_vector());
+        assert_eq!(fileset1.gc(&acked), 1);
+    }
+
+    #[test]
+    fn orphaned_conflict_files_are_collected() {
+        let base_path = test_dir("orphaned_conflicts");
+        let trash = test_dir("orphaned_conflicts_trash");
+        let mut fileset = open_fileset(&base_path, 1);
+        write_file(&base_path, "file1", b"");
+        fileset.process_create(Path::new("file1")).unwrap();
+        write_file(&base_path, "file1(site 2)", b"");
+        write_file(&base_path, "folder(site 3)/file2", b"");
+        write_file(&base_path, "notes(site plan)", b"");
+
+        let operations = fileset.collect_orphaned_conflicts(&OrphanPolicy::MoveInto(trash.clone())).unwrap();
+        assert!(operations.is_empty());
+        assert!(trash.join("file1(site 2)").exists());
+        assert!(trash.join("folder(site 3)/file2").exists());
+        assert!(!base_path.join("file1(site 2)").exists());
+        assert!(base_path.join("notes(site plan)").exists());
+        assert!(base_path.join("file1").exists());
+
+        write_file(&base_path, "file4(site 2)", b"");
+        fileset.collect_orphaned_conflicts(&OrphanPolicy::MoveInto(PathBuf::from(".trash"))).unwrap();
+        assert!(base_path.join(".trash/file4(site 2)").exists());
+        // What was moved isn't found again, by this or by a scan
+        fileset.collect_orphaned_conflicts(&OrphanPolicy::MoveInto(PathBuf::from(".trash"))).unwrap();
+        assert!(base_path.join(".trash/file4(site 2)").exists());
+        assert!(!base_path.join(".trash/.trash").exists());
+        fileset.reconcile_local();
+        assert!(!fileset.has_path(&PathBuf::from(".trash")));
+        assert!(fileset.get_all_files().values().all(|file| file.get_file_path()[0] != ".trash"));
+
+        write_file(&base_path, "file3(site 2)", b"");
+        let operations = fileset.collect_orphaned_conflicts(&OrphanPolicy::Adopt).unwrap();
+        assert_eq!(operations.len(), 1);
+        assert!(fileset.has_path(&PathBuf::from("file3(site 2)")));
+    }
+
+    #[test]
+    fn retried_operations_apply_once() {
+        let base_path1 = test_dir("retried_operations_1");
+        let base_path2 = test_dir("retried_operations_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+        let generation = fileset2.get_generation();
+        let retry = FileSetOperation::Create(CreateOperation {
+            state: State {
+                site_id: 1,
+                time_stamp: 0
+            },
+            producer: Producer::default(),
+            filename: vec!["file1".to_string()],
+            id: (1, 0),
+            directory: false,
+            hybrid_time: HybridTimestamp::default()
+        });
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        fileset2.integrate_remote(retry).ok().unwrap();
+        assert_eq!(fileset2.get_all_files().len(), 1);
+        assert_eq!(fileset2.get_generation(), generation);
+        assert!(!base_path2.join("file1(site 1)").exists());
+    }
+
+    fn remove_while_updating(name: &str, conflict_policy: ConflictPolicy) -> (FileSet<TestUpdater>, FileSet<TestUpdater>) {
+        let base_path1 = test_dir(&format!("{}_1", name));
+        let base_path2 = test_dir(&format!("{}_2", name));
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        fileset1.set_conflict_policy(conflict_policy);
+        fileset2.set_conflict_policy(conflict_policy);
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+
+        let remove = fileset1.process_remove(Path::new("file1"));
+        let update = fileset2.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+        fileset1.integrate_remote(update).ok().unwrap();
+        fileset2.integrate_remote(remove).ok().unwrap();
+        (fileset1, fileset2)
+    }
+
+    #[test]
+    fn conflict_policies_converge() {
+        let (fileset1, fileset2) = remove_while_updating("remove_wins", ConflictPolicy::RemoveWins);
+        assert!(fileset1.get_all_files().is_empty());
+        assert!(fileset2.get_all_files().is_empty());
+        assert_eq!(fileset1.get_pending_operation_count(), 0);
+
+        let (fileset1, fileset2) = remove_while_updating("add_wins", ConflictPolicy::AddWins);
+        assert_eq!(fileset1.get_all_files().len(), 1);
+        assert_eq!(fileset2.get_all_files().len(), 1);
+        assert!(fileset1.has_path(&PathBuf::from("file1")));
+    }
+
+    fn remove_while_renaming(name: &str, rename_policy: RenamePolicy) -> (FileSet<TestUpdater>, FileSet<TestUpdater>) {
+        let base_path1 = test_dir(&format!("{}_1", name));
+        let base_path2 = test_dir(&format!("{}_2", name));
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        for fileset in [&mut fileset1, &mut fileset2].iter_mut() {
+            fileset.set_rename_policy(rename_policy);
+            fileset.set_resurrection_folder(Some("Conflicted".to_string()));
+        }
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+
+        let remove = fileset1.process_remove(Path::new("file1"));
+        let rename = fileset2.process_file_move(Path::new("file1"), Path::new("file2")).unwrap();
+        fs::rename(base_path2.join("file1"), base_path2.join("file2")).unwrap();
+        fileset1.integrate_remote(rename).ok().unwrap();
+        fileset2.integrate_remote(remove).ok().unwrap();
+        (fileset1, fileset2)
+    }
+
+    #[test]
+    fn rename_policies_converge() {
+        let (fileset1, fileset2) = remove_while_renaming("rename_remove_wins", RenamePolicy::RemoveWins);
+        assert!(fileset1.get_all_files().is_empty());
+        assert!(fileset2.get_all_files().is_empty());
+
+        let (fileset1, fileset2) = remove_while_renaming("rename_wins", RenamePolicy::RenameWins);
+        for fileset in [&fileset1, &fileset2].iter() {
+            assert_eq!(fileset.get_all_files().len(), 1);
+            assert!(fileset.has_path(&PathBuf::from("file2")));
+            assert!(fileset.updater.get_base_path().join("file2").exists());
+        }
+
+        let (fileset1, fileset2) = remove_while_renaming("rename_kept_as_conflict", RenamePolicy::KeepAsConflict);
+        for fileset in [&fileset1, &fileset2].iter() {
+            assert!(fileset.has_path(&PathBuf::from("Conflicted/file2")));
+            assert!(!fileset.has_path(&PathBuf::from("file2")));
+            assert!(fileset.updater.get_base_path().join("Conflicted/file2").exists());
+        }
+    }
+
+    #[test]
+    fn updates_the_remover_had_seen_lose_to_the_removal() {
+        let base_paths: Vec<_> = (1..4).map(|site_id| test_dir(&format!("seen_update_{}", site_id))).collect();
+        let mut filesets: Vec<_> = base_paths.iter().zip(1..4).map(|(base_path, site_id)| {
+            let mut fileset = open_fileset(base_path, site_id);
+            fileset.set_conflict_policy(ConflictPolicy::AddWins);
+            fileset
+        }).collect();
+        write_file(&base_paths[0], "file1", b"");
+        let create = filesets[0].process_create(Path::new("file1")).unwrap();
+        filesets[2].integrate_remote(copy_operation(&create)).ok().unwrap();
+        filesets[1].integrate_remote(create).ok().unwrap();
+
+        // The second site removes the file after seeing the first site's update,
+        // and the third site gets the removal before the update
+        let update = filesets[0].process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+        filesets[1].integrate_remote(copy_operation(&update)).ok().unwrap();
+        let remove = filesets[1].process_remove(Path::new("file1"));
+        fs::remove_file(base_paths[1].join("file1")).unwrap();
+        filesets[2].integrate_remote(copy_operation(&remove)).ok().unwrap();
+        filesets[2].integrate_remote(update).ok().unwrap();
+        filesets[0].integrate_remote(remove).ok().unwrap();
+        for (fileset, base_path) in filesets.iter().zip(base_paths.iter()) {
+            assert!(fileset.get_all_files().is_empty());
+            assert!(!base_path.join("file1").exists());
+        }
+    }
+
+    #[test]
+    fn renames_the_remover_had_seen_lose_to_the_removal() {
+        let base_paths: Vec<_> = (1..4).map(|site_id| test_dir(&format!("seen_rename_{}", site_id))).collect();
+        let mut filesets: Vec<_> = base_paths.iter().zip(1..4).map(|(base_path, site_id)| {
+            let mut fileset = open_fileset(base_path, site_id);
+            fileset.set_rename_policy(RenamePolicy::RenameWins);
+            fileset
+        }).collect();
+        write_file(&base_paths[0], "file1", b"");
+        let create = filesets[0].process_create(Path::new("file1")).unwrap();
+        filesets[2].integrate_remote(copy_operation(&create)).ok().unwrap();
+        filesets[1].integrate_remote(create).ok().unwrap();
+
+        // The second site removes the file after seeing the first site's rename,
+        // and the third site gets the removal before the rename
+        let rename = filesets[0].process_file_move(Path::new("file1"), Path::new("file2")).unwrap();
+        fs::rename(base_paths[0].join("file1"), base_paths[0].join("file2")).unwrap();
+        filesets[1].integrate_remote(copy_operation(&rename)).ok().unwrap();
+        let remove = filesets[1].process_remove(Path::new("file2"));
+        fs::remove_file(base_paths[1].join("file2")).unwrap();
+        filesets[2].integrate_remote(copy_operation(&remove)).ok().unwrap();
+        filesets[2].integrate_remote(rename).ok().unwrap();
+        filesets[0].integrate_remote(remove).ok().unwrap();
+        for (fileset, base_path) in filesets.iter().zip(base_paths.iter()) {
+            assert!(fileset.get_all_files().is_empty());
+            assert!(!base_path.join("file1").exists());
+            assert!(!base_path.join("file2").exists());
+        }
+    }
+
+    #[test]
+    fn resurrected_files_go_to_the_resurrection_folder() {
+        let base_path1 = test_dir("resurrection_folder_1");
+        let base_path2 = test_dir("resurrection_folder_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        for fileset in [&mut fileset1, &mut fileset2].iter_mut() {
+            fileset.set_conflict_policy(ConflictPolicy::AddWins);
+            fileset.set_resurrection_folder(Some("Conflicted".to_string()));
+        }
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+
+        let remove = fileset1.process_remove(Path::new("file1"));
+        let update = fileset2.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+        fileset1.integrate_remote(update).ok().unwrap();
+        fileset2.integrate_remote(remove).ok().unwrap();
+        for fileset in [&fileset1, &fileset2].iter() {
+            assert!(fileset.has_path(&PathBuf::from("Conflicted/file1")));
+            assert!(!fileset.has_path(&PathBuf::from("file1")));
+        }
+        assert!(base_path2.join("Conflicted/file1").exists());
+        assert!(!base_path2.join("file1").exists());
+    }
+
+    #[test]
+    fn conflict_copies_merge_into_one() {
+        let base_path1 = test_dir("conflict_copies_1");
+        let base_path2 = test_dir("conflict_copies_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        write_file(&base_path1, "file1", b"");
+        write_file(&base_path2, "file1", b"");
+        let create1 = fileset1.process_create(Path::new("file1")).unwrap();
+        let create2 = fileset2.process_create(Path::new("file1")).unwrap();
+        let update2 = fileset2.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+        fileset1.integrate_remote(create2).ok().unwrap();
+        fileset1.integrate_remote(update2).ok().unwrap();
+        fileset2.integrate_remote(create1).ok().unwrap();
+        assert!(base_path1.join("file1(site 2)").exists());
+        assert_eq!(fileset1.list_conflicts(), vec![ConflictRecord {
+            path: PathBuf::from("file1"),
+            kept: (1, 0),
+            kept_on_disk: PathBuf::from("file1"),
+            copy: (2, 0),
+            copy_on_disk: PathBuf::from("file1(site 2)")
+        }]);
+
+        match fileset1.merge_conflict_copies((1, 0), (1, 0)) {
+            Err(FileSetError::NotConflictCopies(keep, discard)) => assert_eq!((keep, discard), ((1, 0), (1, 0))),
+            result => panic!("Expected the merge to be refused, got {:?}", result.map(|operations| operations.len()))
+        }
+        assert!(fileset1.merge_conflict_copies((2, 0), (3, 0)).is_err());
+
+        for operation in fileset1.merge_conflict_copies((2, 0), (1, 0)).unwrap() {
+            fileset2.integrate_remote(operation).ok().unwrap();
+        }
+        for fileset in [&fileset1, &fileset2].iter() {
+            assert_eq!(fileset.get_all_files().keys().collect::<Vec<_>>(), vec![&(2, 0)]);
+            assert!(fileset.has_path(&PathBuf::from("file1")));
+            assert!(fileset.list_conflicts().is_empty());
+        }
+        assert_eq!(fs::read(base_path1.join("file1")).unwrap(), b"contents");
+        assert!(!base_path1.join("file1(site 2)").exists());
+        assert!(!base_path2.join("file1(site 1)").exists());
+
+        write_file(&base_path1, "file2", b"");
+        fileset1.process_create(Path::new("file2")).unwrap();
+        let other = *fileset1.get_all_files().iter().find(|&(_, file)| file.printed_filename == "file2").unwrap().0;
+        assert!(fileset1.merge_conflict_copies((2, 0), other).is_err());
+        assert_eq!(fileset1.get_all_files().len(), 2);
+    }
+
+    #[test]
+    fn conflicts_are_resolved_as_asked() {
+        let base_path1 = test_dir("resolve_conflict_1");
+        let base_path2 = test_dir("resolve_conflict_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        for name in ["both", "merged"].iter() {
+            write_file(&base_path1, name, b"");
+            write_file(&base_path2, name, b"");
+            let create1 = fileset1.process_create(Path::new(name)).unwrap();
+            fileset1.integrate_remote(fileset2.process_create(Path::new(name)).unwrap()).ok().unwrap();
+            fileset2.integrate_remote(create1).ok().unwrap();
+        }
+        write_file(&base_path1, "by hand", b"both sides");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("by hand")).unwrap()).ok().unwrap();
+        let conflicts = fileset1.list_conflicts();
+        assert_eq!(conflicts.len(), 2);
+
+        let mut operations = fileset1.resolve_conflict(conflicts[0].copy, Resolution::KeepBoth).unwrap();
+        assert!(fileset1.has_path(&PathBuf::from("both(site 2)")));
+        operations.extend(fileset1.resolve_conflict(conflicts[1].copy, Resolution::MergeInto((1, 2))).unwrap());
+        assert!(fileset1.list_conflicts().is_empty());
+        assert_eq!(fs::read(base_path1.join("merged")).unwrap(), b"both sides");
+        assert!(!base_path1.join("merged(site 2)").exists());
+        assert!(!base_path1.join("by hand").exists());
+        assert!(fileset1.resolve_conflict(conflicts[0].copy, Resolution::KeepLocal).is_err());
+
+        for operation in operations {
+            fileset2.integrate_remote(operation).ok().unwrap();
+        }
+        let mut names: Vec<_> = fileset2.get_all_files().values().map(|file| file.get_file_path().join("/")).collect();
+        names.sort();
+        assert_eq!(names, vec!["both", "both(site 2)", "merged"]);
+    }
+
+    #[test]
+    fn recently_modified_entries_are_scanned_first() {
+        let base_path = test_dir("scan_order");
+        let mut fileset = open_fileset(&base_path, 1);
+        write_file(&base_path, "a/file1", b"");
+        write_file(&base_path, "b/file2", b"");
+        write_file(&base_path, "c/file3", b"");
+        let now = SystemTime::now();
+        for &(name, age) in [("a", 200), ("b", 100), ("c", 300)].iter() {
+            fs::File::open(base_path.join(name)).unwrap().set_modified(now - Duration::from_secs(age)).unwrap();
+        }
+        fileset.reconcile_local();
+
+        // The order comes from when the folders had last changed as of the previous scan,
+        // and operations come out while the rest of the tree is still being scanned
+        write_file(&base_path, "a/new1", b"");
+        write_file(&base_path, "b/new2", b"");
+        write_file(&base_path, "c/new3", b"");
+        let mut created = Vec::new();
+        fileset.reconcile_local_with(|operation| {
+            if let FileSetOperation::Create(o) = operation {
+                if created.is_empty() {
+                    fs::remove_dir_all(base_path.join("c")).unwrap();
+                }
+                created.push(o.filename.join("/"));
+            }
+        });
+        assert_eq!(created, vec!["b/new2", "a/new1"]);
+        // A folder that went away part way through is skipped, and what was in it removed
+        assert!(fileset.has_path(&PathBuf::from("a/new1")));
+        assert!(!fileset.has_path(&PathBuf::from("c/file3")));
+    }
+
+    #[test]
+    fn concurrent_creates_merge() {
+        let base_path1 = test_dir("concurrent_creates_1");
+        let base_path2 = test_dir("concurrent_creates_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        fileset1.set_merge_concurrent_creates(true);
+        fileset2.set_merge_concurrent_creates(true);
+        write_file(&base_path1, "file1", b"");
+        write_file(&base_path2, "file1", b"");
+
+        let create1 = fileset1.process_create(Path::new("file1")).unwrap();
+        let create2 = fileset2.process_create(Path::new("file1")).unwrap();
+        let update2 = fileset2.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+        fileset1.integrate_remote(create2).ok().unwrap();
+        fileset1.integrate_remote(update2).ok().unwrap();
+        fileset2.integrate_remote(create1).ok().unwrap();
+        for fileset in [&fileset1, &fileset2].iter() {
+            assert_eq!(fileset.get_all_files().keys().collect::<Vec<_>>(), vec![&(1, 0)]);
+            assert!(fileset.has_path(&PathBuf::from("file1")));
+        }
+        assert_eq!(fs::read(base_path1.join("file1")).unwrap(), b"contents");
+        assert!(!base_path1.join("file1(site 2)").exists());
+        assert!(!base_path2.join("file1(site 1)").exists());
+    }
+
+    #[test]
+    fn empty_directories_replicate() {
+        let base_path1 = test_dir("directories_1");
+        let base_path2 = test_dir("directories_2");
+        let base_path3 = test_dir("directories_3");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        let mut fileset3 = open_fileset(&base_path3, 3);
+
+        fs::create_dir(base_path1.join("folder1")).unwrap();
+        let create = fileset1.process_create_directory(Path::new("folder1")).unwrap();
+        write_file(&base_path1, "folder1/file1", b"");
+        fileset1.process_create(Path::new("folder1/file1")).unwrap();
+        fileset1.process_remove(Path::new("folder1/file1"));
+        fs::remove_file(base_path1.join("folder1/file1")).unwrap();
+        assert!(fileset1.reconcile_local().is_empty());
+        assert!(fileset1.get_all_files()[&(1, 0)].is_directory());
+
+        fileset2.integrate_remote(create).ok().unwrap();
+        assert!(base_path2.join("folder1").is_dir());
+        fileset3.integrate_remote_file_list(fileset1.get_changes_since(None), TimestampMap::new());
+        assert!(base_path3.join("folder1").is_dir());
+
+        let remove = fileset1.process_remove(Path::new("folder1"));
+        fileset2.integrate_remote(remove).ok().unwrap();
+        assert!(!base_path2.join("folder1").exists());
+    }
+
+    #[test]
+    fn invalid_updates_are_quarantined() {
+        let base_path1 = test_dir("quarantine_1");
+        let base_path2 = test_dir("quarantine_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+        fileset2.integrate_remote(fileset1.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new())).ok().unwrap();
+
+        let info = SiteInfo {
+            display_name: "Carol".to_string(),
+            device_name: "Carol's phone".to_string(),
+            platform: "android".to_string(),
+            public_key: Vec::new(),
+            application_version: "2.3".to_string(),
+            crate_version: CRATE_VERSION.to_string()
+        };
+        fileset2.integrate_remote(fileset1.process_announce_site(1, info.clone())).ok().unwrap();
+
+        let corrupt = fileset1.process_update(Path::new("file1"), b"corrupted".to_vec(), TimestampMap::new());
+        // The site upgrading before the update arrives doesn't change who made it
+        let upgraded = SiteInfo { application_version: "2.4".to_string(), ..info };
+        fileset2.integrate_remote(fileset1.process_announce_site(1, upgraded)).ok().unwrap();
+        let error = fileset2.integrate_remote(corrupt).err().unwrap();
+        assert!(error.to_string().contains(&format!("running android 2.3 with crdt_fileset {}", CRATE_VERSION)));
+        assert_eq!(fs::read(base_path2.join("file1")).unwrap(), b"contents");
+        assert_eq!(fileset2.take_quarantined().len(), 1);
+        assert!(fileset2.take_quarantined().is_empty());
+    }
+
+    #[test]
+    fn folder_moves_replicate() {
+        let base_path1 = test_dir("folder_move_1");
+        let base_path2 = test_dir("folder_move_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        fs::create_dir_all(base_path1.join("folder1/subfolder1")).unwrap();
+        fileset2.integrate_remote(fileset1.process_create_directory(Path::new("folder1")).unwrap()).ok().unwrap();
+        write_file(&base_path1, "folder1/file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("folder1/file1")).unwrap()).ok().unwrap();
+        write_file(&base_path1, "folder1/subfolder1/file2", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("folder1/subfolder1/file2")).unwrap()).ok().unwrap();
+
+        fs::rename(base_path1.join("folder1"), base_path1.join("folder2")).unwrap();
+        let operation = fileset1.process_folder_move(Path::new("folder1"), Path::new("folder2")).unwrap();
+        let move_state = operation.state().cloned().unwrap();
+        if let FileSetOperation::MoveFolder(ref o) = operation {
+            assert_eq!(o.files.len(), 3);
+        } else {
+            panic!("Expected a folder move");
+        }
+        fileset2.integrate_remote(operation).ok().unwrap();
+        let id = *fileset1.get_all_files().iter().find(|&(_, file)| file.printed_filename == "file1").unwrap().0;
+        for fileset in [&fileset1, &fileset2].iter() {
+            assert!(fileset.has_path(&PathBuf::from("folder2")));
+            assert!(fileset.has_path(&PathBuf::from("folder2/subfolder1/file2")));
+            assert!(!fileset.has_path(&PathBuf::from("folder1/file1")));
+            // The move's site breaks later ties over the names it gave
+            assert_eq!(fileset.files[&id].filename_state(), Some(move_state));
+            let history = fileset.get_metadata_history(id);
+            assert_eq!(history.last().unwrap().state, move_state);
+            assert_eq!(history.last().unwrap().value, MetadataValue::Filename(vec!["folder2".to_string(), "file1".to_string()]));
+        }
+        assert!(base_path2.join("folder2/file1").is_file());
+        assert!(base_path2.join("folder2/subfolder1/file2").is_file());
+        assert!(!base_path2.join("folder1").exists());
+    }
+
+    #[test]
+    fn file_list_integration_reports_changes() {
+        let base_path1 = test_dir("report_1");
+        let base_path2 = test_dir("report_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        write_file(&base_path1, "file1", b"contents");
+        let create = fileset1.process_create(Path::new("file1")).unwrap();
+        fileset1.process_update(Path::new("file1"), b"contents".to_vec(), TimestampMap::new());
+        write_file(&base_path1, "file2", b"");
+        fileset1.process_create(Path::new("file2")).unwrap();
+        write_file(&base_path2, "file3", b"");
+        fileset2.process_create(Path::new("file3")).unwrap();
+        fileset2.integrate_remote(create).ok().unwrap();
+        fileset2.exclude_locally((1, 0)).unwrap();
+
+        let (_, report) = fileset2.integrate_remote_file_list_with_report(fileset1.get_changes_since(None), TimestampMap::new());
+        assert_eq!(report.created, vec![PathBuf::from("file2")]);
+        assert_eq!(report.deleted, vec![PathBuf::from("file3")]);
+        assert_eq!(report.skipped, vec![(PathBuf::from("file1"), "excluded locally".to_string())]);
+        assert!(report.updated.is_empty());
+        assert!(report.is_clean());
+        assert_eq!(fs::read(base_path2.join("file2")).unwrap(), b"");
+    }
+
+    #[test]
+    fn files_are_listed_in_byte_order() {
+        let base_path = test_dir("list_files");
+        let mut fileset = open_fileset(&base_path, 1);
+        fs::create_dir(base_path.join("a")).unwrap();
+        fileset.process_create_directory(Path::new("a")).unwrap();
+        for name in ["b", "\u{e9}", "a b", "B", "a/c"].iter() {
+            write_file(&base_path, name, b"");
+            fileset.process_create(Path::new(name)).unwrap();
+        }
+        let listed: Vec<_> = fileset.list_files(0, 10).into_iter().map(|(_, path)| path).collect();
+        let expected: Vec<_> = ["B", "a", "a/c", "a b", "b", "\u{e9}"].iter().map(PathBuf::from).collect();
+        assert_eq!(listed, expected);
+        assert_eq!(fileset.list_files(2, 2).into_iter().map(|(_, path)| path).collect::<Vec<_>>(), &expected[2..4]);
+        assert!(fileset.list_files(6, 2).is_empty());
+    }
+
+    #[test]
+    fn crossed_folder_moves_converge() {
+        let base_path1 = test_dir("crossed_moves_1");
+        let base_path2 = test_dir("crossed_moves_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        fs::create_dir(base_path1.join("a")).unwrap();
+        fileset2.integrate_remote(fileset1.process_create_directory(Path::new("a")).unwrap()).ok().unwrap();
+        write_file(&base_path1, "a/x", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("a/x")).unwrap()).ok().unwrap();
+        fs::create_dir(base_path2.join("b")).unwrap();
+        fileset1.integrate_remote(fileset2.process_create_directory(Path::new("b")).unwrap()).ok().unwrap();
+        write_file(&base_path2, "b/y", b"");
+        fileset1.integrate_remote(fileset2.process_create(Path::new("b/y")).unwrap()).ok().unwrap();
+
+        // Each site moves its folder into the other's at the same time
+        fs::rename(base_path1.join("a"), base_path1.join("b/a")).unwrap();
+        let move1 = fileset1.process_folder_move(Path::new("a"), Path::new("b/a")).unwrap();
+        fs::create_dir(base_path2.join("a/b")).unwrap();
+        fs::rename(base_path2.join("b/y"), base_path2.join("a/b/y")).unwrap();
+        fs::remove_dir(base_path2.join("b")).unwrap();
+        let move2 = fileset2.process_folder_move(Path::new("b"), Path::new("a/b")).unwrap();
+        fileset1.integrate_remote(move2).ok().unwrap();
+        fileset2.integrate_remote(move1).ok().unwrap();
+
+        for fileset in [&fileset1, &fileset2].iter() {
+            assert!(fileset.has_path(&PathBuf::from("b/a/x")));
+            assert!(fileset.has_path(&PathBuf::from("b/y")));
+            assert!(!fileset.has_path(&PathBuf::from("a/b/y")));
+        }
+        assert!(base_path2.join("b/a/x").is_file());
+        assert!(base_path2.join("b/y").is_file());
+        assert!(!base_path2.join("a").exists());
+    }
+
+    #[test]
+    fn materialized_names_match_what_was_integrated() {
+        let base_path1 = test_dir("materialize_integrated_1");
+        let base_path2 = test_dir("materialize_integrated_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        for fileset in [&mut fileset1, &mut fileset2].iter_mut() {
+            fileset.set_conflict_policy(ConflictPolicy::AddWins);
+            fileset.set_resurrection_folder(Some("Conflicted".to_string()));
+        }
+        fs::create_dir(base_path1.join("a")).unwrap();
+        fileset2.integrate_remote(fileset1.process_create_directory(Path::new("a")).unwrap()).ok().unwrap();
+        write_file(&base_path1, "a/x", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("a/x")).unwrap()).ok().unwrap();
+        fs::create_dir(base_path2.join("b")).unwrap();
+        fileset1.integrate_remote(fileset2.process_create_directory(Path::new("b")).unwrap()).ok().unwrap();
+        write_file(&base_path2, "b/y", b"");
+        fileset1.integrate_remote(fileset2.process_create(Path::new("b/y")).unwrap()).ok().unwrap();
+        write_file(&base_path1, "c", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("c")).unwrap()).ok().unwrap();
+
+        // The second site's move is undone and skipped on the site that gets it
+        // first, and the remove loses to the update it hadn't seen
+        fs::rename(base_path1.join("a"), base_path1.join("b/a")).unwrap();
+        let move1 = fileset1.process_folder_move(Path::new("a"), Path::new("b/a")).unwrap();
+        let remove = fileset1.process_remove(Path::new("c"));
+        fs::create_dir(base_path2.join("a/b")).unwrap();
+        fs::rename(base_path2.join("b/y"), base_path2.join("a/b/y")).unwrap();
+        fs::remove_dir(base_path2.join("b")).unwrap();
+        let move2 = fileset2.process_folder_move(Path::new("b"), Path::new("a/b")).unwrap();
+        let update = fileset2.process_update(Path::new("c"), b"contents".to_vec(), TimestampMap::new());
+        fileset1.integrate_remote(move2).ok().unwrap();
+        fileset1.integrate_remote(update).ok().unwrap();
+        fileset2.integrate_remote(move1).ok().unwrap();
+        fileset2.integrate_remote(remove).ok().unwrap();
+
+        for fileset in [&fileset1, &fileset2].iter() {
+            assert!(fileset.has_path(&PathBuf::from("b/a/x")));
+            assert!(fileset.has_path(&PathBuf::from("Conflicted/c")));
+            let live: HashMap<_, _> = fileset.get_all_files().iter().map(|(&id, file)| (id, file.get_file_path().clone())).collect();
+            let materialized: HashMap<_, _> = fileset.materialize_at(&fileset.get_version_vector()).unwrap().into_iter().map(|(id, file)| (id, file.get_file_path().clone())).collect();
+            assert_eq!(materialized, live);
+        }
+    }
+
+    #[test]
+    fn reconciliation_plans_can_be_paused() {
+        let base_path1 = test_dir("plan_1");
+        let base_path2 = test_dir("plan_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        write_file(&base_path1, "folder1/file1", b"");
+        fileset1.process_create(Path::new("folder1/file1")).unwrap();
+        write_file(&base_path1, "folder2/file2", b"contents");
+        fileset1.process_create(Path::new("folder2/file2")).unwrap();
+        fileset1.process_update(Path::new("folder2/file2"), b"contents".to_vec(), TimestampMap::new());
+
+        let mut plan = fileset2.plan_reconciliation(fileset1.get_changes_since(None), TimestampMap::new());
+        assert_eq!(plan.get_total(), 2);
+        plan.exclude_folder("folder1");
+        assert!(fileset2.execute_reconciliation(&mut plan, |done, total| done < total - 1).is_none());
+        assert_eq!(plan.get_remaining(), 1);
+        let (operations, report) = fileset2.execute_reconciliation(&mut plan, |_, _| true).unwrap();
+        assert!(operations.is_empty());
+        assert_eq!(report.created.len(), 1);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(fileset2.is_excluded_locally((1, 0)));
+        assert!(!base_path2.join("folder1/file1").exists());
+        assert_eq!(fs::read(base_path2.join("folder2/file2")).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn path_limits_apply_everywhere() {
+        let base_path1 = test_dir("path_limits_1");
+        let base_path2 = test_dir("path_limits_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        let limits = PathLimits {
+            max_depth: 2,
+            max_name_length: 8,
+            max_length: 16
+        };
+        fileset2.set_path_limits(limits);
+        write_file(&base_path2, "folder1/folder2/file1", b"");
+        match fileset2.process_create(Path::new("folder1/folder2/file1")) {
+            Err(FileSetError::PathLimitExceeded(_)) => {},
+            _ => panic!("Expected the path to be refused")
+        }
+        assert!(fileset2.reconcile_local().is_empty());
+
+        // Within the limits, a create from a site that shares them applies
+        fileset1.set_path_limits(limits);
+        write_file(&base_path1, "folder1/file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("folder1/file1")).unwrap()).ok().unwrap();
+        assert!(fileset2.has_path(&PathBuf::from("folder1/file1")));
+    }
+
+    #[test]
+    fn sites_with_different_path_limits_refuse_each_other() {
+        let base_path1 = test_dir("different_path_limits_1");
+        let base_path2 = test_dir("different_path_limits_2");
+        let mut fileset1 = open_fileset(&base_path1, 1);
+        let mut fileset2 = open_fileset(&base_path2, 2);
+        let limits = PathLimits {
+            max_depth: 2,
+            max_name_length: 8,
+            max_length: 16
+        };
+        fileset2.set_path_limits(limits);
+        let info = |name: &str| SiteInfo {
+            display_name: name.to_string(),
+            device_name: name.to_string(),
+            platform: "linux".to_string(),
+            public_key: Vec::new(),
+            application_version: "1.0".to_string(),
+            crate_version: CRATE_VERSION.to_string()
+        };
+        let refused = |result: Result<(), FileSetError>| match result {
+            Err(FileSetError::InOperation(_, ref e)) => match **e {
+                FileSetError::PathLimitsDiffer(_) => true,
+                _ => false
+            },
+            _ => false
+        };
+
+        // Announcements are the handshake, and each site refuses the other's
+        let announcement1 = fileset1.process_announce_site(1, info("site1"));
+        let announcement2 = fileset2.process_announce_site(2, info("site2"));
+        assert!(refused(fileset2.integrate_remote(announcement1)));
+        assert!(refused(fileset1.integrate_remote(announcement2)));
+
+        // So a create one site allows and the other doesn't is applied at neither
+        write_file(&base_path1, "folder1/a_long_name", b"");
+        let create = fileset1.process_create(Path::new("folder1/a_long_name")).unwrap();
+        assert!(refused(fileset2.integrate_remote(create)));
+        assert!(!fileset2.has_path(&PathBuf::from("folder1/a_long_name")));
+        assert!(fileset2.take_quarantined().is_empty());
+        write_file(&base_path2, "file2", b"");
+        let create = fileset2.process_create(Path::new("file2")).unwrap();
+        assert!(refused(fileset1.integrate_remote(create)));
+        assert!(!fileset1.has_path(&PathBuf::from("file2")));
+
+        // Until the site announces the same limits
+        fs::remove_dir_all(base_path1.join("folder1")).unwrap();
+        fileset1.reconcile_local();
+        fileset1.set_path_limits(limits);
+        fileset2.integrate_remote(fileset1.process_announce_site(1, info("site1"))).ok().unwrap();
+        write_file(&base_path1, "file1", b"");
+        fileset2.integrate_remote(fileset1.process_create(Path::new("file1")).unwrap()).ok().unwrap();
+        assert!(fileset2.has_path(&PathBuf::from("file1")));
     }
 }
@@ -0,0 +1,61 @@
+use std::usize;
+
+// Limits on the paths in a fileset. Lengths are counted in bytes of the names
+// joined by single separators, so that every platform measures a path the same
+// way. Every site sharing a fileset has to use the same limits, or a path one
+// site accepts could be refused by another, so sites announce their limits and
+// refuse operations from a site that announced different ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathLimits {
+    pub max_depth: usize,
+    pub max_name_length: usize,
+    pub max_length: usize
+}
+
+impl PathLimits {
+    #[inline]
+    pub fn unlimited() -> PathLimits {
+        PathLimits {
+            max_depth: usize::MAX,
+            max_name_length: usize::MAX,
+            max_length: usize::MAX
+        }
+    }
+
+    pub fn allows(&self, filename: &[String]) -> bool {
+        let length = filename.iter().map(|name| name.len()).sum::<usize>() + filename.len().saturating_sub(1);
+        filename.len() <= self.max_depth &&
+            filename.iter().all(|name| name.len() <= self.max_name_length) &&
+            length <= self.max_length
+    }
+}
+
+impl Default for PathLimits {
+    fn default() -> PathLimits {
+        PathLimits::unlimited()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PathLimits;
+
+    fn path(path: &str) -> Vec<String> {
+        path.split('/').map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn limits_are_checked() {
+        let limits = PathLimits {
+            max_depth: 3,
+            max_name_length: 5,
+            max_length: 11
+        };
+        assert!(limits.allows(&path("ab/cd/efghi")));
+        assert!(!limits.allows(&path("ab/cd/efghij")));
+        assert!(!limits.allows(&path("a/b/c/d")));
+        assert!(limits.allows(&path("abcde/fghij")));
+        assert!(!limits.allows(&path("abcde/fghij/k")));
+        assert!(PathLimits::unlimited().allows(&path("a/b/c/d")));
+    }
+}
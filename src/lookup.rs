@@ -1,10 +1,48 @@
 use std::collections::hash_map::{HashMap};
+use std::cmp;
 use std::ffi::{OsString, OsStr};
 
-use super::FileID;
+use super::{FileID, SiteId};
+
+// Most filesystems refuse names longer than this many bytes
+pub const MAX_NAME_LENGTH: usize = 255;
+// Anything longer after the last dot is taken to be part of the name, not an extension
+const MAX_EXTENSION_LENGTH: usize = 16;
+
+// Whether a name has the suffix given to an entry created at the same path as another
+pub fn is_conflict_name(name: &str) -> bool {
+    name.ends_with(')') && name.rfind("(site ").map_or(false, |start| {
+        let digits = &name[start + "(site ".len()..name.len() - 1];
+        !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+    })
+}
+
+// The name given to an entry created at the same path as another. If the suffix
+// would make it too long, the end of the stem gives way to a hash of the whole
+// name, so that the extension is kept and names that began the same stay apart.
+pub fn conflict_name(name: &str, suffix: &str, max_name_length: usize) -> String {
+    if name.len() + suffix.len() <= max_name_length {
+        return format!("{}{}", name, suffix)
+    }
+    let stem_length = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= MAX_EXTENSION_LENGTH => dot,
+        _ => name.len()
+    };
+    // FNV-1a, which gives the same hash on every platform and version
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3));
+    let tail = format!("~{:016x}{}{}", hash, &name[stem_length..], suffix);
+    // Only ever cut between characters
+    let mut keep = cmp::min(stem_length, max_name_length.saturating_sub(tail.len()));
+    while !name.is_char_boundary(keep) {
+        keep -= 1;
+    }
+    format!("{}{}", &name[..keep], tail)
+}
 
 pub struct IDLookup {
-    head: LookupNode
+    head: LookupNode,
+    // The longest conflict name given out, which the fileset's path limits can lower
+    max_name_length: usize
 }
 
 struct LookupNode {
@@ -16,23 +54,31 @@ impl IDLookup {
     #[inline]
     pub fn new() -> IDLookup {
         IDLookup {
-            head: LookupNode::new()
+            head: LookupNode::new(),
+            max_name_length: MAX_NAME_LENGTH
         }
     }
 
-    pub fn add_file<'a, I: 'a + IntoIterator<Item=&'a OsStr>>(&mut self, path: I, id: FileID, site_id: u32) -> String {
-        let result = IDLookup::add_file_component(&mut path.into_iter(), id, &mut self.head, site_id);
+    pub fn set_max_name_length(&mut self, max_name_length: usize) {
+        self.max_name_length = cmp::min(max_name_length, MAX_NAME_LENGTH);
+    }
+
+    pub fn add_file<'a, I: 'a + IntoIterator<Item=&'a OsStr>>(&mut self, path: I, id: FileID, site_id: SiteId) -> String {
+        let result = IDLookup::add_file_component(&mut path.into_iter(), id, &mut self.head, site_id, self.max_name_length);
         println!("{:?}", result);
         result.1.unwrap()
     }
 
-    fn add_file_component<'a, I: 'a + Iterator<Item=&'a OsStr>>(path: &mut I, id: FileID, node: &mut LookupNode, site_id: u32) -> (bool, Option<String>) {
+    fn add_file_component<'a, I: 'a + Iterator<Item=&'a OsStr>>(path: &mut I, id: FileID, node: &mut LookupNode, site_id: SiteId, max_name_length: usize) -> (bool, Option<String>) {
         if let Some(component) = path.next() {
-            let mut filename = component.to_os_string().into_string().unwrap();
-            let (mut try_again, mut result) = IDLookup::add_file_component(path, id, node.children.entry(component.to_os_string()).or_insert_with(LookupNode::new), site_id);
+            let name = component.to_os_string().into_string().unwrap();
+            let mut filename = name.clone();
+            let mut suffix = String::new();
+            let (mut try_again, mut result) = IDLookup::add_file_component(path, id, node.children.entry(component.to_os_string()).or_insert_with(LookupNode::new), site_id, max_name_length);
             while try_again {
-                filename.push_str(&format!("(site {})", site_id));
-                let lookup_result = IDLookup::add_file_component(&mut Some(OsStr::new(&filename.clone())).into_iter(), id, node.children.entry(OsString::from(filename.clone())).or_insert_with(LookupNode::new), site_id);
+                suffix.push_str(&format!("(site {})", site_id));
+                filename = conflict_name(&name, &suffix, max_name_length);
+                let lookup_result = IDLookup::add_file_component(&mut None.into_iter(), id, node.children.entry(OsString::from(filename.clone())).or_insert_with(LookupNode::new), site_id, max_name_length);
                 try_again = lookup_result.0;
                 result = lookup_result.1;
             }
@@ -70,6 +116,36 @@ impl IDLookup {
         }
     }
 
+    pub fn get_child_ids<'a, I: 'a +IntoIterator<Item=&'a OsStr>>(&self, path: I) -> Vec<FileID> {
+        let mut node = &self.head;
+        for component in path {
+            match node.children.get(component) {
+                Some(child) => node = child,
+                None => return Vec::new()
+            }
+        }
+        node.children.values().filter_map(|child| child.id).collect()
+    }
+
+    pub fn get_folder_ids<'a, I: 'a +IntoIterator<Item=&'a OsStr>>(&self, path: I) -> Vec<FileID> {
+        let mut node = &self.head;
+        for component in path {
+            match node.children.get(component) {
+                Some(child) => node = child,
+                None => return Vec::new()
+            }
+        }
+        let mut ids = Vec::new();
+        IDLookup::collect_ids(node, &mut ids);
+        ids
+    }
+
+    pub fn get_all_ids(&self) -> Vec<FileID> {
+        let mut ids = Vec::new();
+        IDLookup::collect_ids(&self.head, &mut ids);
+        ids
+    }
+
     pub fn remove_file<'a, I: 'a +IntoIterator<Item=&'a OsStr>>(&mut self, path: I) -> Option<FileID> {
         IDLookup::remove_file_component(path.into_iter(), &mut self.head).1
     }
@@ -84,7 +160,8 @@ impl IDLookup {
             if should_remove {
                 node.children.remove(component);
             }
-            (node.children.is_empty(), result)
+            // A directory with an id of its own outlives its last child
+            (node.children.is_empty() && node.id.is_none(), result)
         } else {
             if node.id.is_none() {
                 (false, None)
@@ -111,7 +188,7 @@ impl IDLookup {
             if should_remove {
                 node.children.remove(component);
             }
-            (node.children.is_empty(), result)
+            (node.children.is_empty() && node.id.is_none(), result)
         } else {
             let mut removed_ids = Vec::new();
             IDLookup::collect_ids(node, &mut removed_ids);
@@ -146,7 +223,7 @@ impl LookupNode {
 
 #[cfg(test)]
 mod test {
-    use super::IDLookup;
+    use super::{IDLookup, conflict_name, is_conflict_name, MAX_NAME_LENGTH};
     use std::ffi::{OsStr};
 
 
@@ -176,6 +253,9 @@ macro_rules! vec_str {
         assert_eq!(lookup.add_file(vec_str!["folder1", "subfolder1", "file1"], (1, 14), 1), "file1(site 1)".to_string());
         assert_eq!(lookup.add_file(vec_str!["folder1", "subfolder1", "file1"], (1, 15), 1), "file1(site 1)(site 1)".to_string());
         assert_eq!(lookup.add_file(vec_str!["folder1", "subfolder1", "file1"], (2, 16), 2), "file1(site 2)".to_string());
+        assert_eq!(lookup.get_id_for(vec_str!["folder1", "subfolder1", "file1(site 1)"]), Some((1, 14)));
+        assert_eq!(lookup.get_id_for(vec_str!["folder1", "subfolder1", "file1(site 1)(site 1)"]), Some((1, 15)));
+        assert_eq!(lookup.get_id_for(vec_str!["folder1", "subfolder1", "file1(site 2)"]), Some((2, 16)));
     }
 
     #[test]
@@ -196,7 +276,10 @@ macro_rules! vec_str {
         assert_eq!(lookup.get_id_for(vec_str!["folder1", "subfolder1", "file1"]), Some((1, 16)));
         assert_eq!(lookup.remove_file(vec_str!["folder1", "subfolder1", "file1"]), Some((1, 16)));
 
-
+        lookup.add_file(vec_str!["folder2"], (1, 17), 1);
+        assert_eq!(lookup.remove_file(vec_str!["folder2", "file5"]), Some((1, 9)));
+        assert_eq!(lookup.remove_file(vec_str!["folder2", "subfolder1", "file4"]), Some((1, 10)));
+        assert_eq!(lookup.get_id_for(vec_str!["folder2"]), Some((1, 17)));
     }
 
     #[test]
@@ -233,4 +316,30 @@ macro_rules! vec_str {
         assert_eq!(lookup.add_file(vec_str!["folder1", "subfolder1", "file1"], (1, 15), 1), "file1(site 1)".to_string());
         assert_eq!(lookup.add_file(vec_str!["folder1", "subfolder1", "file1"], (2, 16), 2), "file1(site 2)".to_string());
     }
+
+    #[test]
+    fn long_conflict_names_are_truncated() {
+        let long_name = format!("{}.txt", "é".repeat(130));
+        let truncated = conflict_name(&long_name, "(site 12)", MAX_NAME_LENGTH);
+        assert!(truncated.len() <= MAX_NAME_LENGTH);
+        assert!(truncated.ends_with(".txt(site 12)"));
+        assert!(truncated.starts_with("éé"));
+        assert!(is_conflict_name(&truncated));
+        let other_name = format!("{}x.txt", "é".repeat(130));
+        assert!(conflict_name(&other_name, "(site 12)", MAX_NAME_LENGTH) != truncated);
+        assert_eq!(conflict_name("file1.txt", "(site 12)", MAX_NAME_LENGTH), "file1.txt(site 12)");
+
+        let mut lookup = IDLookup::new();
+        lookup.add_file(vec_str![&long_name], (1, 1), 1);
+        let printed = lookup.add_file(vec_str![&long_name], (2, 1), 2);
+        assert_eq!(printed, conflict_name(&long_name, "(site 2)", MAX_NAME_LENGTH));
+        assert_eq!(lookup.get_id_for(vec_str![&printed]), Some((2, 1)));
+
+        // Path limits can make names shorter still
+        lookup.set_max_name_length(40);
+        lookup.add_file(vec_str!["a_name_of_thirty_six_characters.txt"], (3, 1), 3);
+        let printed = lookup.add_file(vec_str!["a_name_of_thirty_six_characters.txt"], (4, 1), 4);
+        assert!(printed.len() <= 40);
+        assert!(printed.ends_with(".txt(site 4)"));
+    }
 }
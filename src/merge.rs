@@ -0,0 +1,250 @@
+use std::collections::hash_map::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use byteorder::{NetworkEndian, ByteOrder};
+
+use super::{FileSet, FileUpdater, FileMetadata, FileSetError, FileID, SiteId, State, Producer, VersionVector, RemoveOperation, ConflictPolicy, RenamePolicy, integrate_attribute, filename_superseded};
+use serialization::{write_id, read_id, compress_metadata, expand_metadata, preallocation};
+use attributes;
+
+// Everything about a fileset that can be joined with another site's without
+// replaying its operations: each file's name, attributes and last update, and
+// each tombstone with what the removing site had seen. File contents are up to
+// the updater, and still arrive as updates.
+#[derive(Debug, Clone)]
+pub struct SerializedFileSet {
+    files: HashMap<FileID, FileMetadata>,
+    last_updates: HashMap<FileID, State>,
+    removed: HashMap<FileID, (State, VersionVector, FileMetadata)>,
+    // States of adopted trees, which the merging site counts as applied
+    adopted: Vec<State>
+}
+
+impl SerializedFileSet {
+    pub fn get_files(&self) -> &HashMap<FileID, FileMetadata> {
+        &self.files
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        NetworkEndian::write_u32(&mut int_buf, self.files.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (&id, file) in self.files.iter() {
+            try!(write_id(writer, id));
+            try!(compress_metadata(writer, &mut int_buf, file));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.last_updates.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (&id, state) in self.last_updates.iter() {
+            try!(write_id(writer, id));
+            try!(write_id(writer, (state.site_id, state.time_stamp)));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.removed.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (&id, &(ref state, ref seen, ref file)) in self.removed.iter() {
+            try!(write_id(writer, id));
+            try!(write_id(writer, (state.site_id, state.time_stamp)));
+            try!(seen.compress_to(writer));
+            try!(compress_metadata(writer, &mut int_buf, file));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.adopted.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for state in self.adopted.iter() {
+            try!(write_id(writer, (state.site_id, state.time_stamp)));
+        }
+        Ok(())
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<SerializedFileSet> {
+        let mut int_buf = [0;4];
+        try!(reader.read_exact(&mut int_buf));
+        let file_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut files = HashMap::with_capacity(preallocation(file_count));
+        for _ in 0..file_count {
+            let id = try!(read_id(reader));
+            files.insert(id, try!(expand_metadata(reader, &mut int_buf)));
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let last_update_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut last_updates = HashMap::with_capacity(preallocation(last_update_count));
+        for _ in 0..last_update_count {
+            let id = try!(read_id(reader));
+            let (site_id, time_stamp) = try!(read_id(reader));
+            last_updates.insert(id, State {
+                site_id: site_id,
+                time_stamp: time_stamp
+            });
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let removed_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut removed = HashMap::with_capacity(preallocation(removed_count));
+        for _ in 0..removed_count {
+            let id = try!(read_id(reader));
+            let (site_id, time_stamp) = try!(read_id(reader));
+            let state = State {
+                site_id: site_id,
+                time_stamp: time_stamp
+            };
+            let seen = try!(VersionVector::expand_from(reader));
+            removed.insert(id, (state, seen, try!(expand_metadata(reader, &mut int_buf))));
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let adopted_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut adopted = Vec::with_capacity(preallocation(adopted_count));
+        for _ in 0..adopted_count {
+            let (site_id, time_stamp) = try!(read_id(reader));
+            adopted.push(State {
+                site_id: site_id,
+                time_stamp: time_stamp
+            });
+        }
+        Ok(SerializedFileSet {
+            files: files,
+            last_updates: last_updates,
+            removed: removed,
+            adopted: adopted
+        })
+    }
+}
+
+impl<FU: FileUpdater> FileSet<FU> {
+    pub fn get_serialized_state(&self) -> SerializedFileSet {
+        self.serialized_state_shared_with(None)
+    }
+
+    // Leaves out the files, and the removals of files, the peer's outbound filter
+    // doesn't let it have
+    pub fn get_serialized_state_for_peer(&self, peer: SiteId) -> SerializedFileSet {
+        self.serialized_state_shared_with(Some(peer))
+    }
+
+    fn serialized_state_shared_with(&self, peer: Option<SiteId>) -> SerializedFileSet {
+        let files: HashMap<FileID, FileMetadata> = self.files.iter().filter(|&(_, file)| {
+            self.is_metadata_shared_with(peer, file)
+        }).map(|(&id, file)| (id, file.clone())).collect();
+        SerializedFileSet {
+            last_updates: self.last_updates.iter().filter(|&(id, _)| {
+                files.contains_key(id) || self.removed.get(id).map_or(false, |file| self.is_metadata_shared_with(peer, file))
+            }).map(|(&id, &state)| (id, state)).collect(),
+            files: files,
+            removed: self.removed_at.iter().filter_map(|(&id, &state)| {
+                let seen = self.removed_seen.get(&id).cloned().unwrap_or_else(VersionVector::new);
+                self.removed.get(&id).filter(|file| self.is_metadata_shared_with(peer, file)).map(|file| (id, (state, seen, file.clone())))
+            }).collect(),
+            adopted: self.adopted.clone()
+        }
+    }
+
+    // Joins another site's state into this one, so that two sites that have been
+    // apart a long time can catch up without sending every operation. Removals,
+    // names and attributes are settled by the same policies and tie breaker as
+    // the operations that made them, so merging and replaying agree.
+    pub fn merge(&mut self, other_state: SerializedFileSet) -> Result<(), FileSetError> {
+        trace!("Merging the state of {} files and {} tombstones", other_state.files.len(), other_state.removed.len());
+        for state in other_state.adopted {
+            if state.site_id != self.site_id && !self.adopted.contains(&state) {
+                self.applied.insert(&state);
+                self.adopted.push(state);
+            }
+        }
+        for (id, (state, seen, file)) in other_state.removed {
+            if self.removed.contains_key(&id) {
+                continue
+            }
+            self.generation += 1;
+            self.record_change(id, false);
+            if !self.files.contains_key(&id) {
+                // Never seen here, but the tombstone still has to be passed on
+                self.removed.insert(id, file);
+                self.removed_at.insert(id, state);
+                self.removed_seen.insert(id, seen);
+                continue
+            }
+            try!(self.integrate_remove(RemoveOperation {
+                state: state,
+                producer: Producer::default(),
+                id: id,
+                last_update: None,
+                seen: seen
+            }));
+        }
+
+        // Folders have to be there before anything goes in them
+        let mut files: Vec<_> = other_state.files.into_iter().collect();
+        files.sort_by_key(|&(_, ref file)| file.filename.1.len());
+        let mut renames = Vec::new();
+        for (id, file) in files {
+            let resurrected = self.removed.contains_key(&id);
+            if resurrected {
+                // Brought back by an update or a rename the removing site hadn't seen,
+                // under the name the policy gives it rather than the merged one
+                let concurrent_update = other_state.last_updates.get(&id).map_or(false, |update| !self.removal_saw(id, update));
+                let concurrent_rename = file.filename_state().map_or(false, |rename| !self.removal_saw(id, &rename));
+                if self.conflict_policy == ConflictPolicy::AddWins && concurrent_update {
+                    try!(self.resurrect(id));
+                } else if self.rename_policy != RenamePolicy::RemoveWins && concurrent_rename {
+                    let rename = file.filename_state().unwrap();
+                    try!(self.resurrect_renamed(id, file.filename.1.clone(), &rename, file.filename_time));
+                } else {
+                    continue
+                }
+            }
+            let mut conflicts = Vec::new();
+            let mut lock_changed = false;
+            let changed = match self.files.get_mut(&id) {
+                Some(file_metadata) => {
+                    let superseded = resurrected || filename_superseded(&*self.tie_breaker, file_metadata, file.filename.0, file.filename_site, file.filename_time, &file.filename.1);
+                    if !superseded && file.filename.1 != file_metadata.filename.1 {
+                        renames.push((id, file.entry_name()));
+                    } else if !superseded {
+                        file_metadata.set_entry_name(file.entry_name());
+                    }
+                    let mut changed = false;
+                    for (key, (time_stamp, value)) in file.attributes {
+                        let site_id = file.attribute_sites.get(&key).cloned();
+                        let hybrid_time = file.attribute_times.get(&key).cloned().unwrap_or_default();
+                        let unchanged = file_metadata.attributes.get(&key).map_or(false, |&(_, ref current)| *current == value);
+                        if integrate_attribute(file_metadata, id, key.clone(), value, time_stamp, site_id, hybrid_time, &*self.tie_breaker, &mut conflicts) && !unchanged {
+                            lock_changed = lock_changed || key == attributes::EDIT_LOCK;
+                            changed = true;
+                        }
+                    }
+                    changed
+                },
+                None => {
+                    trace!("Adding {:?} from the merged state", id);
+                    let mut file = file;
+                    file.printed_filename = self.id_lookup.add_file(file.filename.1.iter().map(OsStr::new), id, id.0);
+                    let intent = file.create_intent();
+                    self.files.insert(id, file);
+                    try!(self.apply_intent(intent).map_err(|e| FileSetError::IOError(e)));
+                    true
+                }
+            };
+            for conflict in conflicts {
+                self.report_conflict(conflict);
+            }
+            if lock_changed {
+                self.notify_edit_lock(id);
+            }
+            if changed {
+                self.generation += 1;
+                self.record_change(id, false);
+            }
+        }
+        for (id, update) in other_state.last_updates {
+            self.last_updates.entry(id).or_insert(update);
+        }
+        if !renames.is_empty() {
+            for &(id, _) in renames.iter() {
+                self.generation += 1;
+                self.record_change(id, false);
+            }
+            let mut previous_paths = HashMap::new();
+            self.rename_entries(renames, &mut previous_paths);
+            try!(self.move_entries_on_disk(previous_paths, None));
+        }
+        self.save().unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::btree_map::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use sha2::{Sha256, Digest};
+
+use super::{FileSet, FileUpdater, FileID, SiteId};
+use digest::file_digest;
+use serialization::{write_id, write_str};
+
+// One folder of the tree, holding the digests of the files directly inside it and
+// the subtrees of the folders inside it. Files are placed by the name every site
+// agrees on, not the one conflicts are printed under here.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MerkleNode {
+    hash: [u8; 32],
+    files: BTreeMap<FileID, [u8; 32]>,
+    folders: BTreeMap<String, MerkleNode>
+}
+
+impl MerkleNode {
+    #[inline]
+    pub fn get_hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    pub fn get_files(&self) -> &BTreeMap<FileID, [u8; 32]> {
+        &self.files
+    }
+
+    pub fn get_folder_hashes(&self) -> BTreeMap<String, [u8; 32]> {
+        self.folders.iter().map(|(name, node)| (name.clone(), node.hash)).collect()
+    }
+
+    pub fn get_node<P: AsRef<Path>>(&self, path: P) -> Option<&MerkleNode> {
+        let mut node = self;
+        for component in path.as_ref().iter() {
+            node = match component.to_str().and_then(|name| node.folders.get(name)) {
+                Some(child) => child,
+                None => return None
+            };
+        }
+        Some(node)
+    }
+
+    // The files that differ between the two trees, looking only inside folders
+    // whose hashes don't match
+    pub fn diverging_files(&self, other: &MerkleNode) -> Vec<FileID> {
+        let mut diverging = Vec::new();
+        self.collect_diverging(other, &mut diverging);
+        // A file moved between folders turns up in both
+        diverging.sort();
+        diverging.dedup();
+        diverging
+    }
+
+    fn collect_diverging(&self, other: &MerkleNode, diverging: &mut Vec<FileID>) {
+        if self.hash == other.hash {
+            return
+        }
+        for (id, hash) in self.files.iter() {
+            if other.files.get(id) != Some(hash) {
+                diverging.push(*id);
+            }
+        }
+        diverging.extend(other.files.keys().filter(|id| !self.files.contains_key(id)));
+        for (name, node) in self.folders.iter() {
+            match other.folders.get(name) {
+                Some(other_node) => node.collect_diverging(other_node, diverging),
+                None => node.collect_all(diverging)
+            }
+        }
+        for (name, other_node) in other.folders.iter() {
+            if !self.folders.contains_key(name) {
+                other_node.collect_all(diverging);
+            }
+        }
+    }
+
+    fn collect_all(&self, ids: &mut Vec<FileID>) {
+        ids.extend(self.files.keys());
+        for node in self.folders.values() {
+            node.collect_all(ids);
+        }
+    }
+
+    fn insert(&mut self, folder: &[String], id: FileID, hash: [u8; 32]) {
+        match folder.split_first() {
+            Some((name, rest)) => self.folders.entry(name.clone()).or_insert_with(MerkleNode::default).insert(rest, id, hash),
+            None => {
+                self.files.insert(id, hash);
+            }
+        }
+    }
+
+    fn seal(&mut self) {
+        let mut hasher = Sha256::new();
+        let mut int_buf = [0;4];
+        for (&id, hash) in self.files.iter() {
+            write_id(&mut hasher, id).unwrap();
+            hasher.write_all(hash).unwrap();
+        }
+        for (name, node) in self.folders.iter_mut() {
+            node.seal();
+            write_str(&mut hasher, &mut int_buf, name).unwrap();
+            hasher.write_all(&node.hash).unwrap();
+        }
+        self.hash = hasher.finalize().into();
+    }
+}
+
+impl<FU: FileUpdater> FileSet<FU> {
+    // Two sites with large filesets can swap the hashes of the root, then of the
+    // folders under any that differ, and so on down, and only resync the files
+    // that turn out to differ
+    pub fn get_merkle_tree(&self) -> MerkleNode {
+        self.merkle_tree_shared_with(None)
+    }
+
+    pub fn get_merkle_tree_for_peer(&self, peer: SiteId) -> MerkleNode {
+        self.merkle_tree_shared_with(Some(peer))
+    }
+
+    fn merkle_tree_shared_with(&self, peer: Option<SiteId>) -> MerkleNode {
+        let mut root = MerkleNode::default();
+        for (&id, file) in self.files.iter().filter(|&(_, file)| self.is_metadata_shared_with(peer, file)) {
+            let filename = &file.filename.1;
+            root.insert(&filename[..filename.len() - 1], id, file_digest(id, file));
+        }
+        root.seal();
+        root
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::hash_map::HashMap;
+use std::io;
+use byteorder::{NetworkEndian, ByteOrder};
+
+use super::{FileID, FileMetadata, FolderMove, SiteId, State, Producer, VersionVector, HybridTimestamp, TieBreaker, filename_superseded};
+use serialization::{write_path, read_path, write_id, read_id, write_site_id, read_site_id, preallocation};
+
+// An entry's name, with the site and the hybrid time it was given at, which
+// decide whether a later change to the name wins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryName {
+    pub filename: (u64, Vec<String>),
+    pub site_id: Option<SiteId>,
+    pub hybrid_time: HybridTimestamp
+}
+
+impl EntryName {
+    fn compress_to<W: io::Write>(&self, writer: &mut W, int_buf: &mut [u8;4]) -> io::Result<()> {
+        try!(write_u64(writer, self.filename.0));
+        try!(write_path(writer, int_buf, &self.filename.1));
+        match self.site_id {
+            Some(site_id) => {
+                try!(writer.write_all(&[1]));
+                try!(write_site_id(writer, site_id));
+            },
+            None => try!(writer.write_all(&[0]))
+        }
+        self.hybrid_time.compress_to(writer)
+    }
+
+    fn expand_from<R: io::Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<EntryName> {
+        let filename = (try!(read_u64(reader)), try!(read_path(reader, int_buf)));
+        let mut flag = [0;1];
+        try!(reader.read_exact(&mut flag));
+        let site_id = if flag[0] == 0 {
+            None
+        } else {
+            Some(try!(read_site_id(reader)))
+        };
+        Ok(EntryName {
+            filename: filename,
+            site_id: site_id,
+            hybrid_time: try!(HybridTimestamp::expand_from(reader))
+        })
+    }
+}
+
+pub struct MoveRecord {
+    pub operation: FolderMove,
+    // The folder and destination the move actually used, or nothing if it was skipped
+    pub resolved: Option<(Vec<String>, Vec<String>)>,
+    // Each entry the move renamed, with its name before and after
+    pub changed: Vec<(FileID, EntryName, EntryName)>
+}
+
+impl MoveRecord {
+    // The names that undo the move, leaving alone anything renamed again since
+    pub fn undo_renames(&self, files: &HashMap<FileID, FileMetadata>) -> Vec<(FileID, EntryName)> {
+        self.changed.iter().filter(|&&(id, _, ref name)| {
+            files.get(&id).map_or(false, |metadata| metadata.filename == name.filename)
+        }).map(|&(id, ref previous, _)| (id, previous.clone())).collect()
+    }
+}
+
+// Every folder move that has been applied, in the order every site applies them.
+// A move that arrives out of order is slotted into place, and the moves after it
+// are undone and redone so that every site ends up with the same tree.
+#[derive(Default)]
+pub struct MoveLog {
+    records: Vec<MoveRecord>,
+    // The clock moves made from now on start from, once earlier records are pruned
+    next_clock: u64
+}
+
+impl MoveLog {
+    #[inline]
+    pub fn new() -> MoveLog {
+        MoveLog {
+            records: Vec::new(),
+            next_clock: 0
+        }
+    }
+
+    // A move made here has to come after every move already seen
+    pub fn next_clock(&self) -> u64 {
+        self.records.last().map_or(self.next_clock, |record| following(record.operation.clock))
+    }
+
+    // Drops the moves at the start of the log that every site has seen, since no
+    // move still to come can be ordered before them. Returns how many were dropped.
+    pub fn prune(&mut self, stable: &VersionVector) -> usize {
+        let count = self.records.iter().take_while(|record| stable.includes(&record.operation.state)).count();
+        if count > 0 {
+            self.next_clock = following(self.records[count - 1].operation.clock);
+            self.records.drain(..count);
+        }
+        count
+    }
+
+    pub fn split_later(&mut self, operation: &FolderMove) -> Vec<MoveRecord> {
+        let position = self.records.iter().position(|record| order(&record.operation) > order(operation)).unwrap_or(self.records.len());
+        self.records.split_off(position)
+    }
+
+    pub fn push(&mut self, record: MoveRecord) {
+        self.records.push(record);
+    }
+
+    // Follows the folder and its destination through every earlier move the
+    // moving site didn't know about, refusing the move if the destination
+    // has ended up inside the folder
+    pub fn resolve(&self, operation: &FolderMove) -> Option<(Vec<String>, Vec<String>)> {
+        let mut source = operation.old_path.clone();
+        let mut destination = operation.new_path.clone();
+        for record in self.records.iter().filter(|record| !operation.seen.includes(&record.operation.state)) {
+            if let Some((ref from, ref to)) = record.resolved {
+                source = translate(source, from, to);
+                destination = translate(destination, from, to);
+            }
+        }
+        if destination.starts_with(&source) {
+            None
+        } else {
+            Some((source, destination))
+        }
+    }
+
+    // Works out which of the files the move renames, and to what, without renaming
+    // anything, so that integrating a move and replaying it from the operation log
+    // come to the same names
+    pub fn plan(&self, operation: FolderMove, files: &HashMap<FileID, FileMetadata>, tie_breaker: &dyn TieBreaker) -> MoveRecord {
+        let resolved = self.resolve(&operation);
+        let mut changed = Vec::new();
+        match resolved {
+            Some((_, ref destination)) => {
+                for &(id, ref filename) in operation.files.iter() {
+                    let metadata = match files.get(&id) {
+                        Some(metadata) => metadata,
+                        None => {
+                            trace!("Not moving {:?}, which has been removed", id);
+                            continue
+                        }
+                    };
+                    if filename_superseded(tie_breaker, metadata, operation.state.time_stamp, Some(operation.state.site_id), operation.hybrid_time, filename) {
+                        continue
+                    }
+                    let filename:Vec<_> = destination.iter().chain(filename.iter().skip(operation.new_path.len())).cloned().collect();
+                    changed.push((id, metadata.entry_name(), EntryName {
+                        filename: (operation.state.time_stamp, filename),
+                        site_id: Some(operation.state.site_id),
+                        hybrid_time: operation.hybrid_time
+                    }));
+                }
+            },
+            None => warn!("Skipping folder move {}, which would put {:?} inside itself", operation.state, operation.old_path)
+        }
+        MoveRecord {
+            operation: operation,
+            resolved: resolved,
+            changed: changed
+        }
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        try!(write_u64(writer, self.next_clock));
+        NetworkEndian::write_u32(&mut int_buf, self.records.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for record in self.records.iter() {
+            let operation = &record.operation;
+            try!(write_id(writer, (operation.state.site_id, operation.state.time_stamp)));
+            try!(write_u64(writer, operation.clock));
+            try!(operation.hybrid_time.compress_to(writer));
+            try!(operation.seen.compress_to(writer));
+            try!(write_path(writer, &mut int_buf, &operation.old_path));
+            try!(write_path(writer, &mut int_buf, &operation.new_path));
+            try!(write_u32(writer, &mut int_buf, operation.files.len() as u32));
+            for &(id, ref filename) in operation.files.iter() {
+                try!(write_id(writer, id));
+                try!(write_path(writer, &mut int_buf, filename));
+            }
+            match record.resolved {
+                Some((ref source, ref destination)) => {
+                    try!(writer.write_all(&[1]));
+                    try!(write_path(writer, &mut int_buf, source));
+                    try!(write_path(writer, &mut int_buf, destination));
+                },
+                None => try!(writer.write_all(&[0]))
+            }
+            try!(write_u32(writer, &mut int_buf, record.changed.len() as u32));
+            for &(id, ref previous, ref current) in record.changed.iter() {
+                try!(write_id(writer, id));
+                try!(previous.compress_to(writer, &mut int_buf));
+                try!(current.compress_to(writer, &mut int_buf));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<MoveLog> {
+        let mut int_buf = [0;4];
+        let next_clock = try!(read_u64(reader));
+        let record_count = try!(read_u32(reader, &mut int_buf)) as usize;
+        let mut records = Vec::with_capacity(preallocation(record_count));
+        for _ in 0..record_count {
+            let (site_id, time_stamp) = try!(read_id(reader));
+            let clock = try!(read_u64(reader));
+            let hybrid_time = try!(HybridTimestamp::expand_from(reader));
+            let seen = try!(VersionVector::expand_from(reader));
+            let old_path = try!(read_path(reader, &mut int_buf));
+            let new_path = try!(read_path(reader, &mut int_buf));
+            let file_count = try!(read_u32(reader, &mut int_buf)) as usize;
+            let mut files = Vec::with_capacity(preallocation(file_count));
+            for _ in 0..file_count {
+                let id = try!(read_id(reader));
+                files.push((id, try!(read_path(reader, &mut int_buf))));
+            }
+            let mut flag = [0;1];
+            try!(reader.read_exact(&mut flag));
+            let resolved = if flag[0] == 1 {
+                let source = try!(read_path(reader, &mut int_buf));
+                Some((source, try!(read_path(reader, &mut int_buf))))
+            } else {
+                None
+            };
+            let changed_count = try!(read_u32(reader, &mut int_buf)) as usize;
+            let mut changed = Vec::with_capacity(preallocation(changed_count));
+            for _ in 0..changed_count {
+                let id = try!(read_id(reader));
+                let previous = try!(EntryName::expand_from(reader, &mut int_buf));
+                changed.push((id, previous, try!(EntryName::expand_from(reader, &mut int_buf))));
+            }
+            records.push(MoveRecord {
+                operation: FolderMove {
+                    state: State {
+                        site_id: site_id,
+                        time_stamp: time_stamp
+                    },
+                    producer: Producer::default(),
+                    clock: clock,
+                    hybrid_time: hybrid_time,
+                    seen: seen,
+                    old_path: old_path,
+                    new_path: new_path,
+                    files: files
+                },
+                resolved: resolved,
+                changed: changed
+            });
+        }
+        Ok(MoveLog {
+            records: records,
+            next_clock: next_clock
+        })
+    }
+}
+
+fn order(operation: &FolderMove) -> (u64, SiteId, u64) {
+    (operation.clock, operation.state.site_id, operation.state.time_stamp)
+}
+
+// Like timestamps, a clock that was reused would order moves differently at
+// different sites, so running out is never allowed to wrap around
+fn following(clock: u64) -> u64 {
+    clock.checked_add(1).expect("move clocks exhausted")
+}
+
+fn translate(path: Vec<String>, from: &[String], to: &[String]) -> Vec<String> {
+    if path.starts_with(from) {
+        to.iter().chain(path[from.len()..].iter()).cloned().collect()
+    } else {
+        path
+    }
+}
+
+fn write_u32<W: io::Write>(writer: &mut W, int_buf: &mut [u8;4], value: u32) -> io::Result<()> {
+    NetworkEndian::write_u32(int_buf, value);
+    writer.write_all(int_buf)
+}
+
+fn read_u32<R: io::Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<u32> {
+    try!(reader.read_exact(int_buf));
+    Ok(NetworkEndian::read_u32(int_buf))
+}
+
+fn write_u64<W: io::Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    let mut long_buf = [0;8];
+    NetworkEndian::write_u64(&mut long_buf, value);
+    writer.write_all(&long_buf)
+}
+
+fn read_u64<R: io::Read>(reader: &mut R) -> io::Result<u64> {
+    let mut long_buf = [0;8];
+    try!(reader.read_exact(&mut long_buf));
+    Ok(NetworkEndian::read_u64(&long_buf))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MoveLog, MoveRecord};
+    use super::super::{FolderMove, SiteId, State, Producer, VersionVector, HybridTimestamp};
+
+    fn path(path: &str) -> Vec<String> {
+        path.split('/').map(|component| component.to_string()).collect()
+    }
+
+    fn folder_move(site_id: SiteId, old_path: &str, new_path: &str) -> FolderMove {
+        FolderMove {
+            state: State { site_id: site_id, time_stamp: 0 },
+            producer: Producer::default(),
+            clock: 0,
+            hybrid_time: HybridTimestamp::default(),
+            seen: VersionVector::new(),
+            old_path: path(old_path),
+            new_path: path(new_path),
+            files: Vec::new()
+        }
+    }
+
+    #[test]
+    fn concurrent_moves_cannot_form_a_cycle() {
+        let mut log = MoveLog::new();
+        let first = folder_move(1, "a", "b/a");
+        let resolved = log.resolve(&first);
+        assert_eq!(resolved, Some((path("a"), path("b/a"))));
+        log.push(MoveRecord {
+            operation: first,
+            resolved: resolved,
+            changed: Vec::new()
+        });
+        // b into a would now be b into b/a
+        assert_eq!(log.resolve(&folder_move(2, "b", "a/b")), None);
+        assert_eq!(log.resolve(&folder_move(2, "a/c", "d")), Some((path("b/a/c"), path("d"))));
+
+        let mut buffer = Vec::new();
+        log.compress_to(&mut buffer).unwrap();
+        let mut log = MoveLog::expand_from(&mut &buffer[..]).unwrap();
+        assert_eq!(log.next_clock(), 1);
+        assert_eq!(log.split_later(&folder_move(0, "e", "f")).len(), 1);
+        assert_eq!(log.next_clock(), 0);
+    }
+
+    #[test]
+    fn pruned_moves_keep_the_clock() {
+        let mut log = MoveLog::new();
+        log.push(MoveRecord {
+            operation: folder_move(1, "a", "b"),
+            resolved: Some((path("a"), path("b"))),
+            changed: Vec::new()
+        });
+        let mut stable = VersionVector::new();
+        assert_eq!(log.prune(&stable), 0);
+        stable.observe(1, 1);
+        assert_eq!(log.prune(&stable), 1);
+
+        let mut buffer = Vec::new();
+        log.compress_to(&mut buffer).unwrap();
+        let log = MoveLog::expand_from(&mut &buffer[..]).unwrap();
+        assert_eq!(log.next_clock(), 1);
+    }
+
+    #[test]
+    fn clocks_go_past_32_bits() {
+        let mut log = MoveLog::new();
+        let mut operation = folder_move(1, "a", "b");
+        operation.clock = u32::max_value() as u64;
+        log.push(MoveRecord {
+            operation: operation,
+            resolved: Some((path("a"), path("b"))),
+            changed: Vec::new()
+        });
+        let mut buffer = Vec::new();
+        log.compress_to(&mut buffer).unwrap();
+        let log = MoveLog::expand_from(&mut &buffer[..]).unwrap();
+        assert_eq!(log.next_clock(), u32::max_value() as u64 + 1);
+    }
+}
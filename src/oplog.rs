@@ -0,0 +1,553 @@
+use std::collections::hash_map::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use byteorder::{NetworkEndian, ByteOrder};
+
+use super::{FileSet, FileSetOperation, FileUpdater, FileMetadata, FileSetError, MetadataTransaction, FileID, SiteId, State, VersionVector, HybridTimestamp, FolderMove, Producer, ConflictPolicy, RenamePolicy, RemoveOutcome, integrate_attribute, filename_superseded, remove_outcome};
+use serialization::{write_id, read_id, write_site_id, read_site_id, write_str, read_str, write_path, read_path, preallocation};
+use moves::{MoveLog, EntryName};
+
+const CREATE: u8 = 0;
+const REMOVE: u8 = 1;
+const UPDATE: u8 = 2;
+const FILENAME: u8 = 3;
+const ATTRIBUTES: u8 = 4;
+const MOVE_FOLDER: u8 = 5;
+const ANNOUNCE_SITE: u8 = 6;
+
+// What an operation did, without the contents of updates, which only the
+// updater knows how to store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoggedOperation {
+    Create { id: FileID, filename: Vec<String>, directory: bool },
+    // With the last update and everything else the removing site had seen,
+    // which decide whether the file stays
+    Remove { id: FileID, last_update: Option<State>, seen: VersionVector },
+    Update { id: FileID },
+    Filename { id: FileID, filename: Vec<String> },
+    Attributes { id: FileID, values: Vec<(String, String)> },
+    // With what orders the move among the others, and the files it moved
+    MoveFolder { clock: u64, seen: VersionVector, old_path: Vec<String>, new_path: Vec<String>, files: Vec<(FileID, Vec<String>)> },
+    AnnounceSite { site_id: SiteId }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationRecord {
+    pub sequence: u64,
+    pub state: State,
+    pub hybrid_time: HybridTimestamp,
+    pub operation: LoggedOperation
+}
+
+impl LoggedOperation {
+    // Bundles are logged as the operations inside them, one at a time
+    pub fn from_operation<FU: FileUpdater>(operation: &FileSetOperation<FU>) -> Option<LoggedOperation> {
+        Some(match *operation {
+            FileSetOperation::Create(ref o) => LoggedOperation::Create {
+                id: o.id,
+                filename: o.filename.clone(),
+                directory: o.directory
+            },
+            FileSetOperation::Remove(ref o) => LoggedOperation::Remove {
+                id: o.id,
+                last_update: o.last_update,
+                seen: o.seen.clone()
+            },
+            FileSetOperation::Update(ref o, _) => LoggedOperation::Update { id: o.id },
+            FileSetOperation::UpdateMetadata(ref o) => match o.data {
+                MetadataTransaction::Filename(ref filename) => LoggedOperation::Filename {
+                    id: o.id,
+                    filename: filename.clone()
+                },
+                MetadataTransaction::Custom(ref key, ref value) => LoggedOperation::Attributes {
+                    id: o.id,
+                    values: vec![(key.clone(), value.clone())]
+                },
+                MetadataTransaction::CustomBatch(ref values) => LoggedOperation::Attributes {
+                    id: o.id,
+                    values: values.clone()
+                }
+            },
+            FileSetOperation::MoveFolder(ref o) => LoggedOperation::MoveFolder {
+                clock: o.clock,
+                seen: o.seen.clone(),
+                old_path: o.old_path.clone(),
+                new_path: o.new_path.clone(),
+                files: o.files.clone()
+            },
+            FileSetOperation::Bundle(_) => return None,
+            FileSetOperation::AnnounceSite(ref o) => LoggedOperation::AnnounceSite { site_id: o.site_id }
+        })
+    }
+}
+
+// An append-only record of every operation applied here, local or remote, in
+// the order it was applied. It starts with the sequence number of its first
+// record, so that numbering carries on when what's before a snapshot is dropped.
+// Records reach the disk when the log is synced, rather than one at a time.
+pub struct OperationLog {
+    log_path: PathBuf,
+    log_file: Option<fs::File>,
+    unsynced: bool,
+    first_sequence: u64,
+    next_sequence: u64
+}
+
+impl OperationLog {
+    pub fn open<P: AsRef<Path>>(log_path: P) -> io::Result<OperationLog> {
+        let log_path = log_path.as_ref().to_path_buf();
+        let mut first_sequence = 0;
+        let mut next_sequence = 0;
+        match fs::File::open(&log_path) {
+            Ok(log_file) => {
+                let total_length = try!(log_file.metadata()).len();
+                let mut reader = CountingReader {
+                    inner: io::BufReader::new(log_file),
+                    count: 0
+                };
+                let mut long_buf = [0;8];
+                let mut int_buf = [0;4];
+                let mut good_length = 0;
+                if reader.read_exact(&mut long_buf).is_ok() {
+                    first_sequence = NetworkEndian::read_u64(&long_buf);
+                    next_sequence = first_sequence;
+                    good_length = reader.count;
+                }
+                while read_record(&mut reader, &mut int_buf).is_ok() {
+                    next_sequence += 1;
+                    good_length = reader.count;
+                }
+                if good_length < total_length {
+                    // A record cut short by a crash can only be the last one, and
+                    // has to go before anything is written after it
+                    warn!("Dropping {} bytes from the end of the operation log", total_length - good_length);
+                    let log_file = try!(fs::OpenOptions::new().write(true).open(&log_path));
+                    try!(log_file.set_len(good_length));
+                }
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e)
+        }
+        Ok(OperationLog {
+            log_path: log_path,
+            log_file: None,
+            unsynced: false,
+            first_sequence: first_sequence,
+            next_sequence: next_sequence
+        })
+    }
+
+    #[inline]
+    pub fn first_sequence(&self) -> u64 {
+        self.first_sequence
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.next_sequence
+    }
+
+    pub fn append(&mut self, state: &State, hybrid_time: HybridTimestamp, operation: &LoggedOperation) -> io::Result<u64> {
+        let sequence = self.next_sequence;
+        let mut record = Vec::new();
+        let mut int_buf = [0;4];
+        if self.log_file.is_none() {
+            let log_file = try!(fs::OpenOptions::new().create(true).append(true).open(&self.log_path));
+            if try!(log_file.metadata()).len() == 0 {
+                let mut long_buf = [0;8];
+                NetworkEndian::write_u64(&mut long_buf, self.first_sequence);
+                record.extend_from_slice(&long_buf);
+            }
+            self.log_file = Some(log_file);
+        }
+        try!(write_record(&mut record, &mut int_buf, state, hybrid_time, operation));
+        try!(self.log_file.as_mut().unwrap().write_all(&record));
+        self.unsynced = true;
+        self.next_sequence += 1;
+        Ok(sequence)
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let (true, Some(log_file)) = (self.unsynced, self.log_file.as_ref()) {
+            try!(log_file.sync_data());
+        }
+        self.unsynced = false;
+        Ok(())
+    }
+
+    pub fn read_range(&self, range: Range<u64>) -> io::Result<Vec<OperationRecord>> {
+        let mut records = Vec::new();
+        if range.start >= range.end {
+            return Ok(records)
+        }
+        let log_file = match fs::File::open(&self.log_path) {
+            Ok(log_file) => log_file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(records),
+            Err(e) => return Err(e)
+        };
+        let mut reader = io::BufReader::new(log_file);
+        let mut long_buf = [0;8];
+        let mut int_buf = [0;4];
+        try!(reader.read_exact(&mut long_buf));
+        for sequence in self.first_sequence..range.end.min(self.next_sequence) {
+            let (state, hybrid_time, operation) = try!(read_record(&mut reader, &mut int_buf));
+            if sequence >= range.start {
+                records.push(OperationRecord {
+                    sequence: sequence,
+                    state: state,
+                    hybrid_time: hybrid_time,
+                    operation: operation
+                });
+            }
+        }
+        Ok(records)
+    }
+
+    // Drops every record, keeping the numbering. The new log replaces the old one
+    // in one step, so a crash leaves one or the other.
+    pub fn compact(&mut self) -> io::Result<()> {
+        try!(self.sync());
+        // The next record goes in the new log
+        self.log_file = None;
+        let compacted_path = self.log_path.with_extension("compacted");
+        {
+            let mut log_file = try!(fs::File::create(&compacted_path));
+            let mut long_buf = [0;8];
+            NetworkEndian::write_u64(&mut long_buf, self.next_sequence);
+            try!(log_file.write_all(&long_buf));
+            try!(log_file.sync_all());
+        }
+        try!(fs::rename(&compacted_path, &self.log_path));
+        self.first_sequence = self.next_sequence;
+        Ok(())
+    }
+}
+
+impl<FU: FileUpdater> FileSet<FU> {
+    // The names and attributes files had once only the operations the vector
+    // includes had been applied, rebuilt from the operation log. Once the log has
+    // been compacted, it starts from the snapshot taken then, so the vector has to
+    // include everything the snapshot does. Anything from before the log was
+    // started is missing, and names are given as every site agrees on them,
+    // without conflicts printed. Changes are ordered the same way as when they
+    // were integrated, tie breaker included, folder moves go through a move log of
+    // their own, and removes are decided by the same policies.
+    pub fn materialize_at(&self, seen: &VersionVector) -> Result<HashMap<FileID, FileMetadata>, FileSetError> {
+        let records = try!(self.operation_log.read_range(0..self.operation_log.count()).map_err(|e| FileSetError::IOError(e)));
+        let mut files: HashMap<FileID, FileMetadata> = HashMap::new();
+        if self.operation_log.first_sequence() > 0 {
+            let (snapshot_seen, snapshot) = match try!(self.get_snapshot()) {
+                Some(snapshot) => snapshot,
+                None => return Err(FileSetError::HistoryCompacted)
+            };
+            if snapshot_seen.iter().any(|(&site_id, &time_stamp)| seen.get(site_id) < time_stamp) {
+                return Err(FileSetError::HistoryCompacted)
+            }
+            for (&id, file) in snapshot.get_files().iter() {
+                let mut file = file.clone();
+                file.printed_filename = file.filename.1[file.filename.1.len() - 1].clone();
+                files.insert(id, file);
+            }
+        }
+        // What each removed file was, and what its remover had seen
+        let mut removed: HashMap<FileID, Option<(FileMetadata, VersionVector)>> = HashMap::new();
+        let mut last_updates: HashMap<FileID, State> = HashMap::new();
+        let mut folder_moves = MoveLog::new();
+        // Nothing is reported from here
+        let mut conflicts = Vec::new();
+        for record in records {
+            let state = record.state;
+            let hybrid_time = record.hybrid_time;
+            if !seen.includes(&state) {
+                continue
+            }
+            match record.operation {
+                LoggedOperation::Create { id, filename, directory } => {
+                    if removed.contains_key(&id) || files.contains_key(&id) {
+                        continue
+                    }
+                    let printed = filename[filename.len() - 1].clone();
+                    files.insert(id, FileMetadata::new_entry(filename, printed, directory, &state, hybrid_time));
+                },
+                LoggedOperation::Remove { id, seen: remover_seen, .. } => {
+                    let outcome = match files.get(&id) {
+                        Some(file) => remove_outcome(self.conflict_policy, self.rename_policy, last_updates.get(&id), file, &remover_seen),
+                        None => RemoveOutcome::Remove
+                    };
+                    let set_aside = match outcome {
+                        RemoveOutcome::KeepUpdated => true,
+                        RemoveOutcome::KeepRenamed => self.rename_policy == RenamePolicy::KeepAsConflict,
+                        RemoveOutcome::Remove => false
+                    };
+                    if set_aside {
+                        let file = files.get_mut(&id).unwrap();
+                        if let Some(filename) = self.resurrection_filename(&file.filename.1) {
+                            let mut name = file.entry_name();
+                            name.filename.1 = filename;
+                            rename_entry(file, name);
+                        }
+                    }
+                    if outcome == RemoveOutcome::Remove {
+                        match files.remove(&id) {
+                            Some(file) => {
+                                removed.insert(id, Some((file, remover_seen)));
+                            },
+                            // Removing it again changes nothing
+                            None => {
+                                removed.entry(id).or_insert(None);
+                            }
+                        }
+                    }
+                },
+                LoggedOperation::Update { id } => {
+                    let resurrects = match removed.get(&id) {
+                        Some(&Some((_, ref remover_seen))) => self.conflict_policy == ConflictPolicy::AddWins && !remover_seen.includes(&state),
+                        _ => false
+                    };
+                    if resurrects {
+                        let (mut file, _) = removed.remove(&id).unwrap().unwrap();
+                        if let Some(filename) = self.resurrection_filename(&file.filename.1) {
+                            file.filename.1 = filename;
+                        }
+                        file.printed_filename = file.filename.1[file.filename.1.len() - 1].clone();
+                        files.insert(id, file);
+                    }
+                    if files.contains_key(&id) {
+                        last_updates.insert(id, state);
+                    }
+                },
+                LoggedOperation::AnnounceSite { .. } => {},
+                LoggedOperation::Filename { id, filename } => {
+                    let resurrects = match removed.get(&id) {
+                        Some(&Some((_, ref remover_seen))) => self.rename_policy != RenamePolicy::RemoveWins && !remover_seen.includes(&state),
+                        _ => false
+                    };
+                    if resurrects {
+                        let (mut file, _) = removed.remove(&id).unwrap().unwrap();
+                        file.set_filename(filename, &state, hybrid_time);
+                        if self.rename_policy == RenamePolicy::KeepAsConflict {
+                            if let Some(resurrected) = self.resurrection_filename(&file.filename.1) {
+                                file.filename.1 = resurrected;
+                            }
+                        }
+                        file.printed_filename = file.filename.1[file.filename.1.len() - 1].clone();
+                        files.insert(id, file);
+                    } else if let Some(file) = files.get_mut(&id) {
+                        if !filename_superseded(&*self.tie_breaker, file, state.time_stamp, Some(state.site_id), hybrid_time, &filename) {
+                            file.printed_filename = filename[filename.len() - 1].clone();
+                            file.set_filename(filename, &state, hybrid_time);
+                        }
+                    }
+                },
+                LoggedOperation::Attributes { id, values } => {
+                    if let Some(file) = files.get_mut(&id) {
+                        for (key, value) in values {
+                            integrate_attribute(file, id, key, value, state.time_stamp, Some(state.site_id), hybrid_time, &*self.tie_breaker, &mut conflicts);
+                        }
+                    }
+                },
+                LoggedOperation::MoveFolder { clock, seen: mover_seen, old_path, new_path, files: moved } => {
+                    // Moves ordered after this one were undone and redone when it was integrated
+                    let operation = FolderMove {
+                        state: state,
+                        producer: Producer::default(),
+                        clock: clock,
+                        hybrid_time: hybrid_time,
+                        seen: mover_seen,
+                        old_path: old_path,
+                        new_path: new_path,
+                        files: moved
+                    };
+                    let later = folder_moves.split_later(&operation);
+                    for record in later.iter().rev() {
+                        for (id, name) in record.undo_renames(&files) {
+                            rename_entry(files.get_mut(&id).unwrap(), name);
+                        }
+                    }
+                    for operation in iter::once(operation).chain(later.into_iter().map(|record| record.operation)) {
+                        let record = folder_moves.plan(operation, &files, &*self.tie_breaker);
+                        for &(id, _, ref name) in record.changed.iter() {
+                            rename_entry(files.get_mut(&id).unwrap(), name.clone());
+                        }
+                        folder_moves.push(record);
+                    }
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn rename_entry(file: &mut FileMetadata, name: EntryName) {
+    file.printed_filename = name.filename.1[name.filename.1.len() - 1].clone();
+    file.set_entry_name(name);
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: u64
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, int_buf: &mut [u8;4], state: &State, hybrid_time: HybridTimestamp, operation: &LoggedOperation) -> io::Result<()> {
+    try!(write_id(writer, (state.site_id, state.time_stamp)));
+    try!(hybrid_time.compress_to(writer));
+    match *operation {
+        LoggedOperation::Create { id, ref filename, directory } => {
+            try!(writer.write_all(&[CREATE]));
+            try!(write_id(writer, id));
+            try!(write_path(writer, int_buf, filename));
+            writer.write_all(&[directory as u8])
+        },
+        LoggedOperation::Remove { id, last_update, ref seen } => {
+            try!(writer.write_all(&[REMOVE]));
+            try!(write_id(writer, id));
+            match last_update {
+                Some(state) => {
+                    try!(writer.write_all(&[1]));
+                    try!(write_id(writer, (state.site_id, state.time_stamp)));
+                },
+                None => try!(writer.write_all(&[0]))
+            }
+            seen.compress_to(writer)
+        },
+        LoggedOperation::Update { id } => {
+            try!(writer.write_all(&[UPDATE]));
+            write_id(writer, id)
+        },
+        LoggedOperation::Filename { id, ref filename } => {
+            try!(writer.write_all(&[FILENAME]));
+            try!(write_id(writer, id));
+            write_path(writer, int_buf, filename)
+        },
+        LoggedOperation::Attributes { id, ref values } => {
+            try!(writer.write_all(&[ATTRIBUTES]));
+            try!(write_id(writer, id));
+            NetworkEndian::write_u32(int_buf, values.len() as u32);
+            try!(writer.write_all(int_buf));
+            for (key, value) in values.iter() {
+                try!(write_str(writer, int_buf, key));
+                try!(write_str(writer, int_buf, value));
+            }
+            Ok(())
+        },
+        LoggedOperation::MoveFolder { clock, ref seen, ref old_path, ref new_path, ref files } => {
+            try!(writer.write_all(&[MOVE_FOLDER]));
+            let mut long_buf = [0;8];
+            NetworkEndian::write_u64(&mut long_buf, clock);
+            try!(writer.write_all(&long_buf));
+            try!(seen.compress_to(writer));
+            try!(write_path(writer, int_buf, old_path));
+            try!(write_path(writer, int_buf, new_path));
+            NetworkEndian::write_u32(int_buf, files.len() as u32);
+            try!(writer.write_all(int_buf));
+            for &(id, ref filename) in files.iter() {
+                try!(write_id(writer, id));
+                try!(write_path(writer, int_buf, filename));
+            }
+            Ok(())
+        },
+        LoggedOperation::AnnounceSite { site_id } => {
+            try!(writer.write_all(&[ANNOUNCE_SITE]));
+            write_site_id(writer, site_id)
+        }
+    }
+}
+
+fn read_record<R: Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<(State, HybridTimestamp, LoggedOperation)> {
+    let (site_id, time_stamp) = try!(read_id(reader));
+    let state = State {
+        site_id: site_id,
+        time_stamp: time_stamp
+    };
+    let hybrid_time = try!(HybridTimestamp::expand_from(reader));
+    let mut byte = [0;1];
+    try!(reader.read_exact(&mut byte));
+    let operation = match byte[0] {
+        CREATE => {
+            let id = try!(read_id(reader));
+            let filename = try!(read_path(reader, int_buf));
+            try!(reader.read_exact(&mut byte));
+            LoggedOperation::Create {
+                id: id,
+                filename: filename,
+                directory: byte[0] != 0
+            }
+        },
+        REMOVE => {
+            let id = try!(read_id(reader));
+            try!(reader.read_exact(&mut byte));
+            let last_update = if byte[0] == 0 {
+                None
+            } else {
+                let (site_id, time_stamp) = try!(read_id(reader));
+                Some(State {
+                    site_id: site_id,
+                    time_stamp: time_stamp
+                })
+            };
+            LoggedOperation::Remove {
+                id: id,
+                last_update: last_update,
+                seen: try!(VersionVector::expand_from(reader))
+            }
+        },
+        UPDATE => LoggedOperation::Update { id: try!(read_id(reader)) },
+        FILENAME => {
+            let id = try!(read_id(reader));
+            LoggedOperation::Filename {
+                id: id,
+                filename: try!(read_path(reader, int_buf))
+            }
+        },
+        ATTRIBUTES => {
+            let id = try!(read_id(reader));
+            try!(reader.read_exact(int_buf));
+            let count = NetworkEndian::read_u32(int_buf) as usize;
+            let mut values = Vec::with_capacity(preallocation(count));
+            for _ in 0..count {
+                let key = try!(read_str(reader, int_buf));
+                values.push((key, try!(read_str(reader, int_buf))));
+            }
+            LoggedOperation::Attributes {
+                id: id,
+                values: values
+            }
+        },
+        MOVE_FOLDER => {
+            let mut long_buf = [0;8];
+            try!(reader.read_exact(&mut long_buf));
+            let clock = NetworkEndian::read_u64(&long_buf);
+            let seen = try!(VersionVector::expand_from(reader));
+            let old_path = try!(read_path(reader, int_buf));
+            let new_path = try!(read_path(reader, int_buf));
+            try!(reader.read_exact(int_buf));
+            let count = NetworkEndian::read_u32(int_buf) as usize;
+            let mut files = Vec::with_capacity(preallocation(count));
+            for _ in 0..count {
+                let id = try!(read_id(reader));
+                files.push((id, try!(read_path(reader, int_buf))));
+            }
+            LoggedOperation::MoveFolder {
+                clock: clock,
+                seen: seen,
+                old_path: old_path,
+                new_path: new_path,
+                files: files
+            }
+        },
+        ANNOUNCE_SITE => LoggedOperation::AnnounceSite { site_id: try!(read_site_id(reader)) },
+        other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown logged operation kind {}", other)))
+    };
+    Ok((state, hybrid_time, operation))
+}
@@ -0,0 +1,288 @@
+use std::collections::hash_map::HashMap;
+use std::collections::hash_set::HashSet;
+use std::collections::vec_deque::{self, VecDeque};
+use std::ffi::OsStr;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use super::{FileSet, FileUpdater, FileHistory, FileMetadata, FileSetOperation, FileSetError, FileID, State, TimestampMap, SyncEvent, ReconciliationReport, HybridTimestamp};
+
+pub enum PlannedChange<FU: FileUpdater> {
+    // A file on disk that isn't in the fileset yet, which will be sent to the remote site
+    CreateLocal(PathBuf),
+    // A file on both sides and on disk, which gets the remote changes and sends back its own
+    Update(FileID, PathBuf, FileHistory<FU>),
+    // A file on both sides that isn't on disk here
+    Keep(FileID, PathBuf),
+    // A file the remote site doesn't have
+    Remove(FileID, PathBuf),
+    // A file only the remote site has, and whether it should be kept off the disk here
+    Create(FileID, FileHistory<FU>, bool)
+}
+
+impl<FU: FileUpdater> PlannedChange<FU> {
+    pub fn get_path(&self) -> PathBuf {
+        match *self {
+            PlannedChange::CreateLocal(ref path) |
+            PlannedChange::Update(_, ref path, _) |
+            PlannedChange::Keep(_, ref path) |
+            PlannedChange::Remove(_, ref path) => path.clone(),
+            PlannedChange::Create(_, ref history, _) => history.filename.1.iter().collect()
+        }
+    }
+}
+
+// Everything integrating a remote file list will do, worked out before doing any of
+// it, so that the application can look it over and carry it out a piece at a time
+pub struct ReconciliationPlan<FU: FileUpdater> {
+    changes: VecDeque<PlannedChange<FU>>,
+    timestamp_lookup: TimestampMap,
+    total: usize,
+    started: bool,
+    remote_changes: usize,
+    operations: Vec<FileSetOperation<FU>>,
+    report: ReconciliationReport
+}
+
+impl<FU: FileUpdater> ReconciliationPlan<FU> {
+    pub fn changes<'a>(&'a self) -> vec_deque::Iter<'a, PlannedChange<FU>> {
+        self.changes.iter()
+    }
+
+    #[inline]
+    pub fn get_remaining(&self) -> usize {
+        self.changes.len()
+    }
+
+    #[inline]
+    pub fn get_total(&self) -> usize {
+        self.total
+    }
+
+    // Files the remote site has in the folder are kept off the disk, as if excluded
+    // locally, and files only here in the folder aren't sent
+    pub fn exclude_folder<P: AsRef<Path>>(&mut self, folder: P) {
+        let folder = folder.as_ref();
+        let planned = self.changes.len();
+        self.changes.retain(|change| match *change {
+            PlannedChange::CreateLocal(ref path) => !path.starts_with(folder),
+            _ => true
+        });
+        for change in self.changes.iter_mut() {
+            if let PlannedChange::Create(_, ref history, ref mut excluded) = *change {
+                if history.filename.1.iter().collect::<PathBuf>().starts_with(folder) {
+                    *excluded = true;
+                }
+            }
+        }
+        self.total -= planned - self.changes.len();
+    }
+}
+
+impl<FU: FileUpdater> FileSet<FU> {
+    pub fn plan_reconciliation(&mut self, mut file_list: HashMap<FileID, FileHistory<FU>>, timestamp_lookup: TimestampMap) -> ReconciliationPlan<FU> {
+        let mut changes = VecDeque::new();
+        let mut report = ReconciliationReport::new();
+        let base_path = self.updater.get_base_path().to_path_buf();
+        let mut found_files = Vec::new();
+        let mut updated = HashSet::new();
+        let scanned = self.scan_dir(base_path.as_path(), base_path.as_path(), &mut |_, relative_path| {
+            found_files.push(relative_path);
+            Ok(())
+        });
+        if let Err(e) = scanned {
+            report.failed.push((PathBuf::new(), e.to_string()));
+        }
+        for relative_path in found_files {
+            match self.id_lookup.get_id_for(relative_path.iter()) {
+                Some(id) => {
+                    if let Some(history) = file_list.remove(&id) {
+                        updated.insert(id);
+                        changes.push_back(PlannedChange::Update(id, relative_path, history));
+                    }
+                },
+                None => changes.push_back(PlannedChange::CreateLocal(relative_path))
+            }
+        }
+        // Directories go once the files in them have, deepest first
+        let mut removed: Vec<_> = self.files.iter().filter(|&(id, _)| !file_list.contains_key(id) && !updated.contains(id)).map(|(&id, file)| (file.is_directory(), id, file.get_local_filename())).collect();
+        removed.sort_by_key(|&(directory, _, ref path)| (directory, usize::max_value() - path.components().count()));
+        changes.extend(removed.into_iter().map(|(_, id, path)| PlannedChange::Remove(id, path)));
+        for (id, history) in file_list {
+            match self.files.get(&id) {
+                Some(file) => changes.push_back(PlannedChange::Keep(id, file.get_local_filename())),
+                None => changes.push_back(PlannedChange::Create(id, history, false))
+            }
+        }
+        ReconciliationPlan {
+            total: changes.len(),
+            changes: changes,
+            timestamp_lookup: timestamp_lookup,
+            started: false,
+            remote_changes: 0,
+            operations: Vec::new(),
+            report: report
+        }
+    }
+
+    // Carries out the plan, asking the progress callback after each change whether
+    // to go on. If it says no, the rest of the plan is left to be executed later.
+    pub fn execute_reconciliation<F: FnMut(usize, usize) -> bool>(&mut self, plan: &mut ReconciliationPlan<FU>, mut progress: F) -> Option<(Vec<FileSetOperation<FU>>, ReconciliationReport)> {
+        if !plan.started {
+            plan.started = true;
+            self.notify(SyncEvent::SyncStarted);
+        }
+        while let Some(change) = plan.changes.pop_front() {
+            self.execute_change(change, plan);
+            if !progress(plan.total - plan.changes.len(), plan.total) && !plan.changes.is_empty() {
+                trace!("Reconciliation paused with {} changes left", plan.changes.len());
+                self.save().unwrap();
+                return None
+            }
+        }
+        self.replay_pending();
+        self.save().unwrap();
+        self.flush_due_index_changes();
+        let operations = mem::replace(&mut plan.operations, Vec::new());
+        let generation = self.generation;
+        self.notify(SyncEvent::SyncFinished {
+            local_changes: operations.len(),
+            remote_changes: plan.remote_changes,
+            generation: generation
+        });
+        Some((operations, mem::replace(&mut plan.report, ReconciliationReport::new())))
+    }
+
+    fn execute_change(&mut self, change: PlannedChange<FU>, plan: &mut ReconciliationPlan<FU>) {
+        match change {
+            PlannedChange::CreateLocal(path) => {
+                let base_path = self.updater.get_base_path().to_path_buf();
+                if let Err(e) = self.local_create_operations(base_path.as_path(), path.as_path(), &mut plan.operations) {
+                    plan.report.failed.push((path, e.to_string()));
+                }
+            },
+            PlannedChange::Update(id, path, mut history) => {
+                trace!("Getting local changes");
+                let result = self.local_update_operation(path.as_path(), id).and_then(|operation| {
+                    plan.operations.push(operation);
+                    trace!("Updating the file with remote operations");
+                    self.updater.update_file(&path, &plan.timestamp_lookup, &mut history.operation_history)
+                });
+                match result {
+                    Ok(()) => plan.report.updated.push(path),
+                    Err(e) => plan.report.failed.push((path, e.to_string()))
+                }
+                self.count_remote_change(id, plan);
+            },
+            PlannedChange::Keep(id, path) => {
+                if self.excluded.contains(&id) {
+                    plan.report.skipped.push((path, "excluded locally".to_string()));
+                }
+                self.count_remote_change(id, plan);
+            },
+            PlannedChange::Remove(id, path) => {
+                let file = match self.files.remove(&id) {
+                    Some(file) => file,
+                    None => return
+                };
+                plan.remote_changes += 1;
+                self.generation += 1;
+                self.record_change(id, false);
+                let directory = file.is_directory();
+                self.removed.insert(id, file);
+                // There's no remove operation, so the tombstone lasts until every site
+                // has seen what this one had when it was made
+                let state = State {
+                    site_id: self.site_id,
+                    time_stamp: self.last_timestamp
+                };
+                self.removed_at.insert(id, state);
+                if self.excluded.remove(&id) {
+                    plan.report.deleted.push(path);
+                    return
+                }
+                self.id_lookup.remove_file(path.iter());
+                let result = if directory {
+                    self.remove_directory_if_empty(&path)
+                } else {
+                    self.updater.remove_file(&path)
+                };
+                match result {
+                    Ok(()) => plan.report.deleted.push(path),
+                    Err(e) => plan.report.failed.push((path, e.to_string()))
+                }
+            },
+            PlannedChange::Create(id, mut history, excluded) => {
+                if !self.path_limits.allows(&history.filename.1) {
+                    let path: PathBuf = history.filename.1.iter().collect();
+                    plan.report.failed.push((path.clone(), FileSetError::PathLimitExceeded(path).to_string()));
+                    return
+                }
+                if !self.files.contains_key(&id) {
+                    self.create_from_history(id, &mut history, excluded, plan);
+                }
+                self.count_remote_change(id, plan);
+            }
+        }
+    }
+
+    fn create_from_history(&mut self, id: FileID, history: &mut FileHistory<FU>, excluded: bool, plan: &mut ReconciliationPlan<FU>) {
+        let filename = mem::replace(&mut history.filename, (0, Vec::new()));
+        if excluded {
+            let printed = filename.1[filename.1.len() - 1].clone();
+            self.files.insert(id, FileMetadata {
+                filename: filename,
+                printed_filename: printed,
+                attributes: history.attributes.clone(),
+                filename_site: None,
+                attribute_sites: HashMap::new(),
+                filename_time: HybridTimestamp::default(),
+                attribute_times: HashMap::new()
+            });
+            self.excluded.insert(id);
+            plan.report.skipped.push((self.files[&id].get_local_filename(), "excluded from the plan".to_string()));
+            return
+        }
+        let printed = self.id_lookup.add_file(filename.1.iter().map(OsStr::new), id, id.0);
+        let file = FileMetadata {
+            filename: filename,
+            printed_filename: printed,
+            attributes: history.attributes.clone(), // TODO consider retrieving these separately when they are needed
+            filename_site: None,
+            attribute_sites: HashMap::new(),
+            filename_time: HybridTimestamp::default(),
+            attribute_times: HashMap::new()
+        };
+        let actual_filename = file.get_local_filename();
+        let conflicted = file.is_conflicted();
+        let directory = file.is_directory();
+        self.files.insert(id, file);
+        let result = if directory {
+            self.updater.create_directory(&actual_filename)
+        } else {
+            let timestamp_lookup = &plan.timestamp_lookup;
+            let operation_history = &mut history.operation_history;
+            self.updater.create_file(&actual_filename).and_then(|_| {
+                self.updater.update_file(&actual_filename, timestamp_lookup, operation_history)
+            })
+        };
+        match result {
+            Ok(()) => plan.report.created.push(actual_filename.clone()),
+            Err(e) => plan.report.failed.push((actual_filename.clone(), e.to_string()))
+        }
+        if conflicted {
+            plan.report.conflicted.push(actual_filename.clone());
+            self.notify(SyncEvent::ConflictDetected {
+                id: id,
+                path: actual_filename,
+                generation: self.generation
+            });
+        }
+    }
+
+    fn count_remote_change(&mut self, id: FileID, plan: &mut ReconciliationPlan<FU>) {
+        plan.remote_changes += 1;
+        self.generation += 1;
+        self.record_change(id, true);
+    }
+}
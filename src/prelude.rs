@@ -0,0 +1,9 @@
+// What an application sharing a fileset usually needs, so that one glob import
+// covers opening it, implementing an updater and passing operations between sites
+// None of the traits here are sealed: each is a hook applications implement
+pub use {FileSet, FileUpdater, FileSetOperation, FileSetError, FileMetadata, FileID, SiteId, State};
+pub use {IdAllocation, ConflictPolicy, RenamePolicy, OrphanPolicy, ConflictRecord, Resolution};
+pub use {SyncEvent, SyncListener, ResolvedConflict, ConflictHandler, Indexer, IndexChange};
+pub use {TieBreaker, TimestampMap, VersionVector, SiteInfo, SerializedFileSet, SalvageReport};
+#[cfg(feature = "search")]
+pub use SearchIndexer;
@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+
+// What integrating a remote file list did, file by file, so that the outcome
+// of an initial sync can be shown without guessing from the side effects
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReconciliationReport {
+    // Files the remote list brought to this site
+    pub created: Vec<PathBuf>,
+    // Files that were here but not in the remote list
+    pub deleted: Vec<PathBuf>,
+    // Files on both sides that had remote changes applied
+    pub updated: Vec<PathBuf>,
+    // Files placed under a different name to avoid a clash
+    pub conflicted: Vec<PathBuf>,
+    pub skipped: Vec<(PathBuf, String)>,
+    pub failed: Vec<(PathBuf, String)>
+}
+
+impl ReconciliationReport {
+    #[inline]
+    pub fn new() -> ReconciliationReport {
+        Default::default()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.failed.is_empty() && self.conflicted.is_empty()
+    }
+}
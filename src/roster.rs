@@ -0,0 +1,219 @@
+use std::collections::hash_map::{self, HashMap, Entry, RandomState};
+use std::hash::{BuildHasher, Hasher};
+use std::{cmp, io, usize};
+use std::io::Read;
+use byteorder::{NetworkEndian, ByteOrder};
+
+use super::{SiteId, State, PathLimits};
+use serialization::{write_str, read_str, write_site_id, read_site_id, preallocation};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SiteInfo {
+    pub display_name: String,
+    pub device_name: String,
+    pub platform: String,
+    pub public_key: Vec<u8>,
+    // What the site is running, so that operations it made can be traced to it
+    pub application_version: String,
+    pub crate_version: String
+}
+
+// A site id for a new site, so that sites sharing a fileset don't have to agree
+// on ids among themselves. Each half comes from a freshly seeded hasher.
+pub fn random_site_id() -> SiteId {
+    let high = RandomState::new().build_hasher().finish() as SiteId;
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(high as u64);
+    high << 64 | hasher.finish() as SiteId
+}
+
+// Every site that has been announced, along with the state of the announcement
+// that was last applied, so that later announcements win at every replica, and
+// the path limits the site announced it was using
+#[derive(Debug, Default)]
+pub struct SiteRoster {
+    sites: HashMap<SiteId, (State, SiteInfo, PathLimits)>
+}
+
+impl SiteRoster {
+    #[inline]
+    pub fn new() -> SiteRoster {
+        SiteRoster {
+            sites: HashMap::new()
+        }
+    }
+
+    pub fn integrate(&mut self, state: State, site_id: SiteId, info: SiteInfo, path_limits: PathLimits) -> bool {
+        match self.sites.entry(site_id) {
+            Entry::Occupied(mut entry) => {
+                {
+                    let &(ref current, _, _) = entry.get();
+                    if current.time_stamp > state.time_stamp || current.time_stamp == state.time_stamp && current.site_id > state.site_id {
+                        return false
+                    }
+                }
+                entry.insert((state, info, path_limits));
+            },
+            Entry::Vacant(entry) => {
+                entry.insert((state, info, path_limits));
+            }
+        }
+        true
+    }
+
+    #[inline]
+    pub fn get(&self, site_id: SiteId) -> Option<&SiteInfo> {
+        self.sites.get(&site_id).map(|&(_, ref info, _)| info)
+    }
+
+    #[inline]
+    pub fn get_path_limits(&self, site_id: SiteId) -> Option<PathLimits> {
+        self.sites.get(&site_id).map(|&(_, _, path_limits)| path_limits)
+    }
+
+    #[inline]
+    pub fn contains(&self, site_id: SiteId) -> bool {
+        self.sites.contains_key(&site_id)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.sites.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sites.is_empty()
+    }
+
+    pub fn site_ids<'a>(&'a self) -> hash_map::Keys<'a, SiteId, (State, SiteInfo, PathLimits)> {
+        self.sites.keys()
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, self.sites.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (&site_id, &(ref state, ref info, ref path_limits)) in self.sites.iter() {
+            try!(write_site_id(writer, site_id));
+            try!(write_site_id(writer, state.site_id));
+            NetworkEndian::write_u64(&mut long_buf, state.time_stamp);
+            try!(writer.write_all(&long_buf));
+            try!(write_str(writer, &mut int_buf, &info.display_name));
+            try!(write_str(writer, &mut int_buf, &info.device_name));
+            try!(write_str(writer, &mut int_buf, &info.platform));
+            NetworkEndian::write_u32(&mut int_buf, info.public_key.len() as u32);
+            try!(writer.write_all(&int_buf));
+            try!(writer.write_all(&info.public_key));
+            try!(write_str(writer, &mut int_buf, &info.application_version));
+            try!(write_str(writer, &mut int_buf, &info.crate_version));
+            for &limit in [path_limits.max_depth, path_limits.max_name_length, path_limits.max_length].iter() {
+                NetworkEndian::write_u64(&mut long_buf, limit as u64);
+                try!(writer.write_all(&long_buf));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<SiteRoster> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut int_buf));
+        let site_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut sites = HashMap::with_capacity(preallocation(site_count));
+        for _ in 0..site_count {
+            let site_id = try!(read_site_id(reader));
+            let announcing_site = try!(read_site_id(reader));
+            try!(reader.read_exact(&mut long_buf));
+            let time_stamp = NetworkEndian::read_u64(&long_buf);
+            let display_name = try!(read_str(reader, &mut int_buf));
+            let device_name = try!(read_str(reader, &mut int_buf));
+            let platform = try!(read_str(reader, &mut int_buf));
+            try!(reader.read_exact(&mut int_buf));
+            let key_len = NetworkEndian::read_u32(&int_buf) as usize;
+            let mut public_key = Vec::with_capacity(preallocation(key_len));
+            try!((&mut *reader).take(key_len as u64).read_to_end(&mut public_key));
+            if public_key.len() != key_len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "public key ends early"))
+            }
+            let application_version = try!(read_str(reader, &mut int_buf));
+            let crate_version = try!(read_str(reader, &mut int_buf));
+            let mut limits = [0;3];
+            for limit in limits.iter_mut() {
+                try!(reader.read_exact(&mut long_buf));
+                *limit = cmp::min(NetworkEndian::read_u64(&long_buf), usize::MAX as u64) as usize;
+            }
+            sites.insert(site_id, (State {
+                site_id: announcing_site,
+                time_stamp: time_stamp
+            }, SiteInfo {
+                display_name: display_name,
+                device_name: device_name,
+                platform: platform,
+                public_key: public_key,
+                application_version: application_version,
+                crate_version: crate_version
+            }, PathLimits {
+                max_depth: limits[0],
+                max_name_length: limits[1],
+                max_length: limits[2]
+            }));
+        }
+        Ok(SiteRoster {
+            sites: sites
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SiteRoster, SiteInfo, random_site_id};
+    use super::super::{State, PathLimits};
+
+    fn info(name: &str) -> SiteInfo {
+        SiteInfo {
+            display_name: name.to_string(),
+            device_name: "laptop".to_string(),
+            platform: "linux".to_string(),
+            public_key: vec![1, 2, 3],
+            application_version: "1.0".to_string(),
+            crate_version: "0.1.0".to_string()
+        }
+    }
+
+    #[test]
+    fn later_announcements_win() {
+        let mut roster = SiteRoster::new();
+        assert!(roster.integrate(State { site_id: 2, time_stamp: 4 }, 2, info("laptop"), PathLimits::unlimited()));
+        assert!(!roster.integrate(State { site_id: 1, time_stamp: 3 }, 2, info("old laptop"), PathLimits::unlimited()));
+        let limits = PathLimits {
+            max_depth: 8,
+            max_name_length: 64,
+            max_length: 256
+        };
+        assert!(roster.integrate(State { site_id: 3, time_stamp: 4 }, 2, info("work laptop"), limits));
+        assert_eq!(roster.get(2), Some(&info("work laptop")));
+
+        let mut buffer = Vec::new();
+        roster.compress_to(&mut buffer).unwrap();
+        let roster = SiteRoster::expand_from(&mut &buffer[..]).unwrap();
+        assert_eq!(roster.get(2), Some(&info("work laptop")));
+        assert_eq!(roster.get_path_limits(2), Some(limits));
+        assert_eq!(roster.len(), 1);
+    }
+
+    #[test]
+    fn random_site_ids_use_the_full_width() {
+        let site_id = random_site_id();
+        assert!(site_id > u64::max_value() as u128);
+        assert!(random_site_id() != site_id);
+
+        let mut roster = SiteRoster::new();
+        roster.integrate(State { site_id: site_id, time_stamp: 0 }, site_id, info("nas"), PathLimits::unlimited());
+        let mut buffer = Vec::new();
+        roster.compress_to(&mut buffer).unwrap();
+        let roster = SiteRoster::expand_from(&mut &buffer[..]).unwrap();
+        assert_eq!(roster.get(site_id), Some(&info("nas")));
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, Schema, Value, STORED, STRING, TEXT};
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+use super::{FileID, SiteId};
+use index::{Indexer, IndexChange};
+
+// Only the start of a large file is searchable
+const MAX_INDEXED_BYTES: u64 = 1 << 20;
+const WRITER_MEMORY: usize = 15_000_000;
+
+// Keeps a full-text index of the names and contents of the files in a fileset.
+// The index goes in its own folder, which should be outside the fileset or
+// under its storage path, so it isn't synced along with the files.
+pub struct SearchIndexer {
+    base_path: PathBuf,
+    writer: IndexWriter,
+    reader: IndexReader,
+    id_field: Field,
+    path_field: Field,
+    body_field: Field
+}
+
+impl SearchIndexer {
+    // Files are read from the fileset's base path, where the paths in the
+    // changes it is sent are relative to
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(index_path: P, base_path: Q) -> ::tantivy::Result<SearchIndexer> {
+        let mut schema_builder = Schema::builder();
+        let id_field = schema_builder.add_text_field("id", STRING | STORED);
+        let path_field = schema_builder.add_text_field("path", TEXT | STORED);
+        let body_field = schema_builder.add_text_field("body", TEXT);
+        try!(fs::create_dir_all(index_path.as_ref()).map_err(|e| ::tantivy::TantivyError::from(e)));
+        let directory = try!(MmapDirectory::open(index_path.as_ref()));
+        let index = try!(Index::open_or_create(directory, schema_builder.build()));
+        let writer = try!(index.writer_with_num_threads(1, WRITER_MEMORY));
+        let reader = try!(index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into());
+        Ok(SearchIndexer {
+            base_path: base_path.as_ref().to_path_buf(),
+            writer: writer,
+            reader: reader,
+            id_field: id_field,
+            path_field: path_field,
+            body_field: body_field
+        })
+    }
+
+    // The files whose names or contents match the query, best match first
+    pub fn search(&self, query: &str, limit: usize) -> ::tantivy::Result<Vec<(FileID, PathBuf)>> {
+        let searcher = self.reader.searcher();
+        let query_parser = QueryParser::for_index(searcher.index(), vec![self.path_field, self.body_field]);
+        let query = try!(query_parser.parse_query(query));
+        let mut found = Vec::new();
+        for (_, address) in try!(searcher.search(&query, &TopDocs::with_limit(limit))) {
+            let document: TantivyDocument = try!(searcher.doc(address));
+            let id = document.get_first(self.id_field).and_then(|value| value.as_str()).and_then(parse_id);
+            let path = document.get_first(self.path_field).and_then(|value| value.as_str()).map(PathBuf::from);
+            if let (Some(id), Some(path)) = (id, path) {
+                found.push((id, path));
+            }
+        }
+        Ok(found)
+    }
+
+    fn add_file(&mut self, id: FileID, path: &Path) -> ::tantivy::Result<()> {
+        let mut contents = Vec::new();
+        let read = fs::File::open(self.base_path.join(path)).and_then(|file| file.take(MAX_INDEXED_BYTES).read_to_end(&mut contents));
+        if let Err(e) = read {
+            trace!("Indexing {:?} by name only, since it couldn't be read: {}", path, e);
+        }
+        let mut document = TantivyDocument::default();
+        document.add_text(self.id_field, format_id(id));
+        document.add_text(self.path_field, path.to_string_lossy());
+        // Binary files can still be found by name
+        if let Ok(body) = String::from_utf8(contents) {
+            document.add_text(self.body_field, body);
+        }
+        try!(self.writer.add_document(document));
+        Ok(())
+    }
+
+    fn apply(&mut self, changes: &[IndexChange]) -> ::tantivy::Result<()> {
+        for change in changes {
+            match *change {
+                // The path may have changed even when the contents haven't, so
+                // the file is always indexed again
+                IndexChange::Changed { id, ref path, .. } => {
+                    self.writer.delete_term(Term::from_field_text(self.id_field, &format_id(id)));
+                    try!(self.add_file(id, path));
+                },
+                IndexChange::Removed(id) => {
+                    self.writer.delete_term(Term::from_field_text(self.id_field, &format_id(id)));
+                }
+            }
+        }
+        try!(self.writer.commit());
+        self.reader.reload()
+    }
+}
+
+impl Indexer for SearchIndexer {
+    fn index(&mut self, changes: &[IndexChange]) {
+        if let Err(e) = self.apply(changes) {
+            warn!("Unable to index {} changes: {}", changes.len(), e);
+        }
+    }
+}
+
+fn format_id(id: FileID) -> String {
+    format!("{}:{}", id.0, id.1)
+}
+
+fn parse_id(id: &str) -> Option<FileID> {
+    let mut parts = id.splitn(2, ':');
+    match (parts.next().and_then(|site_id| site_id.parse::<SiteId>().ok()), parts.next().and_then(|number| number.parse::<u64>().ok())) {
+        (Some(site_id), Some(number)) => Some((site_id, number)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use super::SearchIndexer;
+    use index::{Indexer, IndexChange};
+
+    #[test]
+    fn files_are_found_by_name_and_contents() {
+        let base_path = env::temp_dir().join("crdt_fileset_test_search");
+        let _ = fs::remove_dir_all(&base_path);
+        fs::create_dir_all(&base_path).unwrap();
+        fs::write(base_path.join("recipes.txt"), b"a pinch of saffron").unwrap();
+        fs::write(base_path.join("notes.txt"), b"nothing to see").unwrap();
+        let mut indexer = SearchIndexer::open(base_path.join(".crdt/search"), &base_path).unwrap();
+        indexer.index(&[IndexChange::Changed {
+            id: (1, 0),
+            path: PathBuf::from("recipes.txt"),
+            content_changed: true
+        }, IndexChange::Changed {
+            id: (1, 1),
+            path: PathBuf::from("notes.txt"),
+            content_changed: true
+        }]);
+        assert_eq!(indexer.search("saffron", 10).unwrap(), vec![((1, 0), PathBuf::from("recipes.txt"))]);
+        assert_eq!(indexer.search("notes", 10).unwrap(), vec![((1, 1), PathBuf::from("notes.txt"))]);
+
+        // A rename replaces the file's entry rather than adding another
+        fs::rename(base_path.join("recipes.txt"), base_path.join("dinner.txt")).unwrap();
+        indexer.index(&[IndexChange::Changed {
+            id: (1, 0),
+            path: PathBuf::from("dinner.txt"),
+            content_changed: false
+        }]);
+        assert_eq!(indexer.search("saffron", 10).unwrap(), vec![((1, 0), PathBuf::from("dinner.txt"))]);
+        indexer.index(&[IndexChange::Removed((1, 0))]);
+        assert!(indexer.search("saffron", 10).unwrap().is_empty());
+    }
+}
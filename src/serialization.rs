@@ -1,135 +1,605 @@
-use {FileSet, FileUpdater, FileMetadata};
-use lookup::IDLookup;
+use {FileSet, FileID, SiteId, FileUpdater, FileMetadata, IdAllocation, SiteRoster, ConflictPolicy, RenamePolicy, PathLimits, HybridClock, HybridTimestamp, VersionVector, State, SitePriority, build_id_lookup};
+use intent::IntentLog;
+use oplog::OperationLog;
+use applied::AppliedOperations;
+use moves::MoveLog;
+use history::MetadataHistory;
 use std::collections::hash_map::HashMap;
-use std::io;
+use std::collections::hash_set::HashSet;
+use std::cmp;
+use std::io::{self, Read};
 use std::path::PathBuf;
+use std::time::Duration;
 use byteorder::{NetworkEndian, ByteOrder};
 
+// Written at the start of every store, followed by the format version, which goes
+// up whenever the layout changes
+const STORE_MAGIC: u32 = 0x4346_5353;
+const STORE_VERSION: u32 = 16;
+// Most a count read from a store or message may reserve before its entries arrive
+const MAX_PREALLOCATION: usize = 1024;
+
 impl<FU: FileUpdater> FileSet<FU> {
 
     pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
         let mut int_buf = [0;4];
-        NetworkEndian::write_u32(&mut int_buf, self.last_timestamp);
-        try!(writer.write(&int_buf));
-        NetworkEndian::write_u32(&mut int_buf, self.last_id);
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, STORE_MAGIC);
         try!(writer.write(&int_buf));
-        NetworkEndian::write_u32(&mut int_buf, self.site_id);
+        NetworkEndian::write_u32(&mut int_buf, STORE_VERSION);
         try!(writer.write(&int_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.last_timestamp);
+        try!(writer.write(&long_buf));
+        NetworkEndian::write_u64(&mut long_buf, self.last_id);
+        try!(writer.write(&long_buf));
+        try!(write_site_id(writer, self.site_id));
         NetworkEndian::write_u32(&mut int_buf, self.files.len() as u32);
         try!(writer.write(&int_buf));
-        for (&(site_id, id), file) in self.files.iter() {
-            NetworkEndian::write_u32(&mut int_buf, site_id);
-            try!(writer.write(&int_buf));
-            NetworkEndian::write_u32(&mut int_buf, id);
-            try!(writer.write(&int_buf));
-            NetworkEndian::write_u32(&mut int_buf, file.filename.0);
-            try!(writer.write(&int_buf));
-            NetworkEndian::write_u32(&mut int_buf, file.filename.1.len() as u32);
-            try!(writer.write(&int_buf));
-            for filename in file.filename.1.iter() {
-                let bytes = filename.as_bytes();
-                NetworkEndian::write_u32(&mut int_buf, bytes.len() as u32);
-                try!(writer.write(&int_buf));
-                try!(writer.write(bytes));
-            }
-            let bytes = file.printed_filename.as_bytes();
-            NetworkEndian::write_u32(&mut int_buf, bytes.len() as u32);
-            try!(writer.write(&int_buf));
-            try!(writer.write(bytes));
-            NetworkEndian::write_u32(&mut int_buf, file.attributes.len() as u32);
-            try!(writer.write(&int_buf));
-            for (key, &(time_stamp, ref value)) in file.attributes.iter() {
-                let bytes = key.as_bytes();
-                NetworkEndian::write_u32(&mut int_buf, bytes.len() as u32);
-                try!(writer.write(&int_buf));
-                try!(writer.write(bytes));
-                NetworkEndian::write_u32(&mut int_buf, time_stamp);
-                try!(writer.write(&int_buf));
-                let bytes = value.as_bytes();
-                NetworkEndian::write_u32(&mut int_buf, bytes.len() as u32);
-                try!(writer.write(&int_buf));
-                try!(writer.write(bytes));
-            }
+        for (&id, file) in self.files.iter() {
+            try!(write_id(writer, id));
+            try!(compress_metadata(writer, &mut int_buf, file));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.excluded.len() as u32);
+        try!(writer.write(&int_buf));
+        for &id in self.excluded.iter() {
+            try!(write_id(writer, id));
+        }
+        NetworkEndian::write_u64(&mut long_buf, self.generation);
+        try!(writer.write(&long_buf));
+        let content_generations: Vec<_> = self.content_generations.iter().filter(|&(id, _)| self.files.contains_key(id)).collect();
+        NetworkEndian::write_u32(&mut int_buf, content_generations.len() as u32);
+        try!(writer.write(&int_buf));
+        for (&id, &content_generation) in content_generations {
+            try!(write_id(writer, id));
+            NetworkEndian::write_u64(&mut long_buf, content_generation);
+            try!(writer.write(&long_buf));
+        }
+        let modified_at: Vec<_> = self.modified_at.iter().filter(|&(id, _)| self.files.contains_key(id)).collect();
+        NetworkEndian::write_u32(&mut int_buf, modified_at.len() as u32);
+        try!(writer.write(&int_buf));
+        for (&id, modified) in modified_at {
+            try!(write_id(writer, id));
+            try!(modified.compress_to(writer));
+        }
+        try!(self.roster.compress_to(writer));
+        try!(self.applied.compress_to(writer));
+        NetworkEndian::write_u32(&mut int_buf, self.last_updates.len() as u32);
+        try!(writer.write(&int_buf));
+        for (&id, state) in self.last_updates.iter() {
+            try!(write_id(writer, id));
+            try!(write_id(writer, (state.site_id, state.time_stamp)));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.removed.len() as u32);
+        try!(writer.write(&int_buf));
+        for (&id, file) in self.removed.iter() {
+            try!(write_id(writer, id));
+            try!(compress_metadata(writer, &mut int_buf, file));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.removed_at.len() as u32);
+        try!(writer.write(&int_buf));
+        for (&id, state) in self.removed_at.iter() {
+            try!(write_id(writer, id));
+            try!(write_id(writer, (state.site_id, state.time_stamp)));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.removed_seen.len() as u32);
+        try!(writer.write(&int_buf));
+        for (&id, seen) in self.removed_seen.iter() {
+            try!(write_id(writer, id));
+            try!(seen.compress_to(writer));
+        }
+        NetworkEndian::write_u32(&mut int_buf, self.aliases.len() as u32);
+        try!(writer.write(&int_buf));
+        for (&id, &survivor) in self.aliases.iter() {
+            try!(write_id(writer, id));
+            try!(write_id(writer, survivor));
+        }
+        try!(self.folder_moves.compress_to(writer));
+        try!(self.clock.compress_to(writer));
+        NetworkEndian::write_u32(&mut int_buf, self.acknowledgements.len() as u32);
+        try!(writer.write(&int_buf));
+        for (&peer, seen) in self.acknowledgements.iter() {
+            try!(write_site_id(writer, peer));
+            try!(seen.compress_to(writer));
+        }
+        try!(self.metadata_history.compress_to(writer));
+        NetworkEndian::write_u32(&mut int_buf, self.adopted.len() as u32);
+        try!(writer.write(&int_buf));
+        for state in self.adopted.iter() {
+            try!(write_id(writer, (state.site_id, state.time_stamp)));
         }
         Ok(())
     }
 
     pub fn expand_from<R: io::Read>(reader: &mut R, updater: FU, storage_path: PathBuf) -> io::Result<FileSet<FU>> {
+        FileSet::expand_store(reader, updater, storage_path, false).map(|(fileset, _)| fileset)
+    }
+
+    // Like expand_from, but a store that is damaged after its header gives up only
+    // what comes after the damage, rather than the whole fileset
+    pub fn salvage_from<R: io::Read>(reader: &mut R, updater: FU, storage_path: PathBuf) -> io::Result<(FileSet<FU>, SalvageReport)> {
+        FileSet::expand_store(reader, updater, storage_path, true)
+    }
+
+    fn expand_store<R: io::Read>(reader: &mut R, updater: FU, storage_path: PathBuf, salvage: bool) -> io::Result<(FileSet<FU>, SalvageReport)> {
         trace!("Expanding Fileset");
         let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
         try!(reader.read_exact(&mut int_buf));
-        let last_timestamp = NetworkEndian::read_u32(&int_buf);
-        trace!("last_timestamp: {}", last_timestamp);
+        if NetworkEndian::read_u32(&int_buf) != STORE_MAGIC {
+            // Stores from before the format was versioned start with a 32 bit timestamp
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a fileset store, or one from before 64 bit timestamps"))
+        }
         try!(reader.read_exact(&mut int_buf));
-        let last_id = NetworkEndian::read_u32(&int_buf);
+        let version = NetworkEndian::read_u32(&int_buf);
+        trace!("store version: {}", version);
+        if version != STORE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported store version {}", version)))
+        }
+        try!(reader.read_exact(&mut long_buf));
+        let last_timestamp = NetworkEndian::read_u64(&long_buf);
+        trace!("last_timestamp: {}", last_timestamp);
+        try!(reader.read_exact(&mut long_buf));
+        let last_id = NetworkEndian::read_u64(&long_buf);
         trace!("last_id: {}", last_id);
-        try!(reader.read_exact(&mut int_buf));
-        let site_id = NetworkEndian::read_u32(&int_buf);
+        let site_id = try!(read_site_id(reader));
         trace!("site_id: {}", site_id);
         try!(reader.read_exact(&mut int_buf));
         let file_count = NetworkEndian::read_u32(&int_buf) as usize;
         trace!("file count: {}", file_count);
-        let mut files = HashMap::with_capacity(file_count);
-        let mut id_lookup = IDLookup::new();
+        let mut report = SalvageReport::default();
+        let mut files = HashMap::with_capacity(preallocation(file_count));
         for _ in 0..file_count {
-            try!(reader.read_exact(&mut int_buf));
-            let file_site_id = NetworkEndian::read_u32(&int_buf);
-            trace!("file site_id: {}", file_site_id);
-            try!(reader.read_exact(&mut int_buf));
-            let id = NetworkEndian::read_u32(&int_buf);
-            trace!("id: {}", id);
-            try!(reader.read_exact(&mut int_buf));
-            let filename_timestamp = NetworkEndian::read_u32(&int_buf);
-            trace!("filename_timestamp: {}", filename_timestamp);
-            try!(reader.read_exact(&mut int_buf));
-            let filename_component_count = NetworkEndian::read_u32(&int_buf) as usize;
-            let mut filename = Vec::with_capacity(filename_component_count);
-            for _ in 0..filename_component_count {
-                filename.push(read_str(reader, &mut int_buf).unwrap())
-            }
-            trace!("filename: {:?}", filename);
-            let printed_filename = try!(read_str(reader, &mut int_buf));
-            trace!("printed_filename: {}", printed_filename);
-            try!(reader.read_exact(&mut int_buf));
-            let attribute_count = NetworkEndian::read_u32(&int_buf) as usize;
-            trace!("attribute_count: {}", attribute_count);
-            let mut attributes = HashMap::with_capacity(attribute_count);
-            for _ in 0..attribute_count {
-                let key = try!(read_str(reader, &mut int_buf));
-                try!(reader.read_exact(&mut int_buf));
-                let attribute_timestamp = NetworkEndian::read_u32(&int_buf);
-                let value = try!(read_str(reader, &mut int_buf));
-                attributes.insert(key, (attribute_timestamp, value));
+            match expand_file(reader, &mut int_buf) {
+                Ok((id, metadata)) => {
+                    trace!("id: {:?}", id);
+                    files.insert(id, metadata);
+                },
+                Err(ref e) if salvage => {
+                    // Entries aren't delimited, so nothing past a damaged one can be trusted
+                    warn!("Store is damaged after {} of {} files: {}", files.len(), file_count, e);
+                    report.lost_files = file_count - files.len();
+                    break
+                },
+                Err(e) => return Err(e)
             }
-            let metadata = FileMetadata{
-                filename: (filename_timestamp, filename),
-                printed_filename: printed_filename.clone(),
-                attributes: attributes
-            };
-            id_lookup.add_file(metadata.get_local_filename().iter(), (file_site_id, id), file_site_id);
-            files.insert((file_site_id, id), metadata);
-
         }
+        report.recovered_files = files.len();
+        let tail = if report.lost_files > 0 {
+            report.lost_history = true;
+            StoreTail::empty()
+        } else {
+            match StoreTail::expand_from(reader) {
+                Ok(tail) => tail,
+                Err(ref e) if salvage => {
+                    warn!("Store is damaged after the files: {}", e);
+                    report.lost_history = true;
+                    StoreTail::empty()
+                },
+                Err(e) => return Err(e)
+            }
+        };
+        let id_lookup = build_id_lookup(&files, &tail.excluded);
         trace!("Fileset loaded");
-        Ok(FileSet {
+        Ok((FileSet {
             files: files,
             id_lookup: id_lookup,
             updater: updater,
             last_timestamp: last_timestamp,
             last_id: last_id,
             site_id: site_id,
-            storage_path: storage_path
+            intents: try!(IntentLog::open(storage_path.join("intents"))),
+            operation_log: try!(OperationLog::open(storage_path.join("oplog"))),
+            storage_path: storage_path,
+            listeners: Vec::new(),
+            conflict_handler: None,
+            outbound_filters: HashMap::new(),
+            excluded: tail.excluded,
+            generation: tail.generation,
+            content_generations: tail.content_generations,
+            modified_at: tail.modified_at,
+            indexers: Vec::new(),
+            pending_index_changes: HashMap::new(),
+            index_debounce: Duration::from_secs(0),
+            last_index_change: None,
+            id_allocation: IdAllocation::Sequential,
+            roster: tail.roster,
+            pending_operations: Vec::new(),
+            applied: tail.applied,
+            adopted: tail.adopted,
+            conflict_policy: ConflictPolicy::RemoveWins,
+            rename_policy: RenamePolicy::RemoveWins,
+            last_updates: tail.last_updates,
+            removed: tail.removed,
+            removed_at: tail.removed_at,
+            removed_seen: tail.removed_seen,
+            acknowledgements: tail.acknowledgements,
+            merge_concurrent_creates: false,
+            aliases: tail.aliases,
+            quarantined: Vec::new(),
+            folder_moves: tail.folder_moves,
+            path_limits: PathLimits::unlimited(),
+            resurrection_folder: None,
+            clock: tail.clock,
+            metadata_history: tail.metadata_history,
+            ignored_paths: Vec::new(),
+            scan_mtimes: HashMap::new(),
+            standby: false,
+            undone: HashSet::new(),
+            tie_breaker: Box::new(SitePriority)
+        }, report))
+    }
+
+}
+
+// What was given up when a damaged store was salvaged. The lost files are still
+// on disk, and the next reconcile_local adds them back as new files, which peers
+// can tell apart from the ones they already have by comparing merkle trees. The
+// history is everything stored after the files: tombstones, the site roster,
+// acknowledgements and the like, which comes back from peers as they sync.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SalvageReport {
+    pub recovered_files: usize,
+    pub lost_files: usize,
+    pub lost_history: bool
+}
+
+impl SalvageReport {
+    pub fn is_intact(&self) -> bool {
+        self.lost_files == 0 && !self.lost_history
+    }
+}
+
+// Everything stored after the files
+struct StoreTail {
+    excluded: HashSet<FileID>,
+    generation: u64,
+    content_generations: HashMap<FileID, u64>,
+    modified_at: HashMap<FileID, VersionVector>,
+    roster: SiteRoster,
+    applied: AppliedOperations,
+    last_updates: HashMap<FileID, State>,
+    removed: HashMap<FileID, FileMetadata>,
+    removed_at: HashMap<FileID, State>,
+    removed_seen: HashMap<FileID, VersionVector>,
+    aliases: HashMap<FileID, FileID>,
+    folder_moves: MoveLog,
+    clock: HybridClock,
+    acknowledgements: HashMap<SiteId, VersionVector>,
+    metadata_history: MetadataHistory,
+    adopted: Vec<State>
+}
+
+impl StoreTail {
+    fn empty() -> StoreTail {
+        StoreTail {
+            excluded: HashSet::new(),
+            generation: 0,
+            content_generations: HashMap::new(),
+            modified_at: HashMap::new(),
+            roster: SiteRoster::new(),
+            applied: AppliedOperations::new(),
+            last_updates: HashMap::new(),
+            removed: HashMap::new(),
+            removed_at: HashMap::new(),
+            removed_seen: HashMap::new(),
+            aliases: HashMap::new(),
+            folder_moves: MoveLog::new(),
+            clock: HybridClock::new(),
+            acknowledgements: HashMap::new(),
+            metadata_history: MetadataHistory::new(),
+            adopted: Vec::new()
+        }
+    }
+
+    fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<StoreTail> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut int_buf));
+        let excluded_count = NetworkEndian::read_u32(&int_buf) as usize;
+        trace!("excluded count: {}", excluded_count);
+        let mut excluded = HashSet::with_capacity(preallocation(excluded_count));
+        for _ in 0..excluded_count {
+            excluded.insert(try!(read_id(reader)));
+        }
+        try!(reader.read_exact(&mut long_buf));
+        let generation = NetworkEndian::read_u64(&long_buf);
+        trace!("generation: {}", generation);
+        try!(reader.read_exact(&mut int_buf));
+        let content_generation_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut content_generations = HashMap::with_capacity(preallocation(content_generation_count));
+        for _ in 0..content_generation_count {
+            let id = try!(read_id(reader));
+            try!(reader.read_exact(&mut long_buf));
+            content_generations.insert(id, NetworkEndian::read_u64(&long_buf));
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let modified_at_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut modified_at = HashMap::with_capacity(preallocation(modified_at_count));
+        for _ in 0..modified_at_count {
+            let id = try!(read_id(reader));
+            modified_at.insert(id, try!(VersionVector::expand_from(reader)));
+        }
+        let roster = try!(SiteRoster::expand_from(reader));
+        trace!("known sites: {}", roster.len());
+        let applied = try!(AppliedOperations::expand_from(reader));
+        try!(reader.read_exact(&mut int_buf));
+        let last_update_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut last_updates = HashMap::with_capacity(preallocation(last_update_count));
+        for _ in 0..last_update_count {
+            let id = try!(read_id(reader));
+            let (update_site_id, time_stamp) = try!(read_id(reader));
+            last_updates.insert(id, State {
+                site_id: update_site_id,
+                time_stamp: time_stamp
+            });
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let removed_count = NetworkEndian::read_u32(&int_buf) as usize;
+        trace!("removed count: {}", removed_count);
+        let mut removed = HashMap::with_capacity(preallocation(removed_count));
+        for _ in 0..removed_count {
+            let id = try!(read_id(reader));
+            removed.insert(id, try!(expand_metadata(reader, &mut int_buf)));
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let removed_at_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut removed_at = HashMap::with_capacity(preallocation(removed_at_count));
+        for _ in 0..removed_at_count {
+            let id = try!(read_id(reader));
+            let (removing_site_id, time_stamp) = try!(read_id(reader));
+            removed_at.insert(id, State {
+                site_id: removing_site_id,
+                time_stamp: time_stamp
+            });
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let removed_seen_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut removed_seen = HashMap::with_capacity(preallocation(removed_seen_count));
+        for _ in 0..removed_seen_count {
+            let id = try!(read_id(reader));
+            removed_seen.insert(id, try!(VersionVector::expand_from(reader)));
+        }
+        try!(reader.read_exact(&mut int_buf));
+        let alias_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut aliases = HashMap::with_capacity(preallocation(alias_count));
+        for _ in 0..alias_count {
+            let id = try!(read_id(reader));
+            aliases.insert(id, try!(read_id(reader)));
+        }
+        let folder_moves = try!(MoveLog::expand_from(reader));
+        let clock = try!(HybridClock::expand_from(reader));
+        try!(reader.read_exact(&mut int_buf));
+        let acknowledgement_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut acknowledgements = HashMap::with_capacity(preallocation(acknowledgement_count));
+        for _ in 0..acknowledgement_count {
+            let peer = try!(read_site_id(reader));
+            acknowledgements.insert(peer, try!(VersionVector::expand_from(reader)));
+        }
+        let metadata_history = try!(MetadataHistory::expand_from(reader));
+        try!(reader.read_exact(&mut int_buf));
+        let adopted_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut adopted = Vec::with_capacity(preallocation(adopted_count));
+        for _ in 0..adopted_count {
+            let (adopting_site_id, time_stamp) = try!(read_id(reader));
+            adopted.push(State {
+                site_id: adopting_site_id,
+                time_stamp: time_stamp
+            });
+        }
+        Ok(StoreTail {
+            excluded: excluded,
+            generation: generation,
+            content_generations: content_generations,
+            modified_at: modified_at,
+            roster: roster,
+            applied: applied,
+            last_updates: last_updates,
+            removed: removed,
+            removed_at: removed_at,
+            removed_seen: removed_seen,
+            aliases: aliases,
+            folder_moves: folder_moves,
+            clock: clock,
+            acknowledgements: acknowledgements,
+            metadata_history: metadata_history,
+            adopted: adopted
         })
     }
+}
 
+fn expand_file<R: io::Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<(FileID, FileMetadata)> {
+    let id = try!(read_id(reader));
+    let metadata = try!(expand_metadata(reader, int_buf));
+    if metadata.filename.1.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} has no filename", id)))
+    }
+    Ok((id, metadata))
 }
 
 
-fn read_str<R: io::Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<String> {
+pub fn compress_metadata<W: io::Write>(writer: &mut W, int_buf: &mut [u8;4], file: &FileMetadata) -> io::Result<()> {
+    let mut long_buf = [0;8];
+    NetworkEndian::write_u64(&mut long_buf, file.filename.0);
+    try!(writer.write(&long_buf));
+    NetworkEndian::write_u32(int_buf, file.filename.1.len() as u32);
+    try!(writer.write(int_buf));
+    for filename in file.filename.1.iter() {
+        let bytes = filename.as_bytes();
+        NetworkEndian::write_u32(int_buf, bytes.len() as u32);
+        try!(writer.write(int_buf));
+        try!(writer.write(bytes));
+    }
+    let bytes = file.printed_filename.as_bytes();
+    NetworkEndian::write_u32(int_buf, bytes.len() as u32);
+    try!(writer.write(int_buf));
+    try!(writer.write(bytes));
+    NetworkEndian::write_u32(int_buf, file.attributes.len() as u32);
+    try!(writer.write(int_buf));
+    for (key, &(time_stamp, ref value)) in file.attributes.iter() {
+        let bytes = key.as_bytes();
+        NetworkEndian::write_u32(int_buf, bytes.len() as u32);
+        try!(writer.write(int_buf));
+        try!(writer.write(bytes));
+        NetworkEndian::write_u64(&mut long_buf, time_stamp);
+        try!(writer.write(&long_buf));
+        let bytes = value.as_bytes();
+        NetworkEndian::write_u32(int_buf, bytes.len() as u32);
+        try!(writer.write(int_buf));
+        try!(writer.write(bytes));
+    }
+    match file.filename_site {
+        Some(site_id) => {
+            try!(writer.write_all(&[1]));
+            try!(write_site_id(writer, site_id));
+        },
+        None => try!(writer.write_all(&[0]))
+    }
+    NetworkEndian::write_u32(int_buf, file.attribute_sites.len() as u32);
+    try!(writer.write_all(int_buf));
+    for (key, &site_id) in file.attribute_sites.iter() {
+        try!(write_str(writer, int_buf, key));
+        try!(write_site_id(writer, site_id));
+    }
+    try!(file.filename_time.compress_to(writer));
+    NetworkEndian::write_u32(int_buf, file.attribute_times.len() as u32);
+    try!(writer.write_all(int_buf));
+    for (key, hybrid_time) in file.attribute_times.iter() {
+        try!(write_str(writer, int_buf, key));
+        try!(hybrid_time.compress_to(writer));
+    }
+    Ok(())
+}
+
+pub fn expand_metadata<R: io::Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<FileMetadata> {
+    let mut long_buf = [0;8];
+    try!(reader.read_exact(&mut long_buf));
+    let filename_timestamp = NetworkEndian::read_u64(&long_buf);
+    trace!("filename_timestamp: {}", filename_timestamp);
+    try!(reader.read_exact(int_buf));
+    let filename_component_count = NetworkEndian::read_u32(int_buf) as usize;
+    let mut filename = Vec::with_capacity(preallocation(filename_component_count));
+    for _ in 0..filename_component_count {
+        filename.push(try!(read_str(reader, int_buf)))
+    }
+    trace!("filename: {:?}", filename);
+    let printed_filename = try!(read_str(reader, int_buf));
+    trace!("printed_filename: {}", printed_filename);
+    try!(reader.read_exact(int_buf));
+    let attribute_count = NetworkEndian::read_u32(int_buf) as usize;
+    trace!("attribute_count: {}", attribute_count);
+    let mut attributes = HashMap::with_capacity(preallocation(attribute_count));
+    for _ in 0..attribute_count {
+        let key = try!(read_str(reader, int_buf));
+        try!(reader.read_exact(&mut long_buf));
+        let attribute_timestamp = NetworkEndian::read_u64(&long_buf);
+        let value = try!(read_str(reader, int_buf));
+        attributes.insert(key, (attribute_timestamp, value));
+    }
+    let mut flag = [0;1];
+    try!(reader.read_exact(&mut flag));
+    let filename_site = if flag[0] == 0 {
+        None
+    } else {
+        Some(try!(read_site_id(reader)))
+    };
+    try!(reader.read_exact(int_buf));
+    let site_count = NetworkEndian::read_u32(int_buf) as usize;
+    let mut attribute_sites = HashMap::with_capacity(preallocation(site_count));
+    for _ in 0..site_count {
+        let key = try!(read_str(reader, int_buf));
+        attribute_sites.insert(key, try!(read_site_id(reader)));
+    }
+    let filename_time = try!(HybridTimestamp::expand_from(reader));
+    try!(reader.read_exact(int_buf));
+    let time_count = NetworkEndian::read_u32(int_buf) as usize;
+    let mut attribute_times = HashMap::with_capacity(preallocation(time_count));
+    for _ in 0..time_count {
+        let key = try!(read_str(reader, int_buf));
+        attribute_times.insert(key, try!(HybridTimestamp::expand_from(reader)));
+    }
+    Ok(FileMetadata{
+        filename: (filename_timestamp, filename),
+        printed_filename: printed_filename.clone(),
+        attributes: attributes,
+        filename_site: filename_site,
+        attribute_sites: attribute_sites,
+        filename_time: filename_time,
+        attribute_times: attribute_times
+    })
+}
+
+pub fn write_str<W: io::Write>(writer: &mut W, int_buf: &mut [u8;4], value: &str) -> io::Result<()> {
+    let bytes = value.as_bytes();
+    NetworkEndian::write_u32(int_buf, bytes.len() as u32);
+    try!(writer.write_all(int_buf));
+    writer.write_all(bytes)
+}
+
+// Capacity to reserve for a count that came off the wire, so a damaged count
+// can't allocate gigabytes up front
+pub fn preallocation(count: usize) -> usize {
+    cmp::min(count, MAX_PREALLOCATION)
+}
+
+pub fn read_str<R: io::Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<String> {
     try!(reader.read_exact(int_buf));
     let str_len = NetworkEndian::read_u32(int_buf) as usize;
-    let mut str_vec:Vec<u8> = Vec::with_capacity(str_len);
-    str_vec.resize(str_len, 0);
-    try!(reader.read_exact(&mut str_vec));
+    // Read as it arrives, so a damaged length doesn't allocate gigabytes up front
+    let mut str_vec:Vec<u8> = Vec::new();
+    try!((&mut *reader).take(str_len as u64).read_to_end(&mut str_vec));
+    if str_vec.len() != str_len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "string ends early"))
+    }
     Ok(String::from_utf8_lossy(str_vec.as_slice()).into_owned())
 }
+
+pub fn write_path<W: io::Write>(writer: &mut W, int_buf: &mut [u8;4], path: &[String]) -> io::Result<()> {
+    NetworkEndian::write_u32(int_buf, path.len() as u32);
+    try!(writer.write_all(int_buf));
+    for component in path.iter() {
+        try!(write_str(writer, int_buf, component));
+    }
+    Ok(())
+}
+
+pub fn read_path<R: io::Read>(reader: &mut R, int_buf: &mut [u8;4]) -> io::Result<Vec<String>> {
+    try!(reader.read_exact(int_buf));
+    let count = NetworkEndian::read_u32(int_buf) as usize;
+    let mut path = Vec::with_capacity(preallocation(count));
+    for _ in 0..count {
+        path.push(try!(read_str(reader, int_buf)));
+    }
+    Ok(path)
+}
+
+// Site ids are written as two 64 bit halves, high half first
+pub fn write_site_id<W: io::Write>(writer: &mut W, site_id: SiteId) -> io::Result<()> {
+    let mut long_buf = [0;8];
+    NetworkEndian::write_u64(&mut long_buf, (site_id >> 64) as u64);
+    try!(writer.write_all(&long_buf));
+    NetworkEndian::write_u64(&mut long_buf, site_id as u64);
+    writer.write_all(&long_buf)
+}
+
+pub fn read_site_id<R: io::Read>(reader: &mut R) -> io::Result<SiteId> {
+    let mut long_buf = [0;8];
+    try!(reader.read_exact(&mut long_buf));
+    let high = NetworkEndian::read_u64(&long_buf) as SiteId;
+    try!(reader.read_exact(&mut long_buf));
+    Ok(high << 64 | NetworkEndian::read_u64(&long_buf) as SiteId)
+}
+
+// Writes a file ID, or any other site id and 64 bit value pair, such as a state
+pub fn write_id<W: io::Write>(writer: &mut W, id: FileID) -> io::Result<()> {
+    let mut long_buf = [0;8];
+    try!(write_site_id(writer, id.0));
+    NetworkEndian::write_u64(&mut long_buf, id.1);
+    writer.write_all(&long_buf)
+}
+
+pub fn read_id<R: io::Read>(reader: &mut R) -> io::Result<FileID> {
+    let mut long_buf = [0;8];
+    let site_id = try!(read_site_id(reader));
+    try!(reader.read_exact(&mut long_buf));
+    Ok((site_id, NetworkEndian::read_u64(&long_buf)))
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::io;
+
+use super::{FileSet, FileUpdater, FileSetError, VersionVector};
+use merge::SerializedFileSet;
+
+impl<FU: FileUpdater> FileSet<FU> {
+    // Writes the names and attributes of every file as a baseline, along with the
+    // version vector it is current to, and drops the operation log up to here so
+    // that it doesn't grow forever. Peers that have seen everything in the vector
+    // carry on syncing as before, and those that haven't can merge the baseline.
+    pub fn snapshot(&mut self) -> Result<VersionVector, FileSetError> {
+        let seen = self.get_version_vector();
+        try!(self.write_snapshot(&seen).map_err(|e| FileSetError::IOError(e)));
+        try!(self.operation_log.compact().map_err(|e| FileSetError::IOError(e)));
+        trace!("Took a snapshot, with the operation log starting again at {}", self.operation_log.first_sequence());
+        Ok(seen)
+    }
+
+    pub fn get_snapshot(&self) -> Result<Option<(VersionVector, SerializedFileSet)>, FileSetError> {
+        let mut snapshot_file = match fs::File::open(self.storage_path.join("snapshot")) {
+            Ok(snapshot_file) => io::BufReader::new(snapshot_file),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(FileSetError::IOError(e))
+        };
+        let seen = try!(VersionVector::expand_from(&mut snapshot_file).map_err(|e| FileSetError::IOError(e)));
+        let state = try!(SerializedFileSet::expand_from(&mut snapshot_file).map_err(|e| FileSetError::IOError(e)));
+        Ok(Some((seen, state)))
+    }
+
+    fn write_snapshot(&self, seen: &VersionVector) -> io::Result<()> {
+        try!(self.save());
+        let snapshot_path = self.storage_path.join("snapshot");
+        let written_path = snapshot_path.with_extension("written");
+        {
+            let mut snapshot_file = io::BufWriter::new(try!(fs::File::create(&written_path)));
+            try!(seen.compress_to(&mut snapshot_file));
+            try!(self.get_serialized_state().compress_to(&mut snapshot_file));
+            let snapshot_file = try!(snapshot_file.into_inner().map_err(|e| e.into_error()));
+            try!(snapshot_file.sync_all());
+        }
+        // Only replaced once the new one is complete, so there's always one to go back to
+        fs::rename(&written_path, &snapshot_path)
+    }
+}
@@ -0,0 +1,41 @@
+use super::SiteId;
+
+// One of two changes to a name or attribute made at the same hybrid time and
+// timestamp. Filenames are compared with their components joined by slashes. The
+// site isn't known for values that came from a file list or a merged state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contender<'a> {
+    pub site_id: Option<SiteId>,
+    pub value: &'a str
+}
+
+// Picks which of two changes made at the same timestamp is kept. It has to give
+// the same answer at every site, so every site sharing a fileset has to use the
+// same one, or they will diverge. The key is None for the filename.
+pub trait TieBreaker {
+    fn prefers_incoming(&self, key: Option<&str>, current: &Contender, incoming: &Contender) -> bool;
+}
+
+// The change from the greater site id wins. Where the site of the current value
+// isn't known, the greater value does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SitePriority;
+
+impl TieBreaker for SitePriority {
+    fn prefers_incoming(&self, _key: Option<&str>, current: &Contender, incoming: &Contender) -> bool {
+        match (current.site_id, incoming.site_id) {
+            (Some(current_site), Some(incoming_site)) if current_site != incoming_site => incoming_site > current_site,
+            _ => incoming.value > current.value
+        }
+    }
+}
+
+// The greater value wins, whichever site made it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GreatestValue;
+
+impl TieBreaker for GreatestValue {
+    fn prefers_incoming(&self, _key: Option<&str>, current: &Contender, incoming: &Contender) -> bool {
+        incoming.value > current.value
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::btree_map::{self, BTreeMap, Entry};
+use std::collections::hash_set::HashSet;
+use std::io;
+use byteorder::{NetworkEndian, ByteOrder};
+
+use super::{FileSetError, SiteId};
+use serialization::{write_site_id, read_site_id};
+
+// Maps the timestamps an updater uses locally onto the (site id, timestamp)
+// states that produced them, so that transactions from one site can be
+// interpreted by another. Each local timestamp maps to exactly one state,
+// and no two local timestamps may map to the same state.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TimestampMap {
+    entries: BTreeMap<u64, (SiteId, u64)>
+}
+
+impl TimestampMap {
+    #[inline]
+    pub fn new() -> TimestampMap {
+        TimestampMap {
+            entries: BTreeMap::new()
+        }
+    }
+
+    pub fn insert(&mut self, local_timestamp: u64, site_id: SiteId, time_stamp: u64) -> Result<(), FileSetError> {
+        match self.entries.entry(local_timestamp) {
+            Entry::Occupied(entry) => {
+                if *entry.get() != (site_id, time_stamp) {
+                    return Err(FileSetError::TimestampConflict(local_timestamp))
+                }
+            },
+            Entry::Vacant(entry) => {
+                entry.insert((site_id, time_stamp));
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn get(&self, local_timestamp: u64) -> Option<(SiteId, u64)> {
+        self.entries.get(&local_timestamp).cloned()
+    }
+
+    pub fn merge(&mut self, other: &TimestampMap) -> Result<(), FileSetError> {
+        for (&local_timestamp, &(site_id, time_stamp)) in other.entries.iter() {
+            try!(self.insert(local_timestamp, site_id, time_stamp));
+        }
+        self.validate()
+    }
+
+    pub fn validate(&self) -> Result<(), FileSetError> {
+        let mut seen = HashSet::with_capacity(self.entries.len());
+        for (&local_timestamp, state) in self.entries.iter() {
+            if !seen.insert(state) {
+                return Err(FileSetError::TimestampConflict(local_timestamp))
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[inline]
+    pub fn iter<'a>(&'a self) -> btree_map::Iter<'a, u64, (SiteId, u64)> {
+        self.entries.iter()
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, self.entries.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (&local_timestamp, &(site_id, time_stamp)) in self.entries.iter() {
+            NetworkEndian::write_u64(&mut long_buf, local_timestamp);
+            try!(writer.write_all(&long_buf));
+            try!(write_site_id(writer, site_id));
+            NetworkEndian::write_u64(&mut long_buf, time_stamp);
+            try!(writer.write_all(&long_buf));
+        }
+        Ok(())
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<TimestampMap> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut int_buf));
+        let entry_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut entries = BTreeMap::new();
+        for _ in 0..entry_count {
+            try!(reader.read_exact(&mut long_buf));
+            let local_timestamp = NetworkEndian::read_u64(&long_buf);
+            let site_id = try!(read_site_id(reader));
+            try!(reader.read_exact(&mut long_buf));
+            let time_stamp = NetworkEndian::read_u64(&long_buf);
+            entries.insert(local_timestamp, (site_id, time_stamp));
+        }
+        Ok(TimestampMap {
+            entries: entries
+        })
+    }
+}
+
+impl From<BTreeMap<u64, (SiteId, u64)>> for TimestampMap {
+    fn from(entries: BTreeMap<u64, (SiteId, u64)>) -> TimestampMap {
+        TimestampMap {
+            entries: entries
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TimestampMap;
+
+    #[test]
+    fn merge_maps() {
+        let mut map1 = TimestampMap::new();
+        map1.insert(0, 1, 0).ok().unwrap();
+        map1.insert(1, 2, 0).ok().unwrap();
+        let mut map2 = TimestampMap::new();
+        map2.insert(1, 2, 0).ok().unwrap();
+        map2.insert(2, 1, 1).ok().unwrap();
+        map1.merge(&map2).ok().unwrap();
+        assert_eq!(map1.len(), 3);
+        assert_eq!(map1.get(2), Some((1, 1)));
+
+        let mut conflicting = TimestampMap::new();
+        conflicting.insert(2, 3, 7).ok().unwrap();
+        assert!(map1.merge(&conflicting).is_err());
+        assert!(map1.insert(3, 1, 0).is_ok());
+        assert!(map1.validate().is_err());
+    }
+
+    #[test]
+    fn serialize_map() {
+        let mut map = TimestampMap::new();
+        map.insert(0, 1, 0).ok().unwrap();
+        map.insert(5, 2, 3).ok().unwrap();
+        let mut buffer = Vec::new();
+        map.compress_to(&mut buffer).unwrap();
+        assert_eq!(TimestampMap::expand_from(&mut &buffer[..]).unwrap(), map);
+    }
+}
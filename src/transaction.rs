@@ -0,0 +1,52 @@
+use std::path::Path;
+
+use super::{FileSet, FileUpdater, FileSetOperation, FileSetError, TimestampMap};
+
+// Operations are applied locally as they are added, and are only grouped
+// together for the benefit of remote sites, which apply them all or none.
+pub struct FileSetTransaction<'a, FU: FileUpdater + 'a> {
+    fileset: &'a mut FileSet<FU>,
+    operations: Vec<FileSetOperation<FU>>
+}
+
+impl<'a, FU: FileUpdater> FileSetTransaction<'a, FU> {
+    #[inline]
+    pub fn new(fileset: &'a mut FileSet<FU>) -> FileSetTransaction<'a, FU> {
+        FileSetTransaction {
+            fileset: fileset,
+            operations: Vec::new()
+        }
+    }
+
+    pub fn process_create(&mut self, path: &Path) -> Result<(), FileSetError> {
+        let operation = try!(self.fileset.process_create(path));
+        self.operations.push(operation);
+        Ok(())
+    }
+
+    pub fn process_remove(&mut self, path: &Path) {
+        let operation = self.fileset.process_remove(path);
+        self.operations.push(operation);
+    }
+
+    pub fn process_update(&mut self, path: &Path, transaction: FU::FileTransaction, timestamp_lookup: TimestampMap) {
+        let operation = self.fileset.process_update(path, transaction, timestamp_lookup);
+        self.operations.push(operation);
+    }
+
+    pub fn process_file_move(&mut self, old_path: &Path, new_path: &Path) -> Result<(), FileSetError> {
+        let operation = try!(self.fileset.process_file_move(old_path, new_path));
+        self.operations.push(operation);
+        Ok(())
+    }
+
+    pub fn process_folder_move(&mut self, old_path: &Path, new_path: &Path) -> Result<(), FileSetError> {
+        let operation = try!(self.fileset.process_folder_move(old_path, new_path));
+        self.operations.push(operation);
+        Ok(())
+    }
+
+    pub fn commit(self) -> FileSetOperation<FU> {
+        FileSetOperation::Bundle(self.operations)
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::hash_map::HashMap;
+use std::path::PathBuf;
+
+use super::{FileSet, FileSetOperation, FileUpdater, FileSetError, FileID};
+use attributes;
+use oplog::{LoggedOperation, OperationRecord};
+
+impl<FU: FileUpdater> FileSet<FU> {
+    // Makes the change that takes back the latest local create, remove, move or
+    // attribute change that hasn't been undone yet, on disk as well as in the
+    // fileset, and returns its operation to be sent on. Changes since undone by
+    // other sites are passed over. A removed file comes back under its old name,
+    // but empty, since its contents went with it. Changes from before the last
+    // snapshot can't be undone, since what they replaced went with the log.
+    pub fn undo_last(&mut self) -> Result<Option<FileSetOperation<FU>>, FileSetError> {
+        let records = try!(self.get_logged_operations(0..self.operation_log.count()));
+        for (index, record) in records.iter().enumerate().rev() {
+            if record.state.site_id != self.site_id || self.undone.contains(&record.sequence) {
+                continue
+            }
+            let first_sequence = self.operation_log.count();
+            let operation = match record.operation {
+                LoggedOperation::Create { id, .. } => try!(self.undo_create(id)),
+                LoggedOperation::Remove { id, .. } => try!(self.undo_remove(id)),
+                LoggedOperation::Filename { id, .. } => try!(self.undo_rename(id, &records[..index])),
+                LoggedOperation::Attributes { id, ref values } => try!(self.undo_attributes(id, values, &records[..index])),
+                LoggedOperation::MoveFolder { ref old_path, ref new_path, .. } => Some(try!(self.move_folder_back(old_path, new_path))),
+                LoggedOperation::Update { .. } | LoggedOperation::AnnounceSite { .. } => continue
+            };
+            self.undone.insert(record.sequence);
+            // Undoing an undo is a redo, which isn't what's being asked for
+            self.undone.extend(first_sequence..self.operation_log.count());
+            if operation.is_some() {
+                return Ok(operation)
+            }
+        }
+        if self.operation_log.first_sequence() > 0 {
+            return Err(FileSetError::HistoryCompacted)
+        }
+        Ok(None)
+    }
+
+    fn undo_create(&mut self, id: FileID) -> Result<Option<FileSetOperation<FU>>, FileSetError> {
+        let (path, folder, intent) = match self.files.get(&id) {
+            Some(file_metadata) if !self.excluded.contains(&id) => (file_metadata.get_local_filename(), file_metadata.filename.1.clone(), file_metadata.remove_intent()),
+            _ => return Ok(None)
+        };
+        if self.files.values().any(|file_metadata| file_metadata.filename.1.len() > folder.len() && file_metadata.filename.1.starts_with(&folder)) {
+            return Err(FileSetError::DirectoryNotEmpty(path))
+        }
+        let operation = self.process_remove(&path);
+        try!(self.apply_intent(intent).map_err(|e| FileSetError::IOError(e)));
+        Ok(Some(operation))
+    }
+
+    fn undo_remove(&mut self, id: FileID) -> Result<Option<FileSetOperation<FU>>, FileSetError> {
+        let (path, directory) = match self.removed.get(&id) {
+            Some(file_metadata) => (file_metadata.filename.1.iter().collect::<PathBuf>(), file_metadata.is_directory()),
+            None => return Ok(None)
+        };
+        let operation = try!(self.create_entry(&path, directory));
+        let intent = match operation {
+            FileSetOperation::Create(ref o) => self.files[&o.id].create_intent(),
+            _ => unreachable!()
+        };
+        try!(self.apply_intent(intent).map_err(|e| FileSetError::IOError(e)));
+        Ok(Some(operation))
+    }
+
+    fn undo_rename(&mut self, id: FileID, earlier: &[OperationRecord]) -> Result<Option<FileSetOperation<FU>>, FileSetError> {
+        let old_path = match self.files.get(&id) {
+            Some(file_metadata) if !self.excluded.contains(&id) => file_metadata.get_local_filename(),
+            _ => return Ok(None)
+        };
+        let previous = earlier.iter().rev().filter_map(|record| match record.operation {
+            LoggedOperation::Create { id: created, ref filename, .. } | LoggedOperation::Filename { id: created, ref filename } if created == id => Some(filename),
+            _ => None
+        }).next();
+        let new_path: PathBuf = match previous {
+            Some(filename) => filename.iter().collect(),
+            None if self.operation_log.first_sequence() > 0 => return Err(FileSetError::HistoryCompacted),
+            None => return Ok(None)
+        };
+        let mut previous_paths = HashMap::new();
+        previous_paths.insert(id, old_path.clone());
+        let operation = try!(self.process_file_move(&old_path, &new_path));
+        try!(self.move_entries_on_disk(previous_paths, None));
+        Ok(Some(operation))
+    }
+
+    fn undo_attributes(&mut self, id: FileID, values: &[(String, String)], earlier: &[OperationRecord]) -> Result<Option<FileSetOperation<FU>>, FileSetError> {
+        let path = match self.files.get(&id) {
+            Some(file_metadata) if !self.excluded.contains(&id) => file_metadata.get_local_filename(),
+            _ => return Ok(None)
+        };
+        // Locks and the like are the fileset's own, and aren't the user's to undo
+        let values: Vec<_> = values.iter().filter(|&&(ref key, _)| !attributes::is_system_attribute(key)).collect();
+        if values.is_empty() {
+            return Ok(None)
+        }
+        // A key that had no value before goes back to the empty one, unless the
+        // value it had went with the log
+        let compacted = self.operation_log.first_sequence() > 0;
+        let mut previous_values = HashMap::with_capacity(values.len());
+        for &&(ref key, _) in values.iter() {
+            let previous = earlier.iter().rev().filter_map(|record| match record.operation {
+                LoggedOperation::Attributes { id: changed, ref values } if changed == id => {
+                    values.iter().find(|&&(ref changed_key, _)| changed_key == key).map(|&(_, ref value)| value.clone())
+                },
+                _ => None
+            }).next();
+            let previous = match previous {
+                Some(previous) => previous,
+                None if compacted => return Err(FileSetError::HistoryCompacted),
+                None => String::new()
+            };
+            previous_values.insert(key.clone(), previous);
+        }
+        self.process_set_attributes(&path, previous_values).map(Some)
+    }
+
+    fn move_folder_back(&mut self, old_path: &[String], new_path: &[String]) -> Result<FileSetOperation<FU>, FileSetError> {
+        let previous_paths: HashMap<FileID, PathBuf> = self.files.iter().filter(|&(_, file_metadata)| {
+            file_metadata.filename.1.starts_with(new_path)
+        }).map(|(&id, file_metadata)| (id, file_metadata.get_local_filename())).collect();
+        let old_folder: PathBuf = new_path.iter().collect();
+        let new_folder: PathBuf = old_path.iter().collect();
+        let operation = try!(self.process_folder_move(&old_folder, &new_folder));
+        try!(self.move_entries_on_disk(previous_paths, Some((old_folder, new_folder))));
+        Ok(operation)
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::btree_map::{self, BTreeMap};
+use std::io;
+use byteorder::{NetworkEndian, ByteOrder};
+
+use super::{SiteId, State};
+use serialization::{write_site_id, read_site_id};
+
+// For each site, the timestamp of the first operation from that site that has
+// not been seen. Every operation with an earlier timestamp has been seen.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VersionVector {
+    entries: BTreeMap<SiteId, u64>
+}
+
+impl VersionVector {
+    #[inline]
+    pub fn new() -> VersionVector {
+        VersionVector {
+            entries: BTreeMap::new()
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, site_id: SiteId) -> u64 {
+        self.entries.get(&site_id).cloned().unwrap_or(0)
+    }
+
+    pub fn observe(&mut self, site_id: SiteId, next_time_stamp: u64) {
+        let entry = self.entries.entry(site_id).or_insert(0);
+        if next_time_stamp > *entry {
+            *entry = next_time_stamp;
+        }
+    }
+
+    #[inline]
+    pub fn includes(&self, state: &State) -> bool {
+        state.time_stamp < self.get(state.site_id)
+    }
+
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (&site_id, &next_time_stamp) in other.entries.iter() {
+            self.observe(site_id, next_time_stamp);
+        }
+    }
+
+    // What both vectors have seen
+    pub fn meet(&self, other: &VersionVector) -> VersionVector {
+        let mut entries = BTreeMap::new();
+        for (&site_id, &next_time_stamp) in self.entries.iter() {
+            let other_time_stamp = other.get(site_id);
+            if other_time_stamp > 0 {
+                entries.insert(site_id, if next_time_stamp < other_time_stamp { next_time_stamp } else { other_time_stamp });
+            }
+        }
+        VersionVector {
+            entries: entries
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[inline]
+    pub fn iter<'a>(&'a self) -> btree_map::Iter<'a, SiteId, u64> {
+        self.entries.iter()
+    }
+
+    pub fn compress_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        NetworkEndian::write_u32(&mut int_buf, self.entries.len() as u32);
+        try!(writer.write_all(&int_buf));
+        for (&site_id, &next_time_stamp) in self.entries.iter() {
+            try!(write_site_id(writer, site_id));
+            NetworkEndian::write_u64(&mut long_buf, next_time_stamp);
+            try!(writer.write_all(&long_buf));
+        }
+        Ok(())
+    }
+
+    pub fn expand_from<R: io::Read>(reader: &mut R) -> io::Result<VersionVector> {
+        let mut int_buf = [0;4];
+        let mut long_buf = [0;8];
+        try!(reader.read_exact(&mut int_buf));
+        let entry_count = NetworkEndian::read_u32(&int_buf) as usize;
+        let mut entries = BTreeMap::new();
+        for _ in 0..entry_count {
+            let site_id = try!(read_site_id(reader));
+            try!(reader.read_exact(&mut long_buf));
+            entries.insert(site_id, NetworkEndian::read_u64(&long_buf));
+        }
+        Ok(VersionVector {
+            entries: entries
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::VersionVector;
+    use super::super::State;
+
+    #[test]
+    fn merge_vectors() {
+        let mut vector1 = VersionVector::new();
+        vector1.observe(1, 4);
+        vector1.observe(1, 2);
+        let mut vector2 = VersionVector::new();
+        vector2.observe(1, 3);
+        vector2.observe(2, 7);
+        vector1.merge(&vector2);
+        assert_eq!(vector1.get(1), 4);
+        assert_eq!(vector1.get(2), 7);
+        assert!(vector1.includes(&State { site_id: 2, time_stamp: 6 }));
+        assert!(!vector1.includes(&State { site_id: 2, time_stamp: 7 }));
+        assert!(!vector1.includes(&State { site_id: 3, time_stamp: 0 }));
+
+        let mut buffer = Vec::new();
+        vector1.compress_to(&mut buffer).unwrap();
+        assert_eq!(VersionVector::expand_from(&mut &buffer[..]).unwrap(), vector1);
+    }
+}